@@ -0,0 +1,28 @@
+use crate::{
+    state_machine::states::{LogEntry, LogEntryIndex},
+    storage::{SnapshotReader, SnapshotWriter},
+};
+use async_trait::async_trait;
+use std::io;
+
+/// The replicated state machine driven by tfar, where committed client commands are applied in order.
+///
+/// To apply every command exactly once across restarts, the index of the last applied entry should
+/// be persisted atomically with the effects of applying it, and reported by `last_applied` when
+/// the server restarts. Entries up to that index will not be applied again.
+#[async_trait]
+pub trait Application: Send + Sync {
+    /// apply a committed log entry with a `Command::Client`, returns the response for client
+    async fn apply(&mut self, entry: &LogEntry) -> Vec<u8>;
+
+    /// index of the last applied log entry, 0 if nothing was applied
+    fn last_applied(&self) -> LogEntryIndex;
+
+    /// Take a snapshot of current states including the last applied index, writing its data to `writer`. The
+    /// data can be written in pieces rather than built as a whole.
+    async fn snapshot(&self, writer: &mut SnapshotWriter) -> io::Result<()>;
+
+    /// Replace current states with a snapshot taken by `snapshot`. Its data can be read in pieces from the
+    /// reader rather than loaded as a whole.
+    async fn restore(&mut self, snapshot: SnapshotReader) -> io::Result<()>;
+}
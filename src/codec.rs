@@ -0,0 +1,673 @@
+//! Binary encoding of tfar data types, in little endian with length prefixed byte arrays.
+//!
+//! Messages between servers are encoded by `encode_message`, with a header of the protocol version. Only RPCs of
+//! servers are messages, local events such as timeouts and client requests are never sent over the wire. Decoding
+//! never panics on malformed input, but returns an error.
+
+use crate::state_machine::{
+    events::StateEvent,
+    states::{Command, LogEntry, LogEntryId, Membership, ServerId, Snapshot, TfarCommand},
+};
+use std::{convert::TryInto, fmt};
+
+/// Version of the wire protocol, in the header of each message. A server decodes messages of versions from
+/// MIN_PROTOCOL_VERSION up to its own, and rejects others.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// the oldest version of the wire protocol which is still supported
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// types can be encoded into bytes
+pub trait Encode {
+    /// append encoded bytes of self to `buf`
+    fn encode(&self, buf: &mut Vec<u8>);
+}
+
+/// types can be decoded from bytes
+pub trait Decode: Sized {
+    /// decode an instance from the beginning of `buf`, and advance `buf` past the decoded bytes
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError>;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// input ended before a complete value was decoded
+    UnexpectedEof,
+    /// unknown tag of an enum type
+    InvalidTag(u8),
+    /// unexpected bytes left after a complete value was decoded
+    TrailingBytes(usize),
+    /// message of an unsupported protocol version
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEof => write!(f, "unexpected end of input"),
+            DecodeError::InvalidTag(tag) => write!(f, "invalid tag {}", tag),
+            DecodeError::TrailingBytes(len) => write!(f, "{} trailing bytes", len),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {}", version),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, PartialEq)]
+pub enum EncodeError {
+    /// a local event, which is not a message between servers
+    LocalEvent,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::LocalEvent => write!(f, "local event is not a message"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// encode `value` into a new buffer
+pub fn to_bytes<T: Encode>(value: &T) -> Vec<u8> {
+    let mut buf = Vec::new();
+    value.encode(&mut buf);
+    buf
+}
+
+/// decode a value taking all bytes of `buf`
+pub fn from_bytes<T: Decode>(mut buf: &[u8]) -> Result<T, DecodeError> {
+    let value = T::decode(&mut buf)?;
+    if buf.is_empty() {
+        Ok(value)
+    } else {
+        Err(DecodeError::TrailingBytes(buf.len()))
+    }
+}
+
+/// encode `event` as a message to another server, with the protocol version header
+pub fn encode_message(event: &StateEvent) -> Result<Vec<u8>, EncodeError> {
+    let mut buf = Vec::new();
+    PROTOCOL_VERSION.encode(&mut buf);
+    encode_event(event, &mut buf)?;
+    Ok(buf)
+}
+
+/// decode a message taking all bytes of `buf`, which is rejected if its protocol version is not supported
+pub fn decode_message(mut buf: &[u8]) -> Result<StateEvent, DecodeError> {
+    let version = u8::decode(&mut buf)?;
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    from_bytes(buf)
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], DecodeError> {
+    if buf.len() < len {
+        return Err(DecodeError::UnexpectedEof);
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(bytes)
+}
+
+impl Encode for u8 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self);
+    }
+}
+
+impl Decode for u8 {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(take(buf, 1)?[0])
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (*self as u8).encode(buf);
+    }
+}
+
+impl Decode for bool {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(buf)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for u32 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Decode for u32 {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(u32::from_le_bytes(take(buf, 4)?.try_into().unwrap()))
+    }
+}
+
+impl Encode for u64 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Decode for u64 {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(u64::from_le_bytes(take(buf, 8)?.try_into().unwrap()))
+    }
+}
+
+impl Encode for Vec<u8> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u64).encode(buf);
+        buf.extend_from_slice(self);
+    }
+}
+
+impl Decode for Vec<u8> {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = u64::decode(buf)?;
+        if len > buf.len() as u64 {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        Ok(take(buf, len as usize)?.to_vec())
+    }
+}
+
+impl Encode for Vec<ServerId> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u64).encode(buf);
+        for server in self {
+            (*server as u64).encode(buf);
+        }
+    }
+}
+
+impl Decode for Vec<ServerId> {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = u64::decode(buf)?;
+        // each server takes 8 bytes
+        if len > buf.len() as u64 / 8 {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        (0..len).map(|_| Ok(u64::decode(buf)? as ServerId)).collect()
+    }
+}
+
+const TFAR_NOOP: u8 = 0;
+const TFAR_JOINT_CONFIG: u8 = 1;
+const TFAR_NEW_CONFIG: u8 = 2;
+
+impl Encode for TfarCommand {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            TfarCommand::Noop => TFAR_NOOP.encode(buf),
+            TfarCommand::JointConfig { old, new, learners } => {
+                TFAR_JOINT_CONFIG.encode(buf);
+                old.encode(buf);
+                new.encode(buf);
+                learners.encode(buf);
+            },
+            TfarCommand::NewConfig { servers, learners } => {
+                TFAR_NEW_CONFIG.encode(buf);
+                servers.encode(buf);
+                learners.encode(buf);
+            },
+        }
+    }
+}
+
+impl Decode for TfarCommand {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(buf)? {
+            TFAR_NOOP => Ok(TfarCommand::Noop),
+            TFAR_JOINT_CONFIG => Ok(TfarCommand::JointConfig {
+                old:      Vec::decode(buf)?,
+                new:      Vec::decode(buf)?,
+                learners: Vec::decode(buf)?,
+            }),
+            TFAR_NEW_CONFIG => Ok(TfarCommand::NewConfig {
+                servers:  Vec::decode(buf)?,
+                learners: Vec::decode(buf)?,
+            }),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+const COMMAND_TFAR: u8 = 0;
+const COMMAND_CLIENT: u8 = 1;
+
+impl Encode for Command {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Command::Tfar(command) => {
+                COMMAND_TFAR.encode(buf);
+                command.encode(buf);
+            },
+            Command::Client(data) => {
+                COMMAND_CLIENT.encode(buf);
+                data.encode(buf);
+            },
+        }
+    }
+}
+
+impl Decode for Command {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(buf)? {
+            COMMAND_TFAR => Ok(Command::Tfar(TfarCommand::decode(buf)?)),
+            COMMAND_CLIENT => Ok(Command::Client(Vec::decode(buf)?)),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for LogEntry {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.term.encode(buf);
+        self.index.encode(buf);
+        self.command.encode(buf);
+    }
+}
+
+impl Decode for LogEntry {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(LogEntry {
+            term:    u64::decode(buf)?,
+            index:   u64::decode(buf)?,
+            command: Command::decode(buf)?,
+        })
+    }
+}
+
+impl Encode for Vec<LogEntry> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u64).encode(buf);
+        for entry in self {
+            entry.encode(buf);
+        }
+    }
+}
+
+impl Decode for Vec<LogEntry> {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = u64::decode(buf)?;
+        // each entry takes at least 18 bytes
+        if len > buf.len() as u64 / 18 {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        (0..len).map(|_| LogEntry::decode(buf)).collect()
+    }
+}
+
+impl Encode for LogEntryId {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.index.encode(buf);
+        self.term.encode(buf);
+    }
+}
+
+impl Decode for LogEntryId {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(LogEntryId {
+            index: u64::decode(buf)?,
+            term:  u64::decode(buf)?,
+        })
+    }
+}
+
+/// a membership is encoded as the configuration command setting it
+impl Encode for Membership {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.to_config().encode(buf);
+    }
+}
+
+impl Decode for Membership {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        match TfarCommand::decode(buf)? {
+            TfarCommand::Noop => Err(DecodeError::InvalidTag(TFAR_NOOP)),
+            config => Ok(Membership::from_command(&Command::Tfar(config)).unwrap()),
+        }
+    }
+}
+
+impl Encode for Snapshot {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.last_included.encode(buf);
+        self.membership.encode(buf);
+        self.size.encode(buf);
+        self.checksum.encode(buf);
+    }
+}
+
+impl Decode for Snapshot {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Snapshot {
+            last_included: LogEntryId::decode(buf)?,
+            membership:    Membership::decode(buf)?,
+            size:          u64::decode(buf)?,
+            checksum:      u32::decode(buf)?,
+        })
+    }
+}
+
+const EVENT_VOTE_REQUEST: u8 = 0;
+const EVENT_VOTE_RESPONSE: u8 = 1;
+const EVENT_PRE_VOTE_REQUEST: u8 = 2;
+const EVENT_PRE_VOTE_RESPONSE: u8 = 3;
+const EVENT_APPEND_ENTRIES_REQUEST: u8 = 4;
+const EVENT_APPEND_ENTRIES_RESPONSE: u8 = 5;
+const EVENT_INSTALL_SNAPSHOT_REQUEST: u8 = 6;
+const EVENT_INSTALL_SNAPSHOT_RESPONSE: u8 = 7;
+const EVENT_TIMEOUT_NOW: u8 = 8;
+
+/// encode RPCs of servers, which are the only events sent over the wire
+fn encode_event(event: &StateEvent, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+    match event {
+        StateEvent::VoteRequest { term, candidate, last_log, leader_transfer } => {
+            EVENT_VOTE_REQUEST.encode(buf);
+            term.encode(buf);
+            (*candidate as u64).encode(buf);
+            last_log.encode(buf);
+            leader_transfer.encode(buf);
+        },
+        StateEvent::VoteResponse { term, vote_granted, server_id } => {
+            EVENT_VOTE_RESPONSE.encode(buf);
+            term.encode(buf);
+            vote_granted.encode(buf);
+            (*server_id as u64).encode(buf);
+        },
+        StateEvent::PreVoteRequest { term, candidate, last_log } => {
+            EVENT_PRE_VOTE_REQUEST.encode(buf);
+            term.encode(buf);
+            (*candidate as u64).encode(buf);
+            last_log.encode(buf);
+        },
+        StateEvent::PreVoteResponse { term, vote_granted, server_id } => {
+            EVENT_PRE_VOTE_RESPONSE.encode(buf);
+            term.encode(buf);
+            vote_granted.encode(buf);
+            (*server_id as u64).encode(buf);
+        },
+        StateEvent::AppendEntriesRequest { term, leader, prev_log, entries, commit_idx } => {
+            EVENT_APPEND_ENTRIES_REQUEST.encode(buf);
+            term.encode(buf);
+            (*leader as u64).encode(buf);
+            prev_log.encode(buf);
+            entries.encode(buf);
+            commit_idx.encode(buf);
+        },
+        StateEvent::AppendEntriesResponse { term, success, server, match_idx } => {
+            EVENT_APPEND_ENTRIES_RESPONSE.encode(buf);
+            term.encode(buf);
+            success.encode(buf);
+            (*server as u64).encode(buf);
+            match_idx.encode(buf);
+        },
+        StateEvent::InstallSnapshotRequest {
+            term,
+            leader,
+            last_included,
+            membership,
+            offset,
+            data,
+            done,
+            checksum,
+        } => {
+            EVENT_INSTALL_SNAPSHOT_REQUEST.encode(buf);
+            term.encode(buf);
+            (*leader as u64).encode(buf);
+            last_included.encode(buf);
+            membership.encode(buf);
+            offset.encode(buf);
+            data.encode(buf);
+            done.encode(buf);
+            checksum.encode(buf);
+        },
+        StateEvent::InstallSnapshotResponse { term, server, match_idx, offset } => {
+            EVENT_INSTALL_SNAPSHOT_RESPONSE.encode(buf);
+            term.encode(buf);
+            (*server as u64).encode(buf);
+            match_idx.encode(buf);
+            offset.encode(buf);
+        },
+        StateEvent::TimeoutNow { term, leader } => {
+            EVENT_TIMEOUT_NOW.encode(buf);
+            term.encode(buf);
+            (*leader as u64).encode(buf);
+        },
+        StateEvent::ElectionTimeout
+        | StateEvent::HeartbeatTimeout
+        | StateEvent::TakeSnapshot { .. }
+        | StateEvent::SnapshotSaved { .. }
+        | StateEvent::SnapshotFailed
+        | StateEvent::TransferLeadership { .. }
+        | StateEvent::ChangeMembership { .. }
+        | StateEvent::AddServer { .. }
+        | StateEvent::RemoveServer { .. }
+        | StateEvent::AddLearner { .. }
+        | StateEvent::PromoteLearner { .. }
+        | StateEvent::ClientRequest { .. } => return Err(EncodeError::LocalEvent),
+    }
+    Ok(())
+}
+
+/// only RPCs of servers are decoded, tags of local events are invalid
+impl Decode for StateEvent {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let event = match u8::decode(buf)? {
+            EVENT_VOTE_REQUEST => StateEvent::VoteRequest {
+                term:            u64::decode(buf)?,
+                candidate:       u64::decode(buf)? as ServerId,
+                last_log:        LogEntryId::decode(buf)?,
+                leader_transfer: bool::decode(buf)?,
+            },
+            EVENT_VOTE_RESPONSE => StateEvent::VoteResponse {
+                term:         u64::decode(buf)?,
+                vote_granted: bool::decode(buf)?,
+                server_id:    u64::decode(buf)? as ServerId,
+            },
+            EVENT_PRE_VOTE_REQUEST => StateEvent::PreVoteRequest {
+                term:      u64::decode(buf)?,
+                candidate: u64::decode(buf)? as ServerId,
+                last_log:  LogEntryId::decode(buf)?,
+            },
+            EVENT_PRE_VOTE_RESPONSE => StateEvent::PreVoteResponse {
+                term:         u64::decode(buf)?,
+                vote_granted: bool::decode(buf)?,
+                server_id:    u64::decode(buf)? as ServerId,
+            },
+            EVENT_APPEND_ENTRIES_REQUEST => StateEvent::AppendEntriesRequest {
+                term:       u64::decode(buf)?,
+                leader:     u64::decode(buf)? as ServerId,
+                prev_log:   LogEntryId::decode(buf)?,
+                entries:    Vec::decode(buf)?,
+                commit_idx: u64::decode(buf)?,
+            },
+            EVENT_APPEND_ENTRIES_RESPONSE => StateEvent::AppendEntriesResponse {
+                term:      u64::decode(buf)?,
+                success:   bool::decode(buf)?,
+                server:    u64::decode(buf)? as ServerId,
+                match_idx: u64::decode(buf)?,
+            },
+            EVENT_INSTALL_SNAPSHOT_REQUEST => StateEvent::InstallSnapshotRequest {
+                term:          u64::decode(buf)?,
+                leader:        u64::decode(buf)? as ServerId,
+                last_included: LogEntryId::decode(buf)?,
+                membership:    Membership::decode(buf)?,
+                offset:        u64::decode(buf)?,
+                data:          Vec::decode(buf)?,
+                done:          bool::decode(buf)?,
+                checksum:      u32::decode(buf)?,
+            },
+            EVENT_INSTALL_SNAPSHOT_RESPONSE => StateEvent::InstallSnapshotResponse {
+                term:      u64::decode(buf)?,
+                server:    u64::decode(buf)? as ServerId,
+                match_idx: u64::decode(buf)?,
+                offset:    u64::decode(buf)?,
+            },
+            EVENT_TIMEOUT_NOW => StateEvent::TimeoutNow {
+                term:   u64::decode(buf)?,
+                leader: u64::decode(buf)? as ServerId,
+            },
+            tag => return Err(DecodeError::InvalidTag(tag)),
+        };
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::time::Duration;
+
+    fn arb_servers() -> impl Strategy<Value = Vec<ServerId>> {
+        prop::collection::vec(0..100usize, 0..5)
+    }
+
+    fn arb_log_id() -> impl Strategy<Value = LogEntryId> {
+        (any::<u64>(), any::<u64>()).prop_map(|(index, term)| LogEntryId { index, term })
+    }
+
+    fn arb_command() -> impl Strategy<Value = Command> {
+        prop_oneof![
+            Just(Command::Tfar(TfarCommand::Noop)),
+            (arb_servers(), arb_servers(), arb_servers()).prop_map(|(old, new, learners)| Command::Tfar(TfarCommand::JointConfig { old, new, learners })),
+            (arb_servers(), arb_servers()).prop_map(|(servers, learners)| Command::Tfar(TfarCommand::NewConfig { servers, learners })),
+            prop::collection::vec(any::<u8>(), 0..32).prop_map(Command::Client),
+        ]
+    }
+
+    fn arb_entries() -> impl Strategy<Value = Vec<LogEntry>> {
+        prop::collection::vec((any::<u64>(), any::<u64>(), arb_command()).prop_map(|(term, index, command)| LogEntry { term, index, command }), 0..4)
+    }
+
+    fn arb_membership() -> impl Strategy<Value = Membership> {
+        let voters = prop_oneof![arb_servers().prop_map(Membership::new), (arb_servers(), arb_servers()).prop_map(|(old, new)| Membership::joint(old, new))];
+        (voters, arb_servers()).prop_map(|(membership, learners)| membership.with_learners(learners))
+    }
+
+    fn arb_event() -> impl Strategy<Value = StateEvent> {
+        let server = 0..100usize;
+        let data = prop::collection::vec(any::<u8>(), 0..32);
+        prop_oneof![
+            (any::<u64>(), server.clone(), arb_log_id(), any::<bool>()).prop_map(|(term, candidate, last_log, leader_transfer)| StateEvent::VoteRequest { term, candidate, last_log, leader_transfer }),
+            (any::<u64>(), any::<bool>(), server.clone()).prop_map(|(term, vote_granted, server_id)| StateEvent::VoteResponse { term, vote_granted, server_id }),
+            (any::<u64>(), server.clone(), arb_log_id()).prop_map(|(term, candidate, last_log)| StateEvent::PreVoteRequest { term, candidate, last_log }),
+            (any::<u64>(), any::<bool>(), server.clone()).prop_map(|(term, vote_granted, server_id)| StateEvent::PreVoteResponse { term, vote_granted, server_id }),
+            (any::<u64>(), server.clone(), arb_log_id(), arb_entries(), any::<u64>()).prop_map(|(term, leader, prev_log, entries, commit_idx)| StateEvent::AppendEntriesRequest { term, leader, prev_log, entries, commit_idx }),
+            (any::<u64>(), any::<bool>(), server.clone(), any::<u64>()).prop_map(|(term, success, server, match_idx)| StateEvent::AppendEntriesResponse { term, success, server, match_idx }),
+            (any::<u64>(), server.clone(), arb_log_id(), arb_membership(), any::<u64>(), data.clone(), any::<bool>(), any::<u32>()).prop_map(|(term, leader, last_included, membership, offset, data, done, checksum)| {
+                StateEvent::InstallSnapshotRequest {
+                    term,
+                    leader,
+                    last_included,
+                    membership,
+                    offset,
+                    data,
+                    done,
+                    checksum,
+                }
+            }),
+            (any::<u64>(), server.clone(), any::<u64>(), any::<u64>()).prop_map(|(term, server, match_idx, offset)| StateEvent::InstallSnapshotResponse { term, server, match_idx, offset }),
+            (any::<u64>(), server).prop_map(|(term, leader)| StateEvent::TimeoutNow { term, leader }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(event in arb_event()) {
+            prop_assert_eq!(decode_message(&encode_message(&event).unwrap()), Ok(event));
+        }
+
+        #[test]
+        fn truncated_messages_are_rejected(event in arb_event()) {
+            let message = encode_message(&event).unwrap();
+            for len in 0..message.len() {
+                prop_assert!(decode_message(&message[..len]).is_err());
+            }
+        }
+
+        #[test]
+        fn decoding_arbitrary_bytes_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+            let _ = decode_message(&bytes);
+            let mut message = vec![PROTOCOL_VERSION];
+            message.extend(bytes);
+            let _ = decode_message(&message);
+        }
+    }
+
+    #[test]
+    fn local_events_are_not_messages() {
+        let events = vec![
+            StateEvent::ElectionTimeout,
+            StateEvent::HeartbeatTimeout,
+            StateEvent::TakeSnapshot { elapsed: Duration::ZERO, forced: true },
+            StateEvent::SnapshotSaved {
+                snapshot: Snapshot {
+                    last_included: LogEntryId { index: 1, term: 1 },
+                    membership:    Membership::new(vec![0]),
+                    size:          0,
+                    checksum:      0,
+                },
+            },
+            StateEvent::SnapshotFailed,
+            StateEvent::TransferLeadership { target: 1 },
+            StateEvent::ChangeMembership { servers: vec![0, 1] },
+            StateEvent::AddServer { server: 1 },
+            StateEvent::RemoveServer { server: 1 },
+            StateEvent::AddLearner { server: 1 },
+            StateEvent::PromoteLearner { server: 1 },
+            StateEvent::ClientRequest { command: Command::Client(vec![1]) },
+        ];
+        for event in events {
+            assert_eq!(encode_message(&event), Err(EncodeError::LocalEvent));
+        }
+        // a client request in the encoding of a log entry's command, behind any tag not of an RPC
+        let mut command = Vec::new();
+        Command::Client(vec![1]).encode(&mut command);
+        for tag in EVENT_TIMEOUT_NOW + 1..=u8::MAX {
+            let mut message = vec![PROTOCOL_VERSION, tag];
+            message.extend_from_slice(&command);
+            assert_eq!(decode_message(&message), Err(DecodeError::InvalidTag(tag)));
+        }
+    }
+
+    #[test]
+    fn reject_malformed_messages() {
+        let mut message = encode_message(&StateEvent::TimeoutNow { term: 1, leader: 0 }).unwrap();
+        message[0] = PROTOCOL_VERSION + 1;
+        assert_eq!(decode_message(&message), Err(DecodeError::UnsupportedVersion(PROTOCOL_VERSION + 1)));
+        assert_eq!(decode_message(&[PROTOCOL_VERSION, 255]), Err(DecodeError::InvalidTag(255)));
+        message[0] = PROTOCOL_VERSION;
+        message.push(0);
+        assert_eq!(decode_message(&message), Err(DecodeError::TrailingBytes(1)));
+
+        let mut message = encode_message(&StateEvent::VoteResponse {
+            term:         1,
+            vote_granted: true,
+            server_id:    0,
+        })
+        .unwrap();
+        // vote_granted follows the version, the tag and the term
+        message[10] = 2;
+        assert_eq!(decode_message(&message), Err(DecodeError::InvalidTag(2)));
+
+        // a huge length doesn't allocate before the input runs out
+        let mut message = vec![PROTOCOL_VERSION, EVENT_APPEND_ENTRIES_REQUEST];
+        // the term, the leader and the previous log entry precede the entries
+        message.extend_from_slice(&[0; 32]);
+        message.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(decode_message(&message), Err(DecodeError::UnexpectedEof));
+    }
+}
@@ -1,2 +1,10 @@
-#![feature(option_result_contains)]
+pub mod application;
+pub mod codec;
+pub mod node;
+#[cfg(feature = "raftpb")]
+pub mod raftpb;
 pub mod state_machine;
+pub mod storage;
+#[cfg(test)]
+mod testing;
+pub mod transport;
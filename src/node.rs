@@ -0,0 +1,307 @@
+use crate::{
+    application::Application,
+    state_machine::{
+        actions::Action,
+        events::StateEvent,
+        states::{InternalState, LogEntryId, LogEntryIndex, Membership, PersistentState, ServerVolatileState, Snapshot},
+        Follower, StateMachine, Status, Transition,
+    },
+    storage::Storage,
+    transport::Transport,
+};
+use log::{debug, error, warn};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io,
+    ops::Range,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{broadcast, mpsc, watch, Mutex, OwnedMutexGuard},
+    time::{interval, sleep_until, Instant, MissedTickBehavior},
+};
+
+/// number of responses kept for each subscriber, a subscriber falling further behind misses the oldest ones
+const RESPONSES_CAPACITY: usize = 1024;
+
+/// Timeouts driving a node. Election timeouts are randomized within a range so that split votes are rare.
+#[derive(Clone, Debug)]
+pub struct Timeouts {
+    pub election:       Range<Duration>,
+    pub heartbeat:      Duration,
+    /// period of TakeSnapshot ticks, which check the snapshot policy with the time elapsed
+    pub snapshot_check: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            election:       Duration::from_millis(150)..Duration::from_millis(300),
+            heartbeat:      Duration::from_millis(50),
+            snapshot_check: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A Raft server run by the host: it feeds messages from the transport, timeouts and events from handles to the
+/// state machine, and executes the actions of each transition.
+pub struct Node<T> {
+    state:       Option<Box<dyn StateMachine>>,
+    storage:     Arc<dyn Storage>,
+    /// the application shared with the state machine, snapshots are taken of it in background
+    application: Arc<Mutex<Box<dyn Application>>>,
+    transport:   T,
+    timeouts:    Timeouts,
+    /// events from handles
+    events:      mpsc::UnboundedReceiver<StateEvent>,
+    /// results of saving snapshots in background
+    saved:       (mpsc::UnboundedSender<io::Result<Snapshot>>, mpsc::UnboundedReceiver<io::Result<Snapshot>>),
+    status:      watch::Sender<Status>,
+    /// responses of the application to applied entries
+    responses:   broadcast::Sender<(LogEntryIndex, Vec<u8>)>,
+}
+
+/// A handle to feed events to a running node, e.g. client requests, watch its status and receive responses.
+#[derive(Clone)]
+pub struct NodeHandle {
+    events:    mpsc::UnboundedSender<StateEvent>,
+    status:    watch::Receiver<Status>,
+    responses: broadcast::Sender<(LogEntryIndex, Vec<u8>)>,
+}
+
+impl<T: Transport> Node<T> {
+    /// Recover server `internal.id()` from `storage`. The application is restored from the snapshot if it is
+    /// behind the snapshot, since entries replaced by the snapshot were discarded.
+    pub async fn new(storage: Arc<dyn Storage>, application: Box<dyn Application>, internal: InternalState, transport: T, timeouts: Timeouts) -> io::Result<(Node<T>, NodeHandle)> {
+        let persistent = PersistentState::recover(storage.clone()).await?;
+        let volatile = ServerVolatileState::new(application);
+        let volatile = match persistent.snapshot() {
+            Some(snapshot) if volatile.last_applied < snapshot.last_included.index => {
+                volatile.restore(persistent.open_snapshot().await?).await?;
+                volatile.with_restored(snapshot.last_included.index)
+            },
+            _ => volatile,
+        };
+        let application = volatile.application();
+        let internal = internal.with_recovered_membership(&persistent);
+        let state: Box<dyn StateMachine> = Box::new(Follower::new(persistent, volatile, internal));
+
+        let (events_sender, events) = mpsc::unbounded_channel();
+        let (status_sender, status) = watch::channel(state.status());
+        let (responses, _) = broadcast::channel(RESPONSES_CAPACITY);
+        let node = Node {
+            state: Some(state),
+            storage,
+            application,
+            transport,
+            timeouts,
+            events,
+            saved: mpsc::unbounded_channel(),
+            status: status_sender,
+            responses: responses.clone(),
+        };
+        let handle = NodeHandle { events: events_sender, status, responses };
+        Ok((node, handle))
+    }
+
+    /// Run the node until all of its handles are dropped, or the transport is closed. The node stops on a storage
+    /// error as well, which is returned since it's not safe to go on with states that may be changed halfway.
+    pub async fn run(mut self) -> io::Result<()> {
+        let mut election_deadline = Some(Instant::now() + self.election_timeout());
+        let mut heartbeat_deadline: Option<Instant> = None;
+        let mut snapshot_ticks = interval(self.timeouts.snapshot_check);
+        snapshot_ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_tick = Instant::now();
+        loop {
+            // timers are one-shot, they are only restarted by reset actions
+            let event = tokio::select! {
+                event = self.transport.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                event = self.events.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                Some(saved) = self.saved.1.recv() => match saved {
+                    Ok(snapshot) => StateEvent::SnapshotSaved { snapshot },
+                    Err(e) => {
+                        warn!("failed to save snapshot: {}", e);
+                        StateEvent::SnapshotFailed
+                    },
+                },
+                _ = sleep_until(election_deadline.unwrap_or_else(Instant::now)), if election_deadline.is_some() => {
+                    election_deadline = None;
+                    StateEvent::ElectionTimeout
+                },
+                _ = sleep_until(heartbeat_deadline.unwrap_or_else(Instant::now)), if heartbeat_deadline.is_some() => {
+                    heartbeat_deadline = None;
+                    StateEvent::HeartbeatTimeout
+                },
+                tick = snapshot_ticks.tick() => {
+                    let elapsed = tick.saturating_duration_since(last_tick);
+                    last_tick = tick;
+                    StateEvent::TakeSnapshot { elapsed, forced: false }
+                },
+            };
+
+            let Transition { next, actions } = match self.state.take().unwrap().on_events(event).await {
+                Ok(transition) => transition,
+                Err(e) => {
+                    error!("failed to access storage, stopping the node: {}", e);
+                    return Err(e);
+                },
+            };
+            self.state = Some(next);
+            for action in actions {
+                match action {
+                    Action::SendMessage(to, message) => {
+                        if let Err(e) = self.transport.send(to, message).await {
+                            debug!("failed to send message to server {}: {}", to, e);
+                        }
+                    },
+                    Action::Applied { index, response } => {
+                        // there may be no subscriber
+                        let _ = self.responses.send((index, response));
+                    },
+                    Action::ResetElectionTimer => election_deadline = Some(Instant::now() + self.election_timeout()),
+                    Action::ResetHeartbeatTimer => heartbeat_deadline = Some(Instant::now() + self.timeouts.heartbeat),
+                    Action::SaveSnapshot { last_included, membership } => {
+                        // locked before the next event, so that no entry after `last_included` is applied until
+                        // the snapshot is taken
+                        let application = self.application.clone().lock_owned().await;
+                        self.save_snapshot(application, last_included, membership);
+                    },
+                }
+            }
+            self.status.send_replace(self.state.as_ref().unwrap().status());
+        }
+        Ok(())
+    }
+
+    /// Take a snapshot of the locked `application` at `last_included` and save it in background, then feed it back
+    /// to the state machine once it's saved, or a failure if it's not. The application is unlocked once the
+    /// snapshot is taken.
+    fn save_snapshot(&self, application: OwnedMutexGuard<Box<dyn Application>>, last_included: LogEntryId, membership: Membership) {
+        let storage = self.storage.clone();
+        let saved = self.saved.0.clone();
+        tokio::spawn(async move {
+            let result = take_snapshot(storage.as_ref(), application, last_included, membership).await;
+            // the node may have stopped
+            let _ = saved.send(result);
+        });
+    }
+
+    /// a random election timeout within the configured range
+    fn election_timeout(&self) -> Duration {
+        let Range { start, end } = self.timeouts.election;
+        let span = end.saturating_sub(start).as_micros() as u64;
+        // a std hasher is seeded randomly for each instance
+        let random = RandomState::new().build_hasher().finish();
+        start + Duration::from_micros(random % span.max(1))
+    }
+}
+
+/// Write a snapshot of the locked `application` at `last_included` to `storage` as it's taken, and save it. The
+/// application is unlocked once its data is written.
+async fn take_snapshot(storage: &dyn Storage, application: OwnedMutexGuard<Box<dyn Application>>, last_included: LogEntryId, membership: Membership) -> io::Result<Snapshot> {
+    let mut writer = storage.create_snapshot().await?;
+    application.snapshot(&mut writer).await?;
+    drop(application);
+    writer.shutdown().await?;
+    let snapshot = Snapshot::new(last_included, membership, &writer);
+    storage.save_snapshot(&snapshot).await?;
+    Ok(snapshot)
+}
+
+impl NodeHandle {
+    /// feed `event` to the node, e.g. a client request. false if the node has stopped
+    pub fn send(&self, event: StateEvent) -> bool {
+        self.events.send(event).is_ok()
+    }
+
+    /// status of the node after its last transition
+    pub fn status(&self) -> Status {
+        self.status.borrow().clone()
+    }
+
+    /// Subscribe to responses of the application to entries applied from now on, along with their indexes. The
+    /// index of a client request isn't known when it's sent, so a client tells its responses apart by the content,
+    /// e.g. a request id echoed by the application.
+    pub fn responses(&self) -> broadcast::Receiver<(LogEntryIndex, Vec<u8>)> {
+        self.responses.subscribe()
+    }
+
+    /// wait until the status of the node satisfies `predicate`, None if the node has stopped before that
+    pub async fn wait_for(&mut self, predicate: impl FnMut(&Status) -> bool) -> Option<Status> {
+        self.status.wait_for(predicate).await.ok().map(|status| status.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        state_machine::{states::Command, Role},
+        storage::MemStorage,
+        testing::{FailingStorage, Noop},
+        transport::ChannelNetwork,
+    };
+    use std::sync::atomic::Ordering;
+    use tokio::time::{sleep, timeout};
+
+    fn fast_timeouts() -> Timeouts {
+        Timeouts {
+            election: Duration::from_millis(10)..Duration::from_millis(20),
+            ..Timeouts::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn deliver_responses_to_subscribers() {
+        let network = ChannelNetwork::new();
+        let (node, mut handle) = Node::new(Arc::new(MemStorage::new()), Box::new(Noop), InternalState::new(0, vec![0]), network.transport(0), fast_timeouts()).await.unwrap();
+        tokio::spawn(node.run());
+        handle.wait_for(|status| status.role == Role::Leader).await.unwrap();
+        let mut responses = handle.responses();
+        assert!(handle.send(StateEvent::ClientRequest { command: Command::Client(b"command".to_vec()) }));
+        assert_eq!(timeout(Duration::from_secs(5), responses.recv()).await.unwrap().unwrap(), (1, Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn stop_on_storage_error() {
+        let storage = Arc::new(FailingStorage::new());
+        storage.hard_state_fails.store(true, Ordering::SeqCst);
+        let network = ChannelNetwork::new();
+        let (node, handle) = Node::new(storage, Box::new(Noop), InternalState::new(0, vec![0]), network.transport(0), fast_timeouts()).await.unwrap();
+        // the vote for itself can't be saved once the election timer fires
+        let error = timeout(Duration::from_secs(5), node.run()).await.expect("node not stopped").unwrap_err();
+        assert_eq!(error.to_string(), "disk full");
+        assert!(!handle.send(StateEvent::ElectionTimeout));
+    }
+
+    #[tokio::test]
+    async fn take_snapshot_again_after_failing_to_save() {
+        let storage = Arc::new(FailingStorage::new());
+        storage.snapshot_failures.store(1, Ordering::SeqCst);
+        let network = ChannelNetwork::new();
+        let (node, mut handle) = Node::new(storage.clone(), Box::new(Noop), InternalState::new(0, vec![0]), network.transport(0), fast_timeouts()).await.unwrap();
+        tokio::spawn(node.run());
+        handle.wait_for(|status| status.role == Role::Leader).await.unwrap();
+        assert!(handle.send(StateEvent::ClientRequest { command: Command::Client(b"command".to_vec()) }));
+        handle.wait_for(|status| status.last_applied == 1).await.unwrap();
+
+        // the failure ends the snapshot in progress, so that a later request takes another one
+        let take_snapshot = async {
+            while storage.snapshot().await.unwrap().is_none() {
+                assert!(handle.send(StateEvent::TakeSnapshot { elapsed: Duration::ZERO, forced: true }));
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), take_snapshot).await.expect("snapshot not saved");
+        assert_eq!(storage.snapshot_attempts.load(Ordering::SeqCst), 2);
+    }
+}
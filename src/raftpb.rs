@@ -0,0 +1,708 @@
+//! Conversion of messages to and from the protobuf schema `raftpb` of etcd's Raft, so that messages can be
+//! inspected by tools speaking that schema, and recorded etcd traffic can be fed to tfar.
+//!
+//! Only the fields used by tfar are declared, others are skipped in decoding. Server ids are offset by one,
+//! since 0 means no server in etcd. Some events have no counterpart in the other schema:
+//! - a snapshot is sent as a whole by etcd, so only an InstallSnapshot request of a single chunk is converted,
+//!   and only the response of an installed snapshot, which is an AppendEntries response in etcd;
+//! - an empty normal entry is a no-op in etcd, so an empty client command becomes `TfarCommand::Noop`;
+//! - a configuration entry is a ConfChangeV2 whose changes describe the new configuration for tools, while
+//!   tfar's own encoding of the configuration is kept in its context, as changes alone can't restore it;
+//! - requests handled by tfar alone, such as membership changes, are not converted;
+//! - heartbeats of etcd are not converted, since they carry no previous log entry for a follower to match, and
+//!   a leader of tfar sends AppendEntries requests of no entries instead;
+//! - a snapshot must carry its configuration, which tfar can't recover otherwise.
+
+use crate::{
+    codec,
+    state_machine::{
+        events::StateEvent,
+        states::{Command, LogEntry, LogEntryId, Membership, ServerId, TfarCommand},
+    },
+};
+use std::{convert::TryFrom, fmt};
+
+/// context of a vote request for a leadership transfer, as set by etcd
+const CAMPAIGN_TRANSFER: &[u8] = b"CampaignTransfer";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum EntryType {
+    EntryNormal = 0,
+    EntryConfChange = 1,
+    EntryConfChangeV2 = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Entry {
+    #[prost(uint64, tag = "2")]
+    pub term:   u64,
+    #[prost(uint64, tag = "3")]
+    pub index:  u64,
+    #[prost(enumeration = "EntryType", tag = "1")]
+    pub r#type: i32,
+    #[prost(bytes = "vec", tag = "4")]
+    pub data:   Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConfState {
+    #[prost(uint64, repeated, tag = "1")]
+    pub voters:          Vec<u64>,
+    #[prost(uint64, repeated, tag = "2")]
+    pub learners:        Vec<u64>,
+    /// voters of the old configuration in a joint configuration
+    #[prost(uint64, repeated, tag = "3")]
+    pub voters_outgoing: Vec<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SnapshotMetadata {
+    #[prost(message, optional, tag = "1")]
+    pub conf_state: Option<ConfState>,
+    #[prost(uint64, tag = "2")]
+    pub index:      u64,
+    #[prost(uint64, tag = "3")]
+    pub term:       u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Snapshot {
+    #[prost(bytes = "vec", tag = "1")]
+    pub data:     Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub metadata: Option<SnapshotMetadata>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ConfChangeType {
+    ConfChangeAddNode = 0,
+    ConfChangeRemoveNode = 1,
+    ConfChangeUpdateNode = 2,
+    ConfChangeAddLearnerNode = 3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ConfChangeTransition {
+    ConfChangeTransitionAuto = 0,
+    ConfChangeTransitionJointImplicit = 1,
+    ConfChangeTransitionJointExplicit = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConfChangeSingle {
+    #[prost(enumeration = "ConfChangeType", tag = "1")]
+    pub r#type:  i32,
+    #[prost(uint64, tag = "2")]
+    pub node_id: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConfChangeV2 {
+    #[prost(enumeration = "ConfChangeTransition", tag = "1")]
+    pub transition: i32,
+    #[prost(message, repeated, tag = "2")]
+    pub changes:    Vec<ConfChangeSingle>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub context:    Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum MessageType {
+    MsgHup = 0,
+    MsgBeat = 1,
+    MsgProp = 2,
+    MsgApp = 3,
+    MsgAppResp = 4,
+    MsgVote = 5,
+    MsgVoteResp = 6,
+    MsgSnap = 7,
+    MsgHeartbeat = 8,
+    MsgHeartbeatResp = 9,
+    MsgUnreachable = 10,
+    MsgSnapStatus = 11,
+    MsgCheckQuorum = 12,
+    MsgTransferLeader = 13,
+    MsgTimeoutNow = 14,
+    MsgReadIndex = 15,
+    MsgReadIndexResp = 16,
+    MsgPreVote = 17,
+    MsgPreVoteResp = 18,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Message {
+    #[prost(enumeration = "MessageType", tag = "1")]
+    pub r#type:      i32,
+    #[prost(uint64, tag = "2")]
+    pub to:          u64,
+    #[prost(uint64, tag = "3")]
+    pub from:        u64,
+    #[prost(uint64, tag = "4")]
+    pub term:        u64,
+    #[prost(uint64, tag = "5")]
+    pub log_term:    u64,
+    #[prost(uint64, tag = "6")]
+    pub index:       u64,
+    #[prost(message, repeated, tag = "7")]
+    pub entries:     Vec<Entry>,
+    #[prost(uint64, tag = "8")]
+    pub commit:      u64,
+    #[prost(message, optional, tag = "9")]
+    pub snapshot:    Option<Snapshot>,
+    #[prost(bool, tag = "10")]
+    pub reject:      bool,
+    #[prost(uint64, tag = "11")]
+    pub reject_hint: u64,
+    #[prost(bytes = "vec", tag = "12")]
+    pub context:     Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConvertError {
+    /// the event or message has no counterpart in the other schema
+    Unsupported(String),
+    /// a field has an invalid value
+    Invalid(String),
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConvertError::Unsupported(what) => write!(f, "unsupported {}", what),
+            ConvertError::Invalid(what) => write!(f, "invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+/// id of `server` in etcd, where 0 means no server
+fn raft_id(server: ServerId) -> u64 {
+    server as u64 + 1
+}
+
+fn server_id(id: u64) -> Result<ServerId, ConvertError> {
+    match id {
+        0 => Err(ConvertError::Invalid("server id 0".to_string())),
+        id => Ok((id - 1) as ServerId),
+    }
+}
+
+fn raft_ids(servers: &[ServerId]) -> Vec<u64> {
+    servers.iter().map(|server| raft_id(*server)).collect()
+}
+
+fn server_ids(ids: &[u64]) -> Result<Vec<ServerId>, ConvertError> {
+    ids.iter().map(|id| server_id(*id)).collect()
+}
+
+fn conf_state(membership: &Membership) -> ConfState {
+    match membership.to_config() {
+        TfarCommand::JointConfig { old, new, learners } => ConfState {
+            voters:          raft_ids(&new),
+            learners:        raft_ids(&learners),
+            voters_outgoing: raft_ids(&old),
+        },
+        TfarCommand::NewConfig { servers, learners } => ConfState {
+            voters:          raft_ids(&servers),
+            learners:        raft_ids(&learners),
+            voters_outgoing: Vec::new(),
+        },
+        TfarCommand::Noop => unreachable!("membership is set by a configuration"),
+    }
+}
+
+fn membership(conf_state: &ConfState) -> Result<Membership, ConvertError> {
+    let voters = server_ids(&conf_state.voters)?;
+    let learners = server_ids(&conf_state.learners)?;
+    let membership = if conf_state.voters_outgoing.is_empty() {
+        Membership::new(voters)
+    } else {
+        Membership::joint(server_ids(&conf_state.voters_outgoing)?, voters)
+    };
+    Ok(membership.with_learners(learners))
+}
+
+/// a ConfChangeV2 describing configuration `config`, with tfar's encoding of it in the context
+fn conf_change(config: &TfarCommand) -> ConfChangeV2 {
+    let change = |r#type: ConfChangeType, server: &ServerId| ConfChangeSingle {
+        r#type:  r#type as i32,
+        node_id: raft_id(*server),
+    };
+    let (transition, changes) = match config {
+        TfarCommand::JointConfig { old, new, learners } => {
+            let removed = old.iter().filter(|server| !new.contains(server)).map(|server| change(ConfChangeType::ConfChangeRemoveNode, server));
+            let added = new.iter().map(|server| change(ConfChangeType::ConfChangeAddNode, server));
+            let learners = learners.iter().map(|server| change(ConfChangeType::ConfChangeAddLearnerNode, server));
+            (ConfChangeTransition::ConfChangeTransitionJointExplicit, removed.chain(added).chain(learners).collect())
+        },
+        TfarCommand::NewConfig { servers, learners } => {
+            let added = servers.iter().map(|server| change(ConfChangeType::ConfChangeAddNode, server));
+            let learners = learners.iter().map(|server| change(ConfChangeType::ConfChangeAddLearnerNode, server));
+            (ConfChangeTransition::ConfChangeTransitionAuto, added.chain(learners).collect())
+        },
+        TfarCommand::Noop => unreachable!("no-op is a normal entry"),
+    };
+    ConfChangeV2 {
+        transition: transition as i32,
+        changes,
+        context: codec::to_bytes(config),
+    }
+}
+
+impl From<&LogEntry> for Entry {
+    fn from(entry: &LogEntry) -> Entry {
+        let (r#type, data) = match &entry.command {
+            Command::Client(data) => (EntryType::EntryNormal, data.clone()),
+            Command::Tfar(TfarCommand::Noop) => (EntryType::EntryNormal, Vec::new()),
+            Command::Tfar(config) => (EntryType::EntryConfChangeV2, prost::Message::encode_to_vec(&conf_change(config))),
+        };
+        Entry {
+            term: entry.term,
+            index: entry.index,
+            r#type: r#type as i32,
+            data,
+        }
+    }
+}
+
+impl TryFrom<&Entry> for LogEntry {
+    type Error = ConvertError;
+
+    fn try_from(entry: &Entry) -> Result<LogEntry, ConvertError> {
+        let command = match EntryType::try_from(entry.r#type) {
+            Ok(EntryType::EntryNormal) if entry.data.is_empty() => Command::Tfar(TfarCommand::Noop),
+            Ok(EntryType::EntryNormal) => Command::Client(entry.data.clone()),
+            Ok(EntryType::EntryConfChangeV2) => {
+                let change: ConfChangeV2 = prost::Message::decode(entry.data.as_slice()).map_err(|e| ConvertError::Invalid(format!("configuration change: {}", e)))?;
+                let config = codec::from_bytes(&change.context).map_err(|e| ConvertError::Unsupported(format!("configuration change without tfar context: {}", e)))?;
+                Command::Tfar(config)
+            },
+            Ok(EntryType::EntryConfChange) => return Err(ConvertError::Unsupported("configuration change of version 1".to_string())),
+            Err(_) => return Err(ConvertError::Invalid(format!("entry type {}", entry.r#type))),
+        };
+        Ok(LogEntry {
+            term: entry.term,
+            index: entry.index,
+            command,
+        })
+    }
+}
+
+/// convert `event` sent to server `to` into a raftpb message
+pub fn to_message(event: &StateEvent, to: ServerId) -> Result<Message, ConvertError> {
+    let message = |r#type: MessageType, from: ServerId, term: u64| Message {
+        r#type: r#type as i32,
+        to: raft_id(to),
+        from: raft_id(from),
+        term,
+        ..Message::default()
+    };
+    let message = match event {
+        StateEvent::ElectionTimeout => Message {
+            from: raft_id(to),
+            ..message(MessageType::MsgHup, to, 0)
+        },
+        StateEvent::HeartbeatTimeout => message(MessageType::MsgBeat, to, 0),
+        StateEvent::VoteRequest { term, candidate, last_log, leader_transfer } => Message {
+            log_term: last_log.term,
+            index: last_log.index,
+            context: if *leader_transfer { CAMPAIGN_TRANSFER.to_vec() } else { Vec::new() },
+            ..message(MessageType::MsgVote, *candidate, *term)
+        },
+        StateEvent::VoteResponse { term, vote_granted, server_id } => Message {
+            reject: !vote_granted,
+            ..message(MessageType::MsgVoteResp, *server_id, *term)
+        },
+        StateEvent::PreVoteRequest { term, candidate, last_log } => Message {
+            log_term: last_log.term,
+            index: last_log.index,
+            ..message(MessageType::MsgPreVote, *candidate, *term)
+        },
+        StateEvent::PreVoteResponse { term, vote_granted, server_id } => Message {
+            reject: !vote_granted,
+            ..message(MessageType::MsgPreVoteResp, *server_id, *term)
+        },
+        StateEvent::AppendEntriesRequest { term, leader, prev_log, entries, commit_idx } => Message {
+            log_term: prev_log.term,
+            index: prev_log.index,
+            entries: entries.iter().map(Entry::from).collect(),
+            commit: *commit_idx,
+            ..message(MessageType::MsgApp, *leader, *term)
+        },
+        StateEvent::AppendEntriesResponse { term, success, server, match_idx } => Message {
+            index: *match_idx,
+            reject: !success,
+            ..message(MessageType::MsgAppResp, *server, *term)
+        },
+        StateEvent::InstallSnapshotRequest {
+            term,
+            leader,
+            last_included,
+            membership,
+            offset: 0,
+            data,
+            done: true,
+            ..
+        } => Message {
+            snapshot: Some(Snapshot {
+                data:     data.clone(),
+                metadata: Some(SnapshotMetadata {
+                    conf_state: Some(conf_state(membership)),
+                    index:      last_included.index,
+                    term:       last_included.term,
+                }),
+            }),
+            ..message(MessageType::MsgSnap, *leader, *term)
+        },
+        StateEvent::InstallSnapshotResponse { term, server, match_idx, .. } if *match_idx > 0 => Message {
+            index: *match_idx,
+            ..message(MessageType::MsgAppResp, *server, *term)
+        },
+        StateEvent::TimeoutNow { term, leader } => message(MessageType::MsgTimeoutNow, *leader, *term),
+        StateEvent::TransferLeadership { target } => message(MessageType::MsgTransferLeader, *target, 0),
+        StateEvent::ClientRequest { command } => Message {
+            entries: vec![Entry::from(&LogEntry {
+                term:    0,
+                index:   0,
+                command: command.clone(),
+            })],
+            ..message(MessageType::MsgProp, to, 0)
+        },
+        StateEvent::InstallSnapshotRequest { .. } => return Err(ConvertError::Unsupported("snapshot chunk".to_string())),
+        StateEvent::InstallSnapshotResponse { .. } => return Err(ConvertError::Unsupported("progress of a snapshot".to_string())),
+        event => return Err(ConvertError::Unsupported(format!("event {:?}", event))),
+    };
+    Ok(message)
+}
+
+/// convert a raftpb message into an event
+pub fn from_message(message: &Message) -> Result<StateEvent, ConvertError> {
+    let r#type = MessageType::try_from(message.r#type).map_err(|_| ConvertError::Invalid(format!("message type {}", message.r#type)))?;
+    let term = message.term;
+    let log_id = LogEntryId {
+        index: message.index,
+        term:  message.log_term,
+    };
+    let event = match r#type {
+        MessageType::MsgHup => StateEvent::ElectionTimeout,
+        MessageType::MsgBeat => StateEvent::HeartbeatTimeout,
+        MessageType::MsgVote => StateEvent::VoteRequest {
+            term,
+            candidate: server_id(message.from)?,
+            last_log: log_id,
+            leader_transfer: message.context == CAMPAIGN_TRANSFER,
+        },
+        MessageType::MsgVoteResp => StateEvent::VoteResponse {
+            term,
+            vote_granted: !message.reject,
+            server_id: server_id(message.from)?,
+        },
+        MessageType::MsgPreVote => StateEvent::PreVoteRequest {
+            term,
+            candidate: server_id(message.from)?,
+            last_log: log_id,
+        },
+        MessageType::MsgPreVoteResp => StateEvent::PreVoteResponse {
+            term,
+            vote_granted: !message.reject,
+            server_id: server_id(message.from)?,
+        },
+        MessageType::MsgApp => StateEvent::AppendEntriesRequest {
+            term,
+            leader: server_id(message.from)?,
+            prev_log: log_id,
+            entries: message.entries.iter().map(LogEntry::try_from).collect::<Result<_, _>>()?,
+            commit_idx: message.commit,
+        },
+        MessageType::MsgAppResp => StateEvent::AppendEntriesResponse {
+            term,
+            success: !message.reject,
+            server: server_id(message.from)?,
+            match_idx: if message.reject { 0 } else { message.index },
+        },
+        MessageType::MsgSnap => {
+            let snapshot = message.snapshot.as_ref().ok_or_else(|| ConvertError::Invalid("snapshot message without snapshot".to_string()))?;
+            let metadata = snapshot.metadata.as_ref().ok_or_else(|| ConvertError::Invalid("snapshot without metadata".to_string()))?;
+            // etcd always encodes a configuration, which is empty if it's not set
+            let conf_state = metadata
+                .conf_state
+                .as_ref()
+                .filter(|conf_state| !conf_state.voters.is_empty())
+                .ok_or_else(|| ConvertError::Invalid("snapshot without configuration".to_string()))?;
+            StateEvent::InstallSnapshotRequest {
+                term,
+                leader: server_id(message.from)?,
+                last_included: LogEntryId { index: metadata.index, term: metadata.term },
+                membership: membership(conf_state)?,
+                offset: 0,
+                data: snapshot.data.clone(),
+                done: true,
+                checksum: crc32fast::hash(&snapshot.data),
+            }
+        },
+        MessageType::MsgTimeoutNow => StateEvent::TimeoutNow { term, leader: server_id(message.from)? },
+        MessageType::MsgTransferLeader => StateEvent::TransferLeadership { target: server_id(message.from)? },
+        MessageType::MsgProp => match message.entries.as_slice() {
+            [entry] => StateEvent::ClientRequest { command: LogEntry::try_from(entry)?.command },
+            entries => return Err(ConvertError::Unsupported(format!("proposal of {} entries", entries.len()))),
+        },
+        r#type => return Err(ConvertError::Unsupported(format!("message type {:?}", r#type))),
+    };
+    Ok(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message as _;
+    use std::time::Duration;
+
+    fn round_trip(event: StateEvent) {
+        let bytes = to_message(&event, 1).unwrap().encode_to_vec();
+        assert_eq!(from_message(&Message::decode(bytes.as_slice()).unwrap()).unwrap(), event);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let last_log = LogEntryId { index: 5, term: 2 };
+        let entries = vec![
+            LogEntry {
+                term:    2,
+                index:   6,
+                command: Command::Tfar(TfarCommand::Noop),
+            },
+            LogEntry {
+                term:    2,
+                index:   7,
+                command: Command::Client(b"x".to_vec()),
+            },
+            LogEntry {
+                term:    2,
+                index:   8,
+                command: Command::Tfar(TfarCommand::JointConfig {
+                    old:      vec![0, 1, 2],
+                    new:      vec![1, 2, 3],
+                    learners: vec![4],
+                }),
+            },
+            LogEntry {
+                term:    2,
+                index:   9,
+                command: Command::Tfar(TfarCommand::NewConfig { servers: vec![1, 2, 3], learners: vec![] }),
+            },
+        ];
+        let data = b"0123456789".to_vec();
+        let events = vec![
+            StateEvent::ElectionTimeout,
+            StateEvent::HeartbeatTimeout,
+            StateEvent::VoteRequest {
+                term: 3,
+                candidate: 0,
+                last_log,
+                leader_transfer: false,
+            },
+            StateEvent::VoteRequest {
+                term: 3,
+                candidate: 0,
+                last_log,
+                leader_transfer: true,
+            },
+            StateEvent::VoteResponse {
+                term:         3,
+                vote_granted: true,
+                server_id:    2,
+            },
+            StateEvent::VoteResponse {
+                term:         3,
+                vote_granted: false,
+                server_id:    2,
+            },
+            StateEvent::PreVoteRequest { term: 3, candidate: 0, last_log },
+            StateEvent::PreVoteResponse {
+                term:         3,
+                vote_granted: false,
+                server_id:    2,
+            },
+            StateEvent::AppendEntriesRequest {
+                term: 2,
+                leader: 0,
+                prev_log: last_log,
+                entries,
+                commit_idx: 4,
+            },
+            StateEvent::AppendEntriesResponse {
+                term:      2,
+                success:   true,
+                server:    2,
+                match_idx: 9,
+            },
+            StateEvent::AppendEntriesResponse {
+                term:      2,
+                success:   false,
+                server:    2,
+                match_idx: 0,
+            },
+            StateEvent::InstallSnapshotRequest {
+                term: 2,
+                leader: 0,
+                last_included: last_log,
+                membership: Membership::joint(vec![0, 1, 2], vec![1, 2]).with_learners(vec![3]),
+                offset: 0,
+                checksum: crc32fast::hash(&data),
+                data,
+                done: true,
+            },
+            StateEvent::TimeoutNow { term: 2, leader: 0 },
+            StateEvent::TransferLeadership { target: 2 },
+            StateEvent::ClientRequest { command: Command::Client(b"x".to_vec()) },
+        ];
+        for event in events {
+            round_trip(event);
+        }
+    }
+
+    #[test]
+    fn convert_events_without_counterpart() {
+        let chunk = StateEvent::InstallSnapshotRequest {
+            term:          2,
+            leader:        0,
+            last_included: LogEntryId { index: 5, term: 2 },
+            membership:    Membership::new(vec![0, 1, 2]),
+            offset:        0,
+            data:          b"01234".to_vec(),
+            done:          false,
+            checksum:      0,
+        };
+        assert!(matches!(to_message(&chunk, 1), Err(ConvertError::Unsupported(_))));
+        assert!(matches!(to_message(&StateEvent::TakeSnapshot { elapsed: Duration::ZERO, forced: true }, 1), Err(ConvertError::Unsupported(_))));
+
+        // the response of an installed snapshot is an AppendEntries response
+        let installed = StateEvent::InstallSnapshotResponse {
+            term:      2,
+            server:    1,
+            match_idx: 5,
+            offset:    10,
+        };
+        let message = to_message(&installed, 0).unwrap();
+        assert_eq!(
+            from_message(&message).unwrap(),
+            StateEvent::AppendEntriesResponse {
+                term:      2,
+                success:   true,
+                server:    1,
+                match_idx: 5,
+            }
+        );
+
+        // an empty client command is a no-op
+        let entry = Entry::from(&LogEntry {
+            term:    1,
+            index:   1,
+            command: Command::Client(vec![]),
+        });
+        assert_eq!(LogEntry::try_from(&entry).unwrap().command, Command::Tfar(TfarCommand::Noop));
+    }
+
+    #[test]
+    fn decode_etcd_messages() {
+        // Messages from 1 to 2 in term 3 as marshalled by etcd's raftpb, which writes all fields of scalar types in
+        // the order of their numbers, with field 13 (vote) last. Its snapshot and context are omitted if they are
+        // not set, and fields of a snapshot are always written.
+        let header = |r#type: MessageType| vec![0x08, r#type as u8, 0x10, 0x02, 0x18, 0x01, 0x20, 0x03];
+        let trailer = [0x50, 0x00, 0x58, 0x00, 0x68, 0x00];
+
+        // MsgApp after entry 5 of term 2 with an entry of data "x", and commit index 5
+        let mut bytes = header(MessageType::MsgApp);
+        bytes.extend_from_slice(&[0x28, 0x02, 0x30, 0x05, 0x3a, 0x09, 0x08, 0x00, 0x10, 0x03, 0x18, 0x06, 0x22, 0x01, b'x', 0x40, 0x05]);
+        bytes.extend_from_slice(&trailer);
+        let event = from_message(&Message::decode(bytes.as_slice()).unwrap()).unwrap();
+        assert_eq!(
+            event,
+            StateEvent::AppendEntriesRequest {
+                term:       3,
+                leader:     0,
+                prev_log:   LogEntryId { index: 5, term: 2 },
+                entries:    vec![LogEntry {
+                    term:    3,
+                    index:   6,
+                    command: Command::Client(b"x".to_vec()),
+                }],
+                commit_idx: 5,
+            }
+        );
+
+        // MsgSnap of data "snap" at entry 5 of term 2, with voters 1, 2 and 3 in its configuration
+        let snapshot = |conf_state: &[u8]| {
+            let mut metadata = vec![0x0a, conf_state.len() as u8];
+            metadata.extend_from_slice(conf_state);
+            metadata.extend_from_slice(&[0x10, 0x05, 0x18, 0x02]);
+            let mut snapshot = vec![0x0a, 0x04, b's', b'n', b'a', b'p', 0x12, metadata.len() as u8];
+            snapshot.extend(metadata);
+            let mut bytes = header(MessageType::MsgSnap);
+            bytes.extend_from_slice(&[0x28, 0x00, 0x30, 0x00, 0x40, 0x00, 0x4a, snapshot.len() as u8]);
+            bytes.extend(snapshot);
+            bytes.extend_from_slice(&trailer);
+            Message::decode(bytes.as_slice()).unwrap()
+        };
+        // voters are not packed, and auto_leave is always written
+        let event = from_message(&snapshot(&[0x08, 0x01, 0x08, 0x02, 0x08, 0x03, 0x28, 0x00])).unwrap();
+        assert_eq!(
+            event,
+            StateEvent::InstallSnapshotRequest {
+                term:          3,
+                leader:        0,
+                last_included: LogEntryId { index: 5, term: 2 },
+                membership:    Membership::new(vec![0, 1, 2]),
+                offset:        0,
+                data:          b"snap".to_vec(),
+                done:          true,
+                checksum:      crc32fast::hash(b"snap"),
+            }
+        );
+        // a snapshot without a configuration is rejected, rather than installed with no voters
+        assert!(matches!(from_message(&snapshot(&[0x28, 0x00])), Err(ConvertError::Invalid(_))));
+        let mut message = snapshot(&[]);
+        message.snapshot.as_mut().unwrap().metadata.as_mut().unwrap().conf_state = None;
+        assert!(matches!(from_message(&message), Err(ConvertError::Invalid(_))));
+
+        // heartbeats of etcd have no counterpart, nor have their responses
+        let mut bytes = header(MessageType::MsgHeartbeat);
+        bytes.extend_from_slice(&[0x28, 0x00, 0x30, 0x00, 0x40, 0x05]);
+        bytes.extend_from_slice(&trailer);
+        assert!(matches!(from_message(&Message::decode(bytes.as_slice()).unwrap()), Err(ConvertError::Unsupported(_))));
+        let mut bytes = header(MessageType::MsgHeartbeatResp);
+        bytes.extend_from_slice(&[0x28, 0x00, 0x30, 0x00, 0x40, 0x00]);
+        bytes.extend_from_slice(&trailer);
+        assert!(matches!(from_message(&Message::decode(bytes.as_slice()).unwrap()), Err(ConvertError::Unsupported(_))));
+
+        // nor has a message from no server
+        let vote = Message {
+            r#type: MessageType::MsgVote as i32,
+            ..Message::default()
+        };
+        assert!(matches!(from_message(&vote), Err(ConvertError::Invalid(_))));
+        let unknown = Message { r#type: 100, ..Message::default() };
+        assert!(matches!(from_message(&unknown), Err(ConvertError::Invalid(_))));
+
+        // configuration changes of etcd can't be restored without the configuration of tfar
+        let change = ConfChangeV2 {
+            changes: vec![ConfChangeSingle {
+                r#type:  ConfChangeType::ConfChangeAddNode as i32,
+                node_id: 4,
+            }],
+            ..ConfChangeV2::default()
+        };
+        let entry = Entry {
+            term:   1,
+            index:  1,
+            r#type: EntryType::EntryConfChangeV2 as i32,
+            data:   change.encode_to_vec(),
+        };
+        assert!(matches!(LogEntry::try_from(&entry), Err(ConvertError::Unsupported(_))));
+    }
+}
@@ -1,4 +1,5 @@
+pub mod actions;
 pub mod events;
 mod machines;
 pub mod states;
-pub use machines::{candidate::Candidate, follower::Follower, leader::Leader, StateMachine};
+pub use machines::{candidate::Candidate, follower::Follower, leader::Leader, pre_candidate::PreCandidate, Role, StateMachine, Status, Transition};
@@ -0,0 +1,24 @@
+use super::{
+    events::StateEvent,
+    states::{LogEntryId, LogEntryIndex, Membership, ServerId},
+};
+
+/// Side effects of a state transition, which should be executed by the host in order.
+///
+/// Timers are one-shot, the host should feed a timeout event to the state machine when a timer fires,
+/// and a timer is only restarted by a reset action.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    /// send an event to the server
+    SendMessage(ServerId, StateEvent),
+    /// the client command in log entry at `index` was applied, with the response for client
+    Applied { index: LogEntryIndex, response: Vec<u8> },
+    /// restart the election timer with a randomized timeout, e.g. on a valid message from current leader
+    ResetElectionTimer,
+    /// restart the timer for leader sending the next heartbeats
+    ResetHeartbeatTimer,
+    /// Take a snapshot of the application at `last_included`, which is the last applied entry, and save it to
+    /// storage in background. No entry should be applied to the application until the snapshot is taken. A
+    /// SnapshotSaved event is fed back when it's saved, and log entries replaced by it are discarded only after then.
+    SaveSnapshot { last_included: LogEntryId, membership: Membership },
+}
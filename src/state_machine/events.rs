@@ -1,8 +1,12 @@
-use super::states::{LogEntry, LogEntryId, LogEntryIndex, ServerId, TermId};
+use super::states::{Command, LogEntry, LogEntryId, LogEntryIndex, Membership, ServerId, Snapshot, TermId};
 use std::time::Duration;
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum StateEvent {
-    Timeout(Duration),
+    /// election timer fired without hearing from a leader or granting a vote
+    ElectionTimeout,
+    /// heartbeat timer of leader fired
+    HeartbeatTimeout,
     VoteRequest {
         /// candidate's term
         term: TermId,
@@ -10,6 +14,9 @@ pub enum StateEvent {
         candidate: ServerId,
         /// id of candidate's last log entry
         last_log: LogEntryId,
+        /// true if the election is started for a leadership transfer, voters should not ignore it even though
+        /// they are hearing from the leader. this field is an extention by tfar
+        leader_transfer: bool,
     },
     VoteResponse {
         /// Current Term from requested server, for candidate to update itself
@@ -19,6 +26,24 @@ pub enum StateEvent {
         /// response server, this field is an extention by tfar
         server_id: ServerId,
     },
+    /// Sent before starting an election to check whether the sender could win it, this doesn't change
+    /// the term or the vote of any server.
+    PreVoteRequest {
+        /// the term that the sender would use in its next election
+        term: TermId,
+        /// server requesting pre-vote
+        candidate: ServerId,
+        /// id of sender's last log entry
+        last_log: LogEntryId,
+    },
+    PreVoteResponse {
+        /// the requested term if granted, otherwise the current term from requested server
+        term: TermId,
+        /// true means the requested server would grant the vote in an election
+        vote_granted: bool,
+        /// response server
+        server_id: ServerId,
+    },
     AppendEntriesRequest {
         /// leader's term
         term: TermId,
@@ -38,5 +63,112 @@ pub enum StateEvent {
         success: bool,
         /// response server, this field is an extention by tfar
         server:ServerId,
+        /// index of the last new entry when success, this field is an extention by tfar
+        match_idx: LogEntryIndex,
     },
+    /// Sent by leader instead of AppendEntries when entries needed by a follower were replaced by the snapshot, which
+    /// is sent in chunks
+    InstallSnapshotRequest {
+        /// leader's term
+        term: TermId,
+        /// leader id, for followers to redirect clients
+        leader: ServerId,
+        /// id of the last log entry replaced by the snapshot
+        last_included: LogEntryId,
+        /// membership as of the last included entry, this field is an extention by tfar
+        membership: Membership,
+        /// byte offset where the chunk is positioned in the snapshot
+        offset: u64,
+        /// raw bytes of the chunk, starting at offset
+        data: Vec<u8>,
+        /// true if this is the last chunk
+        done: bool,
+        /// crc32 checksum of the whole snapshot, only set in the last chunk. this field is an extention by tfar
+        checksum: u32,
+    },
+    InstallSnapshotResponse {
+        /// current Term, for leader to update itself
+        term: TermId,
+        /// response server, this field is an extention by tfar
+        server: ServerId,
+        /// index of the last entry replaced by the installed snapshot, 0 if the request is denied or the snapshot is
+        /// incomplete. this field is an extention by tfar
+        match_idx: LogEntryIndex,
+        /// bytes of the snapshot received, where the leader should continue from. this field is an extention by tfar
+        offset: u64,
+    },
+    /// Periodic tick of the host to take a snapshot of the application at the last applied entry, log entries
+    /// replaced by it are discarded. A snapshot is taken if it's forced or the snapshot policy is met.
+    /// this event is an extention by tfar
+    TakeSnapshot {
+        /// time elapsed since the previous tick, which is added to the time since the last snapshot
+        elapsed: Duration,
+        /// take a snapshot regardless of the snapshot policy, e.g. requested by a client
+        forced: bool,
+    },
+    /// A snapshot taken by the server was saved to storage by the host, as requested by a SaveSnapshot action.
+    /// this event is an extention by tfar
+    SnapshotSaved { snapshot: Snapshot },
+    /// Taking or saving a snapshot requested by a SaveSnapshot action failed, the server may take another one later.
+    /// this event is an extention by tfar
+    SnapshotFailed,
+    /// Sent by leader to the target of a leadership transfer, which starts an election right away
+    TimeoutNow {
+        /// leader's term
+        term: TermId,
+        /// leader id
+        leader: ServerId,
+    },
+    /// Request to transfer leadership to `target`, which is only accepted by leader. this event is an extention by tfar
+    TransferLeadership {
+        /// server to be the next leader
+        target: ServerId,
+    },
+    /// Request to change servers of the cluster to `servers` through joint consensus, which is only accepted by leader.
+    /// this event is an extention by tfar
+    ChangeMembership {
+        /// servers in the new configuration
+        servers: Vec<ServerId>,
+    },
+    /// Request to add `server` to the cluster after catching it up, which is only accepted by leader.
+    /// this event is an extention by tfar
+    AddServer {
+        /// the new server
+        server: ServerId,
+    },
+    /// Request to remove `server` from the cluster, which is only accepted by leader. this event is an extention by tfar
+    RemoveServer {
+        /// the server to remove
+        server: ServerId,
+    },
+    /// Request to add `server` to the cluster as a learner, which receives log replication without voting. It's
+    /// only accepted by leader. this event is an extention by tfar
+    AddLearner {
+        /// the new learner
+        server: ServerId,
+    },
+    /// Request to promote learner `server` to a voter once it has caught up with leader, which is only accepted
+    /// by leader. this event is an extention by tfar
+    PromoteLearner {
+        /// the learner to promote
+        server: ServerId,
+    },
+    /// A command proposed by client, which is only accepted by leader. this event is an extention by tfar
+    ClientRequest {
+        /// command to be replicated and applied
+        command: Command,
+    },
+}
+
+impl StateEvent {
+    /// the server sending this message, None for events which are not messages between servers
+    pub fn sender(&self) -> Option<ServerId> {
+        match self {
+            StateEvent::VoteRequest { candidate, .. } | StateEvent::PreVoteRequest { candidate, .. } => Some(*candidate),
+            StateEvent::VoteResponse { server_id, .. } | StateEvent::PreVoteResponse { server_id, .. } => Some(*server_id),
+            StateEvent::AppendEntriesRequest { leader, .. } | StateEvent::InstallSnapshotRequest { leader, .. } | StateEvent::TimeoutNow { leader, .. } => Some(*leader),
+            StateEvent::AppendEntriesResponse { server, .. } | StateEvent::InstallSnapshotResponse { server, .. } => Some(*server),
+            _ => None,
+        }
+    }
 }
@@ -1,11 +1,729 @@
 use async_trait::async_trait;
+use std::io;
 pub mod candidate;
 pub mod follower;
 pub mod leader;
+pub mod pre_candidate;
 
-use super::events::StateEvent;
+use super::{
+    actions::Action,
+    events::StateEvent,
+    states::{InternalState, LogEntryId, LogEntryIndex, PersistentState, ServerId, ServerVolatileState, Snapshot, TermId},
+};
+use candidate::Candidate;
+use pre_candidate::PreCandidate;
 
 #[async_trait]
-pub trait StateMachine {
-    async fn on_events(self, event: StateEvent) -> Box<dyn StateMachine>;
+pub trait StateMachine: Send {
+    /// Handle `event`, returning the next state machine with actions for the host. A storage error is returned
+    /// instead, and the state machine is dropped since its states may be changed halfway, the server should stop.
+    async fn on_events(self: Box<Self>, event: StateEvent) -> io::Result<Transition>;
+
+    /// a summary of current states, for the host to report
+    fn status(&self) -> Status;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Follower,
+    PreCandidate,
+    Candidate,
+    Leader,
+}
+
+/// A summary of a server's states
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub id:           ServerId,
+    pub role:         Role,
+    pub term:         TermId,
+    /// the leader current server knows, which is itself for a leader
+    pub leader:       Option<ServerId>,
+    pub commit_index: LogEntryIndex,
+    pub last_applied: LogEntryIndex,
+}
+
+/// Result of a state transition: the next state machine, and actions to be executed by the host in order.
+pub struct Transition {
+    pub next:    Box<dyn StateMachine>,
+    pub actions: Vec<Action>,
+}
+
+impl<S: StateMachine + 'static> From<(S, Vec<Action>)> for Transition {
+    fn from((next, actions): (S, Vec<Action>)) -> Transition {
+        Transition { next: Box::new(next), actions }
+    }
+}
+
+fn status(role: Role, persistent: &PersistentState, volatile: &ServerVolatileState, internal: &InternalState) -> Status {
+    Status {
+        id: internal.id(),
+        role,
+        term: persistent.term(),
+        leader: internal.leader(),
+        commit_index: volatile.commit_index,
+        last_applied: volatile.last_applied,
+    }
+}
+
+/// create an action responding a vote request from `candidate`
+fn vote_response(term: TermId, server_id: ServerId, candidate: ServerId, vote_granted: bool) -> Action {
+    let response = StateEvent::VoteResponse { term, vote_granted, server_id };
+    Action::SendMessage(candidate, response)
+}
+
+/// Create an action responding a pre-vote request from `candidate`. The vote would be granted if the
+/// request is for a newer term with an up-to-date log, and current server hasn't heard from a leader
+/// since its last election timeout. No state is changed by a pre-vote.
+fn pre_vote_response(persistent: &PersistentState, internal: &InternalState, term: TermId, candidate: ServerId, last_log: &LogEntryId) -> Action {
+    let vote_granted = term > persistent.term() && persistent.is_up_to_date(last_log) && !internal.knows_leader();
+    let response = StateEvent::PreVoteResponse {
+        term: if vote_granted { term } else { persistent.term() },
+        vote_granted,
+        server_id: internal.id(),
+    };
+    Action::SendMessage(candidate, response)
+}
+
+/// start a new election after election timeout, with a Pre-Vote round first if it is enabled
+async fn start_election(persistent: PersistentState, volatile: ServerVolatileState, internal: InternalState) -> io::Result<Transition> {
+    if internal.config().pre_vote {
+        PreCandidate::new(persistent, volatile, internal).start_pre_vote().await
+    } else {
+        Candidate::new(persistent, volatile, internal).start_election(false).await
+    }
+}
+
+/// Take a snapshot of the application at the last applied entry if it is `forced` or the snapshot policy is met,
+/// which is taken and saved by the host in background. Nothing happens if no entry was applied since the last
+/// snapshot, or a snapshot is still being saved.
+fn start_snapshot(persistent: &PersistentState, volatile: &ServerVolatileState, internal: InternalState, forced: bool) -> (InternalState, Vec<Action>) {
+    let applied = volatile.last_applied.saturating_sub(persistent.snapshot_index());
+    if applied == 0 || internal.snapshotting() {
+        return (internal, Vec::new());
+    }
+    let policy = &internal.config().snapshot_policy;
+    let log_bytes = if policy.uses_log_bytes() { persistent.log_bytes() } else { 0 };
+    if !forced && !policy.is_met(applied, log_bytes, internal.since_last_snapshot()) {
+        return (internal, Vec::new());
+    }
+    let last_included = LogEntryId {
+        index: volatile.last_applied,
+        term:  persistent.term_of(volatile.last_applied).expect("applied log entry is missing"),
+    };
+    let membership = internal.membership_at(persistent, volatile.last_applied);
+    (internal.start_snapshot(), vec![Action::SaveSnapshot { last_included, membership }])
+}
+
+/// Discard log entries replaced by a snapshot saved in background, except the trailing ones retained by the
+/// snapshot policy. A snapshot older than the current one, e.g. one installed from leader meanwhile, is ignored.
+async fn snapshot_saved(persistent: PersistentState, internal: InternalState, snapshot: Snapshot) -> io::Result<(PersistentState, InternalState)> {
+    let internal = internal.finish_snapshot();
+    if snapshot.last_included.index <= persistent.snapshot_index() {
+        return Ok((persistent, internal));
+    }
+    let persistent = persistent.with_snapshot(snapshot, internal.config().snapshot_policy.trailing_entries);
+    persistent.compact_entries().await?;
+    Ok((persistent, internal))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{follower::Follower, *};
+    use crate::{
+        application::Application,
+        state_machine::states::{Command, Config, LogEntry, LogEntryIndex, Membership, SnapshotPolicy},
+        storage::{MemStorage, SnapshotReader, SnapshotWriter, Storage},
+        testing::Noop,
+    };
+    use async_trait::async_trait;
+    use std::{
+        collections::{HashSet, VecDeque},
+        io,
+        sync::Arc,
+        time::Duration,
+    };
+    use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+    /// servers connected by a network in which some of them may be isolated
+    struct Cluster {
+        servers:      Vec<Option<Box<dyn StateMachine>>>,
+        storages:     Vec<Arc<MemStorage>>,
+        applications: Vec<Arc<Mutex<Box<dyn Application>>>>,
+        isolated:     HashSet<ServerId>,
+        /// index of the last applied client command on each server
+        applied:      Vec<LogEntryIndex>,
+    }
+
+    impl Cluster {
+        fn new(num_servers: usize, pre_vote: bool) -> Cluster {
+            Cluster::with_members(num_servers, (0..num_servers).collect(), pre_vote)
+        }
+
+        /// create a cluster of `num_servers` servers, where only `members` are in the initial configuration
+        fn with_members(num_servers: usize, members: Vec<ServerId>, pre_vote: bool) -> Cluster {
+            Cluster::with_config(num_servers, members, Config { pre_vote, ..Config::default() })
+        }
+
+        fn with_config(num_servers: usize, members: Vec<ServerId>, config: Config) -> Cluster {
+            let storages: Vec<Arc<MemStorage>> = (0..num_servers).map(|_| Arc::new(MemStorage::new())).collect();
+            let mut servers = Vec::new();
+            let mut applications = Vec::new();
+            for (id, storage) in storages.iter().enumerate() {
+                let internal = InternalState::new(id, members.clone()).with_config(config.clone());
+                let volatile = ServerVolatileState::new(Box::new(Noop));
+                applications.push(volatile.application());
+                let follower = Follower::new(PersistentState::with_storage(storage.clone()), volatile, internal);
+                servers.push(Some(Box::new(follower) as Box<dyn StateMachine>));
+            }
+            Cluster {
+                servers,
+                storages,
+                applications,
+                isolated: HashSet::new(),
+                applied: vec![0; num_servers],
+            }
+        }
+
+        /// deliver `event` to `server`, and then all messages caused by it until the network is quiet
+        async fn send(&mut self, server: ServerId, event: StateEvent) {
+            let mut messages = VecDeque::new();
+            messages.push_back((server, event));
+            while let Some((to, event)) = messages.pop_front() {
+                let Transition { next, actions } = self.servers[to].take().unwrap().on_events(event).await.unwrap();
+                self.servers[to] = Some(next);
+                for action in actions {
+                    match action {
+                        Action::SendMessage(dest, message) if !self.isolated.contains(&to) && !self.isolated.contains(&dest) => messages.push_back((dest, message)),
+                        Action::Applied { index, .. } => self.applied[to] = index,
+                        Action::SaveSnapshot { last_included, membership } => {
+                            let mut writer = self.storages[to].create_snapshot().await.unwrap();
+                            self.applications[to].lock().await.snapshot(&mut writer).await.unwrap();
+                            writer.shutdown().await.unwrap();
+                            let snapshot = Snapshot::new(last_included, membership, &writer);
+                            self.storages[to].save_snapshot(&snapshot).await.unwrap();
+                            messages.push_back((to, StateEvent::SnapshotSaved { snapshot }));
+                        },
+                        _ => {},
+                    }
+                }
+            }
+        }
+
+        async fn terms(&self) -> Vec<TermId> {
+            let mut terms = Vec::new();
+            for storage in &self.storages {
+                terms.push(storage.hard_state().await.unwrap().0);
+            }
+            terms
+        }
+
+        async fn last_indexes(&self) -> Vec<LogEntryIndex> {
+            let mut indexes = Vec::new();
+            for storage in &self.storages {
+                indexes.push(storage.last_index().await.unwrap());
+            }
+            indexes
+        }
+    }
+
+    fn command() -> StateEvent {
+        StateEvent::ClientRequest {
+            command: Command::Client(b"command".to_vec()),
+        }
+    }
+
+    fn take_snapshot(elapsed: Duration, forced: bool) -> StateEvent {
+        StateEvent::TakeSnapshot { elapsed, forced }
+    }
+
+    #[tokio::test]
+    async fn lagging_follower_installs_snapshot() {
+        let config = Config {
+            snapshot_chunk_size: 4,
+            ..Config::default()
+        };
+        let mut cluster = Cluster::with_config(3, vec![0, 1, 2], config);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+        cluster.isolated.insert(2);
+        for _ in 0..3 {
+            cluster.send(0, command()).await;
+        }
+        cluster.send(0, take_snapshot(Duration::ZERO, true)).await;
+        let snapshot = cluster.storages[0].snapshot().await.unwrap().unwrap();
+        assert_eq!(snapshot.last_included, LogEntryId { index: 3, term: 1 });
+        assert!(cluster.storages[0].read_entries(1..4).await.unwrap().is_empty());
+
+        // entries needed by server 2 were discarded, so it's sent the snapshot in chunks instead
+        cluster.isolated.clear();
+        cluster.send(0, StateEvent::HeartbeatTimeout).await;
+        assert_eq!(cluster.storages[2].snapshot().await.unwrap(), Some(snapshot));
+        assert_eq!(cluster.last_indexes().await, vec![3, 3, 3]);
+
+        // and replication continues after the snapshot
+        cluster.send(0, command()).await;
+        cluster.send(0, StateEvent::HeartbeatTimeout).await;
+        assert_eq!(cluster.last_indexes().await, vec![4, 4, 4]);
+        assert_eq!(cluster.applied, vec![4, 4, 4]);
+    }
+
+    async fn entry_indexes(storage: &MemStorage) -> Vec<LogEntryIndex> {
+        let last_index = storage.last_index().await.unwrap();
+        storage.read_entries(1..last_index + 1).await.unwrap().into_iter().map(|entry| entry.index).collect()
+    }
+
+    #[tokio::test]
+    async fn snapshot_by_policy_retains_trailing_entries() {
+        let config = Config {
+            snapshot_policy: SnapshotPolicy {
+                applied_entries: 2,
+                trailing_entries: 2,
+                ..SnapshotPolicy::default()
+            },
+            ..Config::default()
+        };
+        let mut cluster = Cluster::with_config(3, vec![0, 1, 2], config);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+        cluster.send(0, command()).await;
+        cluster.send(0, StateEvent::HeartbeatTimeout).await;
+        assert_eq!(cluster.storages[0].snapshot().await.unwrap(), None);
+
+        cluster.isolated.insert(2);
+        cluster.send(0, command()).await;
+        cluster.send(0, command()).await;
+        let snapshot = cluster.storages[0].snapshot().await.unwrap().unwrap();
+        assert_eq!(snapshot.last_included, LogEntryId { index: 2, term: 1 });
+        assert_eq!(entry_indexes(&cluster.storages[0]).await, vec![1, 2, 3]);
+
+        // server 2 catches up with the trailing entries rather than the snapshot, and then takes its own snapshot
+        cluster.isolated.clear();
+        cluster.send(0, StateEvent::HeartbeatTimeout).await;
+        assert_eq!(cluster.last_indexes().await, vec![3, 3, 3]);
+        let snapshot = cluster.storages[2].snapshot().await.unwrap().unwrap();
+        assert_eq!(snapshot.last_included, LogEntryId { index: 3, term: 1 });
+
+        cluster.send(0, command()).await;
+        let snapshot = cluster.storages[0].snapshot().await.unwrap().unwrap();
+        assert_eq!(snapshot.last_included, LogEntryId { index: 4, term: 1 });
+        assert_eq!(entry_indexes(&cluster.storages[0]).await, vec![3, 4]);
+    }
+
+    #[tokio::test]
+    async fn snapshot_by_policy_after_interval_of_ticks() {
+        let config = Config {
+            snapshot_policy: SnapshotPolicy {
+                interval: Some(Duration::from_secs(10)),
+                ..SnapshotPolicy::default()
+            },
+            ..Config::default()
+        };
+        let mut cluster = Cluster::with_config(1, vec![0], config);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+        cluster.send(0, command()).await;
+        cluster.send(0, take_snapshot(Duration::from_secs(6), false)).await;
+        assert_eq!(cluster.storages[0].snapshot().await.unwrap(), None);
+
+        // time elapsed of ticks adds up
+        cluster.send(0, take_snapshot(Duration::from_secs(6), false)).await;
+        let snapshot = cluster.storages[0].snapshot().await.unwrap().unwrap();
+        assert_eq!(snapshot.last_included, LogEntryId { index: 1, term: 1 });
+
+        // and starts over after the snapshot
+        cluster.send(0, command()).await;
+        cluster.send(0, take_snapshot(Duration::from_secs(6), false)).await;
+        assert_eq!(cluster.storages[0].snapshot().await.unwrap(), Some(snapshot));
+    }
+
+    /// deliver a chunk of snapshot "0123456789" to `server`, returns the match index and the offset in its response
+    async fn send_snapshot_chunk(server: &mut Option<Box<dyn StateMachine>>, offset: usize, len: usize, checksum: u32) -> (LogEntryIndex, u64) {
+        let data = b"0123456789";
+        let request = StateEvent::InstallSnapshotRequest {
+            term:          1,
+            leader:        0,
+            last_included: LogEntryId { index: 5, term: 1 },
+            membership:    Membership::new(vec![0, 1, 2]),
+            offset:        offset as u64,
+            data:          data[offset..offset + len].to_vec(),
+            done:          offset + len == data.len(),
+            checksum,
+        };
+        let Transition { next, actions } = server.take().unwrap().on_events(request).await.unwrap();
+        *server = Some(next);
+        match actions.first() {
+            Some(Action::SendMessage(0, StateEvent::InstallSnapshotResponse { match_idx, offset, .. })) => (*match_idx, *offset),
+            _ => panic!("no InstallSnapshot response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn follower_resumes_snapshot_chunks_and_verifies_checksum() {
+        let storage = Arc::new(MemStorage::new());
+        let follower = Follower::new(PersistentState::with_storage(storage.clone()), ServerVolatileState::new(Box::new(Noop)), InternalState::new(1, vec![0, 1, 2]));
+        let mut server: Option<Box<dyn StateMachine>> = Some(Box::new(follower));
+        let checksum = crc32fast::hash(b"0123456789");
+
+        assert_eq!(send_snapshot_chunk(&mut server, 0, 4, 0).await, (0, 4));
+        // a retried chunk or a chunk after a gap asks the leader to resume from the received bytes
+        assert_eq!(send_snapshot_chunk(&mut server, 0, 4, 0).await, (0, 4));
+        assert_eq!(send_snapshot_chunk(&mut server, 8, 2, checksum).await, (0, 4));
+        assert_eq!(send_snapshot_chunk(&mut server, 4, 4, 0).await, (0, 8));
+
+        // the snapshot is received again from the beginning if the checksum mismatches
+        assert_eq!(send_snapshot_chunk(&mut server, 8, 2, checksum + 1).await, (0, 0));
+        assert_eq!(storage.snapshot().await.unwrap(), None);
+        assert_eq!(send_snapshot_chunk(&mut server, 4, 4, 0).await, (0, 0));
+
+        assert_eq!(send_snapshot_chunk(&mut server, 0, 4, 0).await, (0, 4));
+        assert_eq!(send_snapshot_chunk(&mut server, 4, 4, 0).await, (0, 8));
+        assert_eq!(send_snapshot_chunk(&mut server, 8, 2, checksum).await, (5, 0));
+        let (snapshot, data) = storage.read_snapshot(0, 16).await.unwrap().unwrap();
+        assert_eq!((snapshot.size, snapshot.checksum), (10, checksum));
+        assert_eq!(data, b"0123456789".to_vec());
+        assert_eq!(storage.last_index().await.unwrap(), 5);
+    }
+
+    /// an application failing to restore from the next `failures` snapshots
+    struct FailingRestore {
+        failures: usize,
+    }
+
+    #[async_trait]
+    impl Application for FailingRestore {
+        async fn apply(&mut self, _entry: &LogEntry) -> Vec<u8> {
+            Vec::new()
+        }
+
+        fn last_applied(&self) -> LogEntryIndex {
+            0
+        }
+
+        async fn snapshot(&self, _writer: &mut SnapshotWriter) -> io::Result<()> {
+            Ok(())
+        }
+
+        async fn restore(&mut self, _snapshot: SnapshotReader) -> io::Result<()> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupted snapshot"));
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn follower_receives_snapshot_again_after_failing_to_restore() {
+        let storage = Arc::new(MemStorage::new());
+        let volatile = ServerVolatileState::new(Box::new(FailingRestore { failures: 1 }));
+        let follower = Follower::new(PersistentState::with_storage(storage.clone()), volatile, InternalState::new(1, vec![0, 1, 2]));
+        let mut server: Option<Box<dyn StateMachine>> = Some(Box::new(follower));
+        let checksum = crc32fast::hash(b"0123456789");
+
+        // the snapshot is dropped rather than saved, and the leader is asked to send it from the beginning
+        assert_eq!(send_snapshot_chunk(&mut server, 0, 10, checksum).await, (0, 0));
+        assert_eq!(storage.snapshot().await.unwrap(), None);
+        assert_eq!(server.as_ref().unwrap().status().last_applied, 0);
+
+        assert_eq!(send_snapshot_chunk(&mut server, 0, 10, checksum).await, (5, 0));
+        assert_eq!(storage.snapshot().await.unwrap().unwrap().last_included, LogEntryId { index: 5, term: 1 });
+        assert_eq!(server.as_ref().unwrap().status().last_applied, 5);
+    }
+
+    #[tokio::test]
+    async fn follower_rejects_entries_following_an_unknown_entry() {
+        let follower = Follower::new(PersistentState::new(), ServerVolatileState::new(Box::new(Noop)), InternalState::new(1, vec![0, 1, 2]));
+        let request = StateEvent::AppendEntriesRequest {
+            term:       1,
+            leader:     0,
+            prev_log:   LogEntryId { index: LogEntryIndex::MAX, term: 1 },
+            entries:    vec![LogEntry {
+                term:    1,
+                index:   0,
+                command: Command::Client(b"command".to_vec()),
+            }],
+            commit_idx: 0,
+        };
+        let Transition { actions, .. } = Box::new(follower).on_events(request).await.unwrap();
+        let response = StateEvent::AppendEntriesResponse {
+            term:      1,
+            success:   false,
+            server:    1,
+            match_idx: 0,
+        };
+        assert_eq!(actions.first(), Some(&Action::SendMessage(0, response)));
+    }
+
+    #[tokio::test]
+    async fn pre_vote_prevents_partitioned_server_from_disrupting_leader() {
+        let mut cluster = Cluster::new(3, true);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+        assert_eq!(cluster.terms().await, vec![1, 1, 1]);
+
+        // server 2 keeps timing out in a partition, without increasing its term
+        cluster.isolated.insert(2);
+        for _ in 0..3 {
+            cluster.send(2, StateEvent::ElectionTimeout).await;
+        }
+        cluster.send(0, command()).await;
+        assert_eq!(cluster.terms().await, vec![1, 1, 1]);
+        assert_eq!(cluster.last_indexes().await, vec![1, 1, 0]);
+
+        // after the partition heals, its pre-vote is rejected and the leader stays in charge
+        cluster.isolated.clear();
+        cluster.send(2, StateEvent::ElectionTimeout).await;
+        cluster.send(0, StateEvent::HeartbeatTimeout).await;
+        assert_eq!(cluster.terms().await, vec![1, 1, 1]);
+        assert_eq!(cluster.last_indexes().await, vec![1, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn partitioned_server_disrupts_leader_without_pre_vote() {
+        let mut cluster = Cluster::new(3, false);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+        assert_eq!(cluster.terms().await, vec![1, 1, 1]);
+
+        cluster.isolated.insert(2);
+        for _ in 0..3 {
+            cluster.send(2, StateEvent::ElectionTimeout).await;
+        }
+        assert_eq!(cluster.terms().await, vec![1, 1, 4]);
+
+        // the leader steps down after learning the inflated term
+        cluster.isolated.clear();
+        cluster.send(0, StateEvent::HeartbeatTimeout).await;
+        assert_eq!(cluster.terms().await, vec![4, 1, 4]);
+    }
+
+    #[tokio::test]
+    async fn single_server_elects_itself_after_pre_vote() {
+        let mut cluster = Cluster::new(1, true);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+        cluster.send(0, command()).await;
+        assert_eq!(cluster.terms().await, vec![1]);
+        assert_eq!(cluster.last_indexes().await, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn leader_steps_down_after_losing_majority() {
+        let mut cluster = Cluster::new(3, false);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+
+        // responses to the initial heartbeats keep it leading for the first election timeout
+        cluster.isolated.insert(0);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+        cluster.send(0, command()).await;
+        assert_eq!(cluster.last_indexes().await, vec![1, 0, 0]);
+
+        // nobody responded during the last election timeout
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+        cluster.send(0, command()).await;
+        assert_eq!(cluster.last_indexes().await, vec![1, 0, 0]);
+        // it steps down in the same term, keeping its vote
+        assert_eq!(cluster.storages[0].hard_state().await.unwrap(), (1, Some(0)));
+    }
+
+    #[tokio::test]
+    async fn leader_keeps_leading_with_majority() {
+        let mut cluster = Cluster::new(3, false);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+
+        cluster.isolated.insert(2);
+        for _ in 0..3 {
+            cluster.send(0, StateEvent::HeartbeatTimeout).await;
+            cluster.send(0, StateEvent::ElectionTimeout).await;
+        }
+        cluster.send(0, command()).await;
+        assert_eq!(cluster.last_indexes().await, vec![1, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn follower_ignores_vote_request_while_leader_is_alive() {
+        let mut cluster = Cluster::new(3, false);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+
+        // server 2 times out while server 1 still hears from the leader
+        cluster.isolated.insert(0);
+        cluster.send(2, StateEvent::ElectionTimeout).await;
+        assert_eq!(cluster.terms().await, vec![1, 1, 2]);
+
+        // server 1 forgets the leader after its own election timeout, and votes in the next election
+        cluster.send(1, StateEvent::ElectionTimeout).await;
+        assert_eq!(cluster.terms().await, vec![1, 2, 2]);
+        cluster.send(2, StateEvent::ElectionTimeout).await;
+        cluster.send(2, command()).await;
+        assert_eq!(cluster.terms().await, vec![1, 3, 3]);
+        assert_eq!(cluster.last_indexes().await, vec![0, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn transfer_leadership_to_lagging_server() {
+        let mut cluster = Cluster::new(5, false);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+        cluster.isolated.insert(4);
+        cluster.send(0, command()).await;
+        assert_eq!(cluster.last_indexes().await, vec![1, 1, 1, 1, 0]);
+
+        // server 4 catches up before starting an election, which is not ignored by followers
+        cluster.isolated.clear();
+        cluster.send(0, StateEvent::TransferLeadership { target: 4 }).await;
+        assert_eq!(cluster.terms().await, vec![2, 2, 2, 2, 2]);
+        cluster.send(4, command()).await;
+        assert_eq!(cluster.last_indexes().await, vec![2, 2, 2, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn abort_leadership_transfer_after_election_timeout() {
+        let mut cluster = Cluster::new(3, false);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+
+        // client requests are rejected until the transfer is aborted
+        cluster.isolated.insert(2);
+        cluster.send(0, StateEvent::TransferLeadership { target: 2 }).await;
+        cluster.send(0, command()).await;
+        assert_eq!(cluster.last_indexes().await, vec![0, 0, 0]);
+
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+        cluster.send(0, command()).await;
+        assert_eq!(cluster.terms().await, vec![1, 1, 1]);
+        assert_eq!(cluster.last_indexes().await, vec![1, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn replace_servers_through_joint_consensus() {
+        let mut cluster = Cluster::with_members(5, vec![0, 1, 2], false);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+
+        // C_old,new and C_new are committed in turn, the leader steps down since it's not in C_new
+        cluster.send(0, StateEvent::ChangeMembership { servers: vec![2, 3, 4] }).await;
+        assert_eq!(cluster.last_indexes().await, vec![2, 1, 2, 2, 2]);
+        cluster.send(0, command()).await;
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+        assert_eq!(cluster.last_indexes().await, vec![2, 1, 2, 2, 2]);
+        assert_eq!(cluster.terms().await, vec![1, 1, 1, 1, 1]);
+
+        // servers of C_new elect a new leader once they all have timed out
+        for server in &[2, 3, 4, 2] {
+            cluster.send(*server, StateEvent::ElectionTimeout).await;
+        }
+        cluster.send(2, command()).await;
+        assert_eq!(cluster.terms().await, vec![1, 1, 3, 3, 3]);
+        assert_eq!(cluster.last_indexes().await, vec![2, 1, 3, 3, 3]);
+    }
+
+    #[tokio::test]
+    async fn joint_configuration_requires_majority_of_new_servers() {
+        let mut cluster = Cluster::with_members(5, vec![0, 1, 2], false);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+
+        cluster.isolated.insert(3);
+        cluster.isolated.insert(4);
+        cluster.send(0, StateEvent::ChangeMembership { servers: vec![0, 3, 4] }).await;
+        assert_eq!(cluster.last_indexes().await, vec![1, 1, 1, 0, 0]);
+        // only one membership change at a time
+        cluster.send(0, StateEvent::ChangeMembership { servers: vec![0, 1] }).await;
+        assert_eq!(cluster.last_indexes().await, vec![1, 1, 1, 0, 0]);
+
+        cluster.isolated.clear();
+        cluster.send(0, StateEvent::HeartbeatTimeout).await;
+        cluster.send(0, command()).await;
+        assert_eq!(cluster.last_indexes().await, vec![3, 1, 1, 3, 3]);
+    }
+
+    #[tokio::test]
+    async fn add_server_after_catching_up() {
+        let mut cluster = Cluster::with_members(4, vec![0, 1, 2], false);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+        cluster.send(0, command()).await;
+        cluster.send(0, command()).await;
+        assert_eq!(cluster.last_indexes().await, vec![2, 2, 2, 0]);
+
+        cluster.send(0, StateEvent::AddServer { server: 3 }).await;
+        assert_eq!(cluster.last_indexes().await, vec![3, 3, 3, 3]);
+
+        // the new server takes part in elections
+        cluster.isolated.insert(0);
+        cluster.send(3, StateEvent::ElectionTimeout).await;
+        cluster.send(1, StateEvent::ElectionTimeout).await;
+        cluster.send(2, StateEvent::ElectionTimeout).await;
+        cluster.send(3, StateEvent::ElectionTimeout).await;
+        cluster.send(3, command()).await;
+        assert_eq!(cluster.terms().await, vec![1, 3, 3, 3]);
+        assert_eq!(cluster.last_indexes().await, vec![3, 4, 4, 4]);
+    }
+
+    #[tokio::test]
+    async fn abort_adding_unavailable_server() {
+        let mut cluster = Cluster::with_members(4, vec![0, 1, 2], false);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+
+        cluster.isolated.insert(3);
+        cluster.send(0, StateEvent::AddServer { server: 3 }).await;
+        // no other membership change while catching up
+        cluster.send(0, StateEvent::RemoveServer { server: 2 }).await;
+        assert_eq!(cluster.last_indexes().await, vec![0, 0, 0, 0]);
+
+        for _ in 0..2 {
+            cluster.send(0, StateEvent::HeartbeatTimeout).await;
+            cluster.send(0, StateEvent::ElectionTimeout).await;
+        }
+        cluster.isolated.clear();
+        cluster.send(0, StateEvent::HeartbeatTimeout).await;
+        assert_eq!(cluster.last_indexes().await, vec![0, 0, 0, 0]);
+
+        cluster.send(0, StateEvent::RemoveServer { server: 2 }).await;
+        assert_eq!(cluster.last_indexes().await, vec![1, 1, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn reject_membership_change_until_previous_one_is_committed() {
+        let mut cluster = Cluster::new(3, false);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+
+        // the new configuration takes effect without being committed
+        cluster.isolated.insert(1);
+        cluster.isolated.insert(2);
+        cluster.send(0, StateEvent::RemoveServer { server: 1 }).await;
+        cluster.send(0, StateEvent::RemoveServer { server: 2 }).await;
+        assert_eq!(cluster.last_indexes().await, vec![1, 0, 0]);
+
+        // server 1 isn't replicated to anymore, the removal is committed by server 0 and 2
+        cluster.isolated.clear();
+        cluster.send(0, StateEvent::HeartbeatTimeout).await;
+        cluster.send(0, StateEvent::RemoveServer { server: 2 }).await;
+        assert_eq!(cluster.last_indexes().await, vec![2, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn learner_is_excluded_from_quorum_until_promoted() {
+        let mut cluster = Cluster::with_members(4, vec![0, 1, 2], false);
+        cluster.send(0, StateEvent::ElectionTimeout).await;
+        cluster.send(0, StateEvent::AddLearner { server: 3 }).await;
+        assert_eq!(cluster.last_indexes().await, vec![1, 1, 1, 1]);
+
+        // a command replicated to the learner isn't committed without a majority of voters
+        cluster.isolated.insert(1);
+        cluster.isolated.insert(2);
+        cluster.send(0, command()).await;
+        assert_eq!(cluster.last_indexes().await, vec![2, 1, 1, 2]);
+        assert_eq!(cluster.applied, vec![0, 0, 0, 0]);
+        cluster.isolated.clear();
+        cluster.send(0, StateEvent::HeartbeatTimeout).await;
+        assert_eq!(cluster.applied, vec![2, 0, 0, 0]);
+
+        // the learner doesn't start elections
+        cluster.send(3, StateEvent::ElectionTimeout).await;
+        assert_eq!(cluster.terms().await, vec![1, 1, 1, 1]);
+
+        // it's promoted only after catching up
+        cluster.isolated.insert(3);
+        cluster.send(0, command()).await;
+        cluster.send(0, StateEvent::PromoteLearner { server: 3 }).await;
+        assert_eq!(cluster.last_indexes().await, vec![3, 3, 3, 2]);
+        cluster.isolated.clear();
+        cluster.send(0, StateEvent::HeartbeatTimeout).await;
+        cluster.send(0, StateEvent::PromoteLearner { server: 3 }).await;
+        assert_eq!(cluster.last_indexes().await, vec![4, 4, 4, 4]);
+        // and starts elections as a voter
+        cluster.send(3, StateEvent::ElectionTimeout).await;
+        assert_eq!(cluster.terms().await[3], 2);
+    }
 }
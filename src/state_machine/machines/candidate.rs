@@ -1,9 +1,11 @@
-use super::{follower::Follower, StateMachine};
+use super::{follower::Follower, leader::Leader, pre_vote_response, snapshot_saved, start_election, start_snapshot, status, vote_response, Role, StateMachine, Status, Transition};
 use crate::state_machine::{
+    actions::Action,
     events::{StateEvent, StateEvent::*},
-    states::{InternalState, LogEntry, LogEntryId, LogEntryIndex, PersistentState, ServerId, ServerVolatileState, TermId, VoteResult},
+    states::{InternalState, LeaderVolatileState, LogEntry, LogEntryId, LogEntryIndex, PersistentState, ServerId, ServerVolatileState, SnapshotChunk, TermId, VoteResult},
 };
 use async_trait::async_trait;
+use std::io;
 
 pub struct Candidate {
     persistent: PersistentState,
@@ -13,46 +15,120 @@ pub struct Candidate {
 
 #[async_trait]
 impl StateMachine for Candidate {
-    async fn on_events(self, event: StateEvent) -> Box<dyn StateMachine> {
-        match event {
-            Timeout(_) => Box::new(self.become_candidate().await),
+    async fn on_events(self: Box<Self>, event: StateEvent) -> io::Result<Transition> {
+        let transition = match event {
+            ElectionTimeout => {
+                // split vote, start a new election
+                let Candidate { persistent, volatile, internal } = *self;
+                start_election(persistent, volatile, internal).await?
+            },
+            // stale timer of a previous leadership
+            HeartbeatTimeout => (*self, Vec::new()).into(),
             VoteResponse { term, vote_granted, server_id } => {
-                if term <= self.term() {
-                    // TODO: become leader?
-                    Box::new(self.with_vote(vote_granted, server_id))
+                if term > self.term() {
+                    self.become_follower(term).await?.into()
+                } else if term < self.term() {
+                    // stale response from a previous election
+                    (*self, Vec::new()).into()
                 } else {
-                    Box::new(self.become_follower(term).await)
+                    let candidate = self.with_vote(vote_granted, server_id);
+                    match candidate.internal.vote_granted() {
+                        VoteResult::Agreed(_) => candidate.become_leader().await?.into(),
+                        VoteResult::Rejected(_) => candidate.step_down().await.into(),
+                        VoteResult::NotYet(_) => (candidate, Vec::new()).into(),
+                    }
                 }
             },
-            VoteRequest { term, candidate, last_log } => {
+            PreVoteRequest { term, candidate, last_log } => {
+                let actions = vec![pre_vote_response(&self.persistent, &self.internal, term, candidate, &last_log)];
+                (*self, actions).into()
+            },
+            // stale response from a previous pre-vote
+            PreVoteResponse { .. } => (*self, Vec::new()).into(),
+            VoteRequest { term, candidate, last_log, .. } => {
                 if term > self.term() && self.accept_vote(term, candidate, &last_log) {
                     // we granted the vote request
-                    Box::new(self.become_follower_and_vote_for(term, candidate).await)
+                    self.become_follower_and_vote_for(term, candidate).await?.into()
                 } else if term > self.term() {
                     // we denied the request, but we found a new term.
-                    Box::new(self.become_follower(term).await)
+                    let id = self.internal.id();
+                    let (follower, mut actions) = self.become_follower(term).await?;
+                    actions.push(vote_response(term, id, candidate, false));
+                    (follower, actions).into()
                 } else if self.accept_vote(term, candidate, &last_log) {
                     // we granted the vote request
-                    Box::new(self.vote_for(term, candidate).await)
+                    self.vote_for(term, candidate).await?.into()
                 } else {
                     // we denied the vote request
-                    Box::new(self)
+                    let actions = vec![vote_response(self.term(), self.internal.id(), candidate, false)];
+                    (*self, actions).into()
                 }
             },
             AppendEntriesRequest { term, leader, prev_log, entries, commit_idx } => {
                 if term < self.term() {
                     // deny the request
-                    Box::new(self)
-                } else if self.accept_leader_state(&prev_log) {
+                    let response = AppendEntriesResponse {
+                        term:      self.term(),
+                        success:   false,
+                        server:    self.internal.id(),
+                        match_idx: 0,
+                    };
+                    let actions = vec![Action::SendMessage(leader, response)];
+                    (*self, actions).into()
+                } else {
                     // found a new leader
-                    Box::new(self.become_follower_on_new_leader_with_entries(term, leader, prev_log, entries, commit_idx).await)
+                    self.become_follower_on_new_leader(term, leader, prev_log, entries, commit_idx).await?.into()
+                }
+            },
+            InstallSnapshotRequest { term, leader, last_included, membership, offset, data, done, checksum } => {
+                if term < self.term() {
+                    // deny the request
+                    let response = InstallSnapshotResponse {
+                        term:      self.term(),
+                        server:    self.internal.id(),
+                        match_idx: 0,
+                        offset:    0,
+                    };
+                    let actions = vec![Action::SendMessage(leader, response)];
+                    (*self, actions).into()
                 } else {
-                    // found a new leader, but the state didn't match
-                    Box::new(self.become_follower_on_new_leader(term, leader, commit_idx).await)
+                    // found a new leader
+                    let chunk = SnapshotChunk { last_included, membership, offset, data, done, checksum };
+                    self.install_snapshot_from_new_leader(term, leader, chunk).await?.into()
                 }
             },
-            AppendEntriesResponse { .. } => panic!("unrecognized event."),
-        }
+            AppendEntriesResponse { term, .. } | InstallSnapshotResponse { term, .. } => {
+                if term > self.term() {
+                    self.become_follower(term).await?.into()
+                } else {
+                    // stale response from a previous leadership
+                    (*self, Vec::new()).into()
+                }
+            },
+            TakeSnapshot { elapsed, forced } => {
+                let Candidate { persistent, volatile, internal } = *self;
+                let (internal, actions) = start_snapshot(&persistent, &volatile, internal.with_elapsed(elapsed), forced);
+                (Candidate { persistent, volatile, internal }, actions).into()
+            },
+            SnapshotSaved { snapshot } => {
+                let Candidate { persistent, volatile, internal } = *self;
+                let (persistent, internal) = snapshot_saved(persistent, internal, snapshot).await?;
+                (Candidate { persistent, volatile, internal }, Vec::new()).into()
+            },
+            SnapshotFailed => {
+                let Candidate { persistent, volatile, internal } = *self;
+                (Candidate { persistent, volatile, internal: internal.finish_snapshot() }, Vec::new()).into()
+            },
+            // stale request from a previous leader
+            TimeoutNow { .. } => (*self, Vec::new()).into(),
+            // only leader accepts client requests
+            ClientRequest { .. } | TransferLeadership { .. } | ChangeMembership { .. } | AddServer { .. } | RemoveServer { .. } | AddLearner { .. } | PromoteLearner { .. } => (*self, Vec::new()).into(),
+        };
+        Ok(transition)
+    }
+
+    fn status(&self) -> Status {
+        status(Role::Candidate, &self.persistent, &self.volatile, &self.internal)
     }
 }
 
@@ -66,73 +142,177 @@ impl Candidate {
     }
 
     fn accept_vote(&self, term: TermId, candidate: ServerId, last_log: &LogEntryId) -> bool {
-        let candidate_match = self.persistent.accept_candidate(candidate);
-        let newer_log = self.persistent.accept_log(last_log);
+        // a vote in previous terms doesn't count
+        let candidate_match = self.persistent.accept_term(term) || self.persistent.accept_candidate(candidate);
+        let newer_log = self.persistent.is_up_to_date(last_log);
         term >= self.term() && candidate_match && newer_log
     }
+
     /// create a new candidate by updating current one with a vote response
     fn with_vote(self, granted: bool, server_id: ServerId) -> Candidate {
         let Candidate { persistent, volatile, internal } = self;
         Candidate {
-            persistent: persistent,
-            volatile:   volatile,
-            internal:   internal.with_vote(server_id, granted),
+            persistent,
+            volatile,
+            internal: internal.with_vote(server_id, granted),
         }
     }
 
-    async fn become_follower_and_vote_for(self, term: TermId, candidate_id: ServerId) -> Follower {
-        // TODO: write storage
+    /// turn candidate into leader after the majority granted the vote
+    async fn become_leader(self) -> io::Result<(Leader, Vec<Action>)> {
         let Candidate { persistent, volatile, internal } = self;
-        Follower::new(persistent.with_vote(term, candidate_id), volatile, internal.clear_voting())
+        let leader_volatile = LeaderVolatileState::new(persistent.last_log_index(), &internal.membership().servers());
+        let id = internal.id();
+        let leader = Leader::new(persistent, volatile, leader_volatile, internal.with_leader(id));
+        // send initial heartbeats to establish authority, and check quorum on election timeouts
+        let (leader, mut actions) = leader.send_heartbeats().await?;
+        actions.push(Action::ResetElectionTimer);
+        Ok((leader, actions))
     }
 
-    async fn vote_for(self, term: TermId, candidate_id: ServerId) -> Candidate {
-        // TODO: write storage
+    /// turn candidate back into follower of the same term after the majority rejected the vote
+    async fn step_down(self) -> (Follower, Vec<Action>) {
         let Candidate { persistent, volatile, internal } = self;
-        Candidate {
+        let follower = Follower::new(persistent, volatile, internal.clear_voting());
+        (follower, vec![Action::ResetElectionTimer])
+    }
+
+    async fn become_follower_and_vote_for(self, term: TermId, candidate_id: ServerId) -> io::Result<(Follower, Vec<Action>)> {
+        let Candidate { persistent, volatile, internal } = self;
+        let persistent = persistent.with_vote(term, candidate_id);
+        persistent.save_hard_state().await?;
+        let actions = vec![vote_response(term, internal.id(), candidate_id, true), Action::ResetElectionTimer];
+        Ok((Follower::new(persistent, volatile, internal.clear_voting()), actions))
+    }
+
+    async fn vote_for(self, term: TermId, candidate_id: ServerId) -> io::Result<(Candidate, Vec<Action>)> {
+        let Candidate { persistent, volatile, internal } = self;
+        let candidate = Candidate {
             persistent: persistent.with_vote(term, candidate_id),
-            volatile:   volatile,
-            internal:   internal,
-        }
+            volatile,
+            internal,
+        };
+        candidate.persistent.save_hard_state().await?;
+        let actions = vec![vote_response(term, candidate.internal.id(), candidate_id, true)];
+        Ok((candidate, actions))
     }
 
     /// turn candidate into follower after failed voting
-    async fn become_follower(self, term: TermId) -> Follower {
-        // TODO: write storage
+    async fn become_follower(self, term: TermId) -> io::Result<(Follower, Vec<Action>)> {
         let Candidate { persistent, volatile, internal } = self;
-        Follower::new(persistent.with_new_term(term), volatile, internal.clear_voting())
+        let persistent = persistent.with_new_term(term);
+        persistent.save_hard_state().await?;
+        let actions = vec![Action::ResetElectionTimer];
+        Ok((Follower::new(persistent, volatile, internal.clear_voting()), actions))
     }
 
-    /// turn current candidate into a new candidate with term increased
-    async fn become_candidate(self) -> Candidate {
+    /// Start an election: increase current term, vote for itself and request votes from all other
+    /// servers. The vote is persisted before sending any request. `leader_transfer` is set when the
+    /// election is started by a TimeoutNow request from the leader.
+    pub(super) async fn start_election(self, leader_transfer: bool) -> io::Result<Transition> {
         let Candidate { persistent, volatile, internal } = self;
-        // TODO: write storage
-        Candidate {
-            persistent: persistent.incr_term(),
-            volatile:   volatile,
-            internal:   internal.clear_voting(),
+        let id = internal.id();
+        let persistent = persistent.incr_term().with_vote_for(id);
+        persistent.save_hard_state().await?;
+        let candidate = Candidate {
+            persistent,
+            volatile,
+            internal: internal.start_voting(),
+        };
+        if let VoteResult::Agreed(_) = candidate.internal.vote_granted() {
+            // the only server in the cluster
+            return Ok(candidate.become_leader().await?.into());
         }
-    }
 
-    fn accept_leader_state(&self, prev_log: &LogEntryId) -> bool {
-        self.persistent.accept_log(&prev_log)
+        let term = candidate.term();
+        let last_log = candidate.persistent.last_log_id();
+        let mut actions: Vec<Action> = candidate.internal.voting_peers().into_iter().map(|server| Action::SendMessage(server, VoteRequest { term, candidate: id, last_log, leader_transfer })).collect();
+        actions.push(Action::ResetElectionTimer);
+        Ok((candidate, actions).into())
     }
 
-    async fn become_follower_on_new_leader_with_entries(self, term: TermId, leader: ServerId, prev_log: LogEntryId, entries: Vec<LogEntry>, leader_commit: LogEntryIndex) -> Follower {
-        // TODO: write storage
+    async fn become_follower_on_new_leader(self, term: TermId, leader: ServerId, prev_log: LogEntryId, entries: Vec<LogEntry>, leader_commit: LogEntryIndex) -> io::Result<(Follower, Vec<Action>)> {
         let Candidate { persistent, volatile, internal } = self;
-        let persistent = persistent.with_new_term(term).with_log_entries(prev_log, entries);
-        let volatile = volatile.with_commit_index(leader_commit);
-        let internal = internal.with_leader(leader);
-        Follower::new(persistent, volatile, internal)
+        let follower = Follower::new(persistent, volatile, internal.clear_voting());
+        follower.append_entries(term, leader, prev_log, entries, leader_commit).await
     }
 
-    async fn become_follower_on_new_leader(self, term: TermId, leader: ServerId, leader_commit: LogEntryIndex) -> Follower {
-        // TODO: write storage
+    async fn install_snapshot_from_new_leader(self, term: TermId, leader: ServerId, chunk: SnapshotChunk) -> io::Result<(Follower, Vec<Action>)> {
         let Candidate { persistent, volatile, internal } = self;
-        let persistent = persistent.with_new_term(term);
-        let volatile = volatile.with_commit_index(leader_commit);
-        let internal = internal.with_leader(leader);
-        Follower::new(persistent, volatile, internal)
+        let follower = Follower::new(persistent, volatile, internal.clear_voting());
+        follower.install_snapshot(term, leader, chunk).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        state_machine::states::Command,
+        storage::{MemStorage, Storage},
+        testing::Noop,
+    };
+    use std::sync::Arc;
+
+    fn heartbeat(to: ServerId) -> Action {
+        let request = AppendEntriesRequest {
+            term:       1,
+            leader:     0,
+            prev_log:   LogEntryId { index: 0, term: 0 },
+            entries:    Vec::new(),
+            commit_idx: 0,
+        };
+        Action::SendMessage(to, request)
+    }
+
+    #[tokio::test]
+    async fn vote_for_itself_and_request_votes_of_other_servers() {
+        let storage = Arc::new(MemStorage::new());
+        let entry = LogEntry {
+            term:    1,
+            index:   1,
+            command: Command::Client(b"command".to_vec()),
+        };
+        let (persistent, _) = PersistentState::with_storage(storage.clone()).with_new_term(1).with_log_entries(LogEntryId { index: 0, term: 0 }, vec![entry]);
+        let candidate = Candidate::new(persistent, ServerVolatileState::new(Box::new(Noop)), InternalState::new(0, vec![0, 1, 2]));
+        let Transition { next, actions } = candidate.start_election(false).await.unwrap();
+        assert_eq!((next.status().role, next.status().term), (Role::Candidate, 2));
+        assert_eq!(storage.hard_state().await.unwrap(), (2, Some(0)));
+        let request = VoteRequest {
+            term:            2,
+            candidate:       0,
+            last_log:        LogEntryId { index: 1, term: 1 },
+            leader_transfer: false,
+        };
+        assert_eq!(actions, vec![Action::SendMessage(1, request.clone()), Action::SendMessage(2, request), Action::ResetElectionTimer]);
+
+        // its own vote is counted, so one more vote makes a majority
+        let next = next.on_events(VoteResponse { term: 2, vote_granted: true, server_id: 1 }).await.unwrap().next;
+        assert_eq!(next.status().role, Role::Leader);
+    }
+
+    #[tokio::test]
+    async fn count_votes_of_election() {
+        // responses of votes for term 1 in a cluster of servers 0, 1 and 2
+        let cases = vec![
+            // the leader sends initial heartbeats, and checks quorum on the next election timeout
+            (vec![(1, true)], Role::Leader, vec![heartbeat(1), heartbeat(2), Action::ResetHeartbeatTimer, Action::ResetElectionTimer]),
+            (vec![(1, false)], Role::Candidate, Vec::new()),
+            // the candidate waits for the next election timeout as a follower of the same term
+            (vec![(1, false), (2, false)], Role::Follower, vec![Action::ResetElectionTimer]),
+            (vec![(1, false), (2, true)], Role::Leader, vec![heartbeat(1), heartbeat(2), Action::ResetHeartbeatTimer, Action::ResetElectionTimer]),
+        ];
+        for (responses, role, actions) in cases {
+            let candidate = Candidate::new(PersistentState::new(), ServerVolatileState::new(Box::new(Noop)), InternalState::new(0, vec![0, 1, 2]));
+            let Transition { mut next, actions: mut last_actions } = candidate.start_election(false).await.unwrap();
+            for (server_id, vote_granted) in responses.clone() {
+                let transition = next.on_events(VoteResponse { term: 1, vote_granted, server_id }).await.unwrap();
+                next = transition.next;
+                last_actions = transition.actions;
+            }
+            let status = next.status();
+            assert_eq!((status.role, status.term), (role, 1), "responses {:?}", responses);
+            assert_eq!(last_actions, actions, "responses {:?}", responses);
+        }
     }
 }
@@ -1,11 +1,12 @@
-use super::{candidate::Candidate, StateMachine};
+use super::{candidate::Candidate, pre_vote_response, snapshot_saved, start_election, start_snapshot, status, vote_response, Role, StateMachine, Status, Transition};
 use crate::state_machine::{
+    actions::Action,
     events::StateEvent,
-    states::{InternalState, LogEntry, LogEntryId, LogEntryIndex, PersistentState, ServerId, ServerVolatileState, TermId, VoteResult},
+    states::{InternalState, LogEntry, LogEntryId, LogEntryIndex, PersistentState, ServerId, ServerVolatileState, Snapshot, SnapshotChunk, TermId},
 };
 use async_trait::async_trait;
-use log::{debug, error, info};
-use std::time::Duration;
+use log::warn;
+use std::io;
 
 pub struct Follower {
     persistent: PersistentState,
@@ -21,109 +22,386 @@ impl Follower {
 
 #[async_trait]
 impl StateMachine for Follower {
-    async fn on_events(self, event: StateEvent) -> Box<dyn StateMachine> {
+    async fn on_events(self: Box<Self>, event: StateEvent) -> io::Result<Transition> {
         use StateEvent::*;
-        match event {
-            Timeout(_) => Box::new(self.become_candidate().await),
-            VoteRequest { term, candidate, last_log } => {
+        let transition = match event {
+            // learners and servers removed from the cluster don't start elections
+            ElectionTimeout if !self.internal.is_voter() => (*self, Vec::new()).into(),
+            ElectionTimeout => self.become_candidate().await?,
+            // stale timer of a previous leadership
+            HeartbeatTimeout => (*self, Vec::new()).into(),
+            VoteRequest { leader_transfer: false, .. } if self.internal.knows_leader() => {
+                // ignore disruptive candidates while the leader is alive, they might have been removed or partitioned
+                (*self, Vec::new()).into()
+            },
+            VoteRequest { term, candidate, last_log, .. } => {
                 if self.accept_vote(term, candidate, &last_log) {
                     // we granted the vote request
-                    Box::new(self.vote_for(term, candidate).await)
+                    self.vote_for(term, candidate).await?.into()
                 } else if self.persistent.accept_term(term) {
                     // we denied the request, but we found a new term.
-                    // TODO: make sure this is supported by the paper
-                    Box::new(self.new_term(term).await)
+                    self.new_term(term, candidate).await?.into()
                 } else {
                     // we denied the vote request
-                    Box::new(self)
+                    self.deny_vote(candidate).into()
+                }
+            },
+            PreVoteRequest { term, candidate, last_log } => {
+                let actions = vec![pre_vote_response(&self.persistent, &self.internal, term, candidate, &last_log)];
+                (*self, actions).into()
+            },
+            // stale response from a previous pre-vote
+            PreVoteResponse { .. } => (*self, Vec::new()).into(),
+            VoteResponse { term, .. } | AppendEntriesResponse { term, .. } | InstallSnapshotResponse { term, .. } => {
+                if self.persistent.accept_term(term) {
+                    self.new_term_on_response(term).await?.into()
+                } else {
+                    // stale response for a previous role
+                    (*self, Vec::new()).into()
                 }
             },
-            VoteResponse { .. } => panic!("follower receive vote response!"),
             AppendEntriesRequest { term, leader, prev_log, entries, commit_idx } => {
-                let is_new_leader = self.accept_new_leader(term);
-                let accept_logs = self.accept_logs(term, leader, &prev_log);
-
-                if is_new_leader && accept_logs {
-                    Box::new(self.new_leader_with_logs(term, leader, prev_log, entries, commit_idx).await)
-                } else if accept_logs {
-                    Box::new(self.append_logs(prev_log, entries, commit_idx).await)
-                } else if is_new_leader {
-                    Box::new(self.new_leader(term, leader, commit_idx).await)
+                if term < self.persistent.term() {
+                    // deny the request from a stale leader
+                    self.deny_entries(leader).into()
                 } else {
-                    Box::new(self)
+                    self.append_entries(term, leader, prev_log, entries, commit_idx).await?.into()
                 }
             },
-            AppendEntriesResponse { .. } => panic!("follower received append entries response!"),
-        }
+            InstallSnapshotRequest { term, leader, .. } if term < self.persistent.term() => {
+                // deny the request from a stale leader
+                let actions = vec![self.install_snapshot_response(leader, 0, 0)];
+                (*self, actions).into()
+            },
+            InstallSnapshotRequest { term, leader, last_included, membership, offset, data, done, checksum } => {
+                let chunk = SnapshotChunk { last_included, membership, offset, data, done, checksum };
+                self.install_snapshot(term, leader, chunk).await?.into()
+            },
+            TakeSnapshot { elapsed, forced } => {
+                let Follower { persistent, volatile, internal } = *self;
+                let (internal, actions) = start_snapshot(&persistent, &volatile, internal.with_elapsed(elapsed), forced);
+                (Follower { persistent, volatile, internal }, actions).into()
+            },
+            SnapshotSaved { snapshot } => {
+                let Follower { persistent, volatile, internal } = *self;
+                let (persistent, internal) = snapshot_saved(persistent, internal, snapshot).await?;
+                (Follower { persistent, volatile, internal }, Vec::new()).into()
+            },
+            SnapshotFailed => {
+                let Follower { persistent, volatile, internal } = *self;
+                (Follower { persistent, volatile, internal: internal.finish_snapshot() }, Vec::new()).into()
+            },
+            TimeoutNow { term, leader } => {
+                if term == self.persistent.term() && self.internal.has_leader(leader) {
+                    // the leader is transferring its leadership to us
+                    let Follower { persistent, volatile, internal } = *self;
+                    Candidate::new(persistent, volatile, internal).start_election(true).await?
+                } else {
+                    // stale request from a previous leader
+                    (*self, Vec::new()).into()
+                }
+            },
+            // only leader accepts client requests
+            ClientRequest { .. } | TransferLeadership { .. } | ChangeMembership { .. } | AddServer { .. } | RemoveServer { .. } | AddLearner { .. } | PromoteLearner { .. } => (*self, Vec::new()).into(),
+        };
+        Ok(transition)
+    }
+
+    fn status(&self) -> Status {
+        status(Role::Follower, &self.persistent, &self.volatile, &self.internal)
     }
 }
 
 impl Follower {
-    async fn become_candidate(self) -> Candidate {
-        // TODO: write storage
+    async fn become_candidate(self) -> io::Result<Transition> {
         let Follower { persistent, volatile, internal } = self;
-        Candidate::new(persistent.incr_term(), volatile, internal.clear_voting())
+        start_election(persistent, volatile, internal).await
     }
 
-    async fn new_term(self, term: TermId) -> Follower {
-        // TODO: write storage
+    async fn new_term(self, term: TermId, candidate: ServerId) -> io::Result<(Follower, Vec<Action>)> {
         let Follower { persistent, volatile, internal } = self;
-        Follower {
+        let follower = Follower {
             persistent: persistent.with_new_term(term),
-            volatile:   volatile,
-            internal:   internal,
-        }
+            volatile,
+            internal,
+        };
+        follower.persistent.save_hard_state().await?;
+        let actions = vec![follower.vote_response(candidate, false)];
+        Ok((follower, actions))
+    }
+
+    async fn new_term_on_response(self, term: TermId) -> io::Result<(Follower, Vec<Action>)> {
+        let Follower { persistent, volatile, internal } = self;
+        let persistent = persistent.with_new_term(term);
+        persistent.save_hard_state().await?;
+        Ok((Follower { persistent, volatile, internal }, Vec::new()))
     }
 
-    async fn vote_for(self, term: TermId, candidate: ServerId) -> Follower {
-        // TODO: write storage
+    async fn vote_for(self, term: TermId, candidate: ServerId) -> io::Result<(Follower, Vec<Action>)> {
         let Follower { persistent, volatile, internal } = self;
-        Follower {
+        let follower = Follower {
             persistent: persistent.with_new_term(term).with_vote_for(candidate),
-            volatile:   volatile,
-            internal:   internal,
-        }
+            volatile,
+            internal,
+        };
+        follower.persistent.save_hard_state().await?;
+        let actions = vec![follower.vote_response(candidate, true), Action::ResetElectionTimer];
+        Ok((follower, actions))
+    }
+
+    fn deny_vote(self, candidate: ServerId) -> (Follower, Vec<Action>) {
+        let actions = vec![self.vote_response(candidate, false)];
+        (self, actions)
     }
 
-    fn accept_new_leader(&self, term: TermId) -> bool {
-        term > self.persistent.term()
+    fn vote_response(&self, candidate: ServerId, vote_granted: bool) -> Action {
+        vote_response(self.persistent.term(), self.internal.id(), candidate, vote_granted)
     }
 
     fn accept_vote(&self, term: TermId, candidate: ServerId, last_log: &LogEntryId) -> bool {
-        let candidate_match = self.persistent.accept_candidate(candidate);
-        let newer_log = self.persistent.accept_log(last_log);
+        // a vote in previous terms doesn't count
+        let candidate_match = self.persistent.accept_term(term) || self.persistent.accept_candidate(candidate);
+        let newer_log = self.persistent.is_up_to_date(last_log);
         term >= self.persistent.term() && candidate_match && newer_log
     }
 
-    fn accept_logs(&self, term: TermId, server: ServerId, prev_log: &LogEntryId) -> bool {
-        let accept_log = self.persistent.accept_log(prev_log);
-        let accept_server = self.persistent.term() < term || self.persistent.term() == term && self.internal.has_leader(server);
-        accept_log && accept_server
+    fn deny_entries(self, leader: ServerId) -> (Follower, Vec<Action>) {
+        let actions = vec![self.append_entries_response(leader, false, 0)];
+        (self, actions)
     }
 
-    async fn new_leader_with_logs(self, term: TermId, leader: ServerId, prev_log: LogEntryId, entries: Vec<LogEntry>, commit_idx: LogEntryIndex) -> Follower {
-        // TODO: write storage
+    fn append_entries_response(&self, leader: ServerId, success: bool, match_idx: LogEntryIndex) -> Action {
+        let response = StateEvent::AppendEntriesResponse {
+            term: self.persistent.term(),
+            success,
+            server: self.internal.id(),
+            match_idx,
+        };
+        Action::SendMessage(leader, response)
+    }
+
+    /// handle AppendEntries RPC from the leader of `term`, which is not less than current term
+    pub(super) async fn append_entries(self, term: TermId, leader: ServerId, prev_log: LogEntryId, entries: Vec<LogEntry>, commit_idx: LogEntryIndex) -> io::Result<(Follower, Vec<Action>)> {
         let Follower { persistent, volatile, internal } = self;
-        let persistent = persistent.with_new_term(term).with_log_entries(prev_log, entries);
-        let volatile = volatile.with_commit_index(commit_idx);
-        let internal = internal.with_leader(leader);
-        Follower { persistent, volatile, internal }
+        let mut actions = Vec::new();
+        let persistent = if persistent.accept_term(term) {
+            let persistent = persistent.with_new_term(term);
+            persistent.save_hard_state().await?;
+            persistent
+        } else {
+            persistent
+        };
+
+        let num_entries = entries.len() as LogEntryIndex;
+        let missing_entries = persistent.missing_entries(&entries);
+        let (persistent, success) = persistent.with_log_entries(prev_log, entries);
+        // entries should be persisted before responding to the leader
+        let (volatile, internal, match_idx) = if success {
+            // `prev_log` is in the log now, so the index of the last new entry doesn't overflow
+            let last_new_index = prev_log.index + num_entries;
+            persistent.save_entries(&missing_entries).await?;
+            // a configuration takes effect once it's appended
+            let internal = internal.with_appended_entries(&persistent, &missing_entries);
+            (volatile.with_commit_index(commit_idx.min(last_new_index)), internal, last_new_index)
+        } else {
+            (volatile, internal, 0)
+        };
+        let (volatile, applied) = volatile.apply_committed(&persistent).await;
+        actions.extend(applied.into_iter().map(|(index, response)| Action::Applied { index, response }));
+        let (internal, snapshot_actions) = start_snapshot(&persistent, &volatile, internal, false);
+        actions.extend(snapshot_actions);
+
+        let follower = Follower {
+            persistent,
+            volatile,
+            internal: internal.with_leader(leader),
+        };
+        actions.push(follower.append_entries_response(leader, success, match_idx));
+        actions.push(Action::ResetElectionTimer);
+        Ok((follower, actions))
+    }
+
+    fn install_snapshot_response(&self, leader: ServerId, match_idx: LogEntryIndex, offset: u64) -> Action {
+        let response = StateEvent::InstallSnapshotResponse {
+            term: self.persistent.term(),
+            server: self.internal.id(),
+            match_idx,
+            offset,
+        };
+        Action::SendMessage(leader, response)
     }
 
-    async fn append_logs(self, prev_log: LogEntryId, entries: Vec<LogEntry>, commit_idx: LogEntryIndex) -> Follower {
-        // TODO: write storage
+    /// handle a chunk of InstallSnapshot RPC from the leader of `term`, which is not less than current term
+    pub(super) async fn install_snapshot(self, term: TermId, leader: ServerId, chunk: SnapshotChunk) -> io::Result<(Follower, Vec<Action>)> {
         let Follower { persistent, volatile, internal } = self;
-        let persistent = persistent.with_log_entries(prev_log, entries);
-        let volatile = volatile.with_commit_index(commit_idx);
-        Follower { persistent, volatile, internal }
+        let persistent = if persistent.accept_term(term) {
+            let persistent = persistent.with_new_term(term);
+            persistent.save_hard_state().await?;
+            persistent
+        } else {
+            persistent
+        };
+
+        let (follower, match_idx, offset) = Follower { persistent, volatile, internal }.receive_snapshot_chunk(chunk).await?;
+        let Follower { persistent, volatile, internal } = follower;
+        let follower = Follower {
+            persistent,
+            volatile,
+            internal: internal.with_leader(leader),
+        };
+        let actions = vec![follower.install_snapshot_response(leader, match_idx, offset), Action::ResetElectionTimer];
+        Ok((follower, actions))
     }
 
-    async fn new_leader(self, term: TermId, leader: ServerId, commit_idx: LogEntryIndex) -> Follower {
-        // TODO: write storage
+    /// Stage a snapshot chunk following received ones, updating the checksum of received bytes. Once the last chunk
+    /// is received, the snapshot replaces the log and the application if its checksum matches, otherwise it's
+    /// received again from the beginning. Returns the index of the last entry replaced by the installed snapshot,
+    /// and the offset where the leader should continue from.
+    async fn receive_snapshot_chunk(self, chunk: SnapshotChunk) -> io::Result<(Follower, LogEntryIndex, u64)> {
+        let last_included = chunk.last_included;
+        let received = self.internal.snapshot_received(&last_included);
+        if last_included.index <= self.volatile.commit_index && self.persistent.contains_log(&last_included) {
+            // committed entries always match the leader's, so is the snapshot
+            return Ok((self, last_included.index, 0));
+        } else if chunk.offset != received {
+            // a retried or stale chunk, the leader should resume from the staged data
+            return Ok((self, 0, received));
+        }
+
         let Follower { persistent, volatile, internal } = self;
-        let persistent = persistent.with_new_term(term);
-        let volatile = volatile.with_commit_index(commit_idx);
-        let internal = internal.with_leader(leader);
-        Follower { persistent, volatile, internal }
+        persistent.stage_snapshot_chunk(chunk.offset, &chunk.data).await?;
+        let received = received + chunk.data.len() as u64;
+        let internal = internal.with_snapshot_chunk(last_included, &chunk.data);
+        if !chunk.done {
+            return Ok((Follower { persistent, volatile, internal }, 0, received));
+        }
+
+        let checksum = internal.received_checksum();
+        let internal = internal.without_snapshot_received();
+        if checksum != chunk.checksum {
+            warn!("checksum of snapshot {:?} mismatched, receiving it again", last_included);
+            return Ok((Follower { persistent, volatile, internal }, 0, 0));
+        }
+        // The application is restored before the snapshot is saved, so that the snapshot is received again if it fails.
+        // It may have been restored already if the server crashed before saving the snapshot.
+        if volatile.last_applied < last_included.index {
+            if let Err(e) = volatile.restore(persistent.open_staged_snapshot().await?).await {
+                warn!("failed to restore snapshot {:?}, receiving it again: {}", last_included, e);
+                return Ok((Follower { persistent, volatile, internal }, 0, 0));
+            }
+        }
+        // the snapshot should be persisted before responding to the leader
+        let snapshot = Snapshot {
+            last_included,
+            membership: chunk.membership,
+            size: received,
+            checksum,
+        };
+        let persistent = persistent.with_snapshot(snapshot, 0);
+        persistent.save_staged_snapshot().await?;
+        let volatile = volatile.with_restored(last_included.index);
+        let internal = internal.with_recovered_membership(&persistent);
+        Ok((Follower { persistent, volatile, internal }, last_included.index, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        state_machine::states::Command,
+        storage::{MemStorage, Storage},
+        testing::Noop,
+    };
+    use std::sync::Arc;
+
+    fn follower() -> Box<dyn StateMachine> {
+        follower_with_storage(Arc::new(MemStorage::new()))
+    }
+
+    fn follower_with_storage(storage: Arc<dyn Storage>) -> Box<dyn StateMachine> {
+        Box::new(Follower::new(PersistentState::with_storage(storage), ServerVolatileState::new(Box::new(Noop)), InternalState::new(1, vec![0, 1, 2])))
+    }
+
+    fn entry(term: TermId, index: LogEntryIndex) -> LogEntry {
+        LogEntry {
+            term,
+            index,
+            command: Command::Client(index.to_le_bytes().to_vec()),
+        }
+    }
+
+    fn append_entries(term: TermId, prev_log: LogEntryId, entries: Vec<LogEntry>) -> StateEvent {
+        StateEvent::AppendEntriesRequest {
+            term,
+            leader: 0,
+            prev_log,
+            entries,
+            commit_idx: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn respond_to_requests_with_actions() {
+        let request = StateEvent::VoteRequest {
+            term:            1,
+            candidate:       0,
+            last_log:        LogEntryId { index: 0, term: 0 },
+            leader_transfer: false,
+        };
+        let Transition { next, actions } = follower().on_events(request).await.unwrap();
+        let response = StateEvent::VoteResponse { term: 1, vote_granted: true, server_id: 1 };
+        assert_eq!(actions, vec![Action::SendMessage(0, response), Action::ResetElectionTimer]);
+
+        let entry = LogEntry {
+            term:    1,
+            index:   1,
+            command: Command::Client(b"command".to_vec()),
+        };
+        let request = StateEvent::AppendEntriesRequest {
+            term:       1,
+            leader:     0,
+            prev_log:   LogEntryId { index: 0, term: 0 },
+            entries:    vec![entry],
+            commit_idx: 0,
+        };
+        let Transition { actions, .. } = next.on_events(request).await.unwrap();
+        let response = StateEvent::AppendEntriesResponse { term: 1, success: true, server: 1, match_idx: 1 };
+        assert_eq!(actions, vec![Action::SendMessage(0, response), Action::ResetElectionTimer]);
+    }
+
+    #[tokio::test]
+    async fn persist_vote_and_entries_before_responding() {
+        let storage = Arc::new(MemStorage::new());
+        let request = StateEvent::VoteRequest {
+            term:            2,
+            candidate:       0,
+            last_log:        LogEntryId { index: 0, term: 0 },
+            leader_transfer: false,
+        };
+        let follower = follower_with_storage(storage.clone()).on_events(request).await.unwrap().next;
+        assert_eq!(storage.hard_state().await.unwrap(), (2, Some(0)));
+
+        let follower = follower.on_events(append_entries(2, LogEntryId { index: 0, term: 0 }, vec![entry(1, 1), entry(2, 2), entry(2, 3)])).await.unwrap().next;
+        assert_eq!(storage.read_entries(1..4).await.unwrap(), vec![entry(1, 1), entry(2, 2), entry(2, 3)]);
+
+        // conflicting entries are deleted from storage as well, along with the ones following them
+        follower.on_events(append_entries(3, LogEntryId { index: 1, term: 1 }, vec![entry(3, 2)])).await.unwrap();
+        assert_eq!(storage.hard_state().await.unwrap(), (3, None));
+        assert_eq!(storage.last_index().await.unwrap(), 2);
+        assert_eq!(storage.read_entries(1..3).await.unwrap(), vec![entry(1, 1), entry(3, 2)]);
+    }
+
+    #[tokio::test]
+    async fn reset_election_timer_only_on_current_leader() {
+        let follower = follower().on_events(append_entries(2, LogEntryId { index: 0, term: 0 }, Vec::new())).await.unwrap().next;
+        let Transition { next, actions } = follower.on_events(append_entries(1, LogEntryId { index: 0, term: 0 }, Vec::new())).await.unwrap();
+        let response = StateEvent::AppendEntriesResponse { term: 2, success: false, server: 1, match_idx: 0 };
+        assert_eq!(actions, vec![Action::SendMessage(0, response)]);
+
+        // a heartbeat of the leader resets the timer, even if its entries don't match
+        let Transition { actions, .. } = next.on_events(append_entries(2, LogEntryId { index: 1, term: 2 }, Vec::new())).await.unwrap();
+        let response = StateEvent::AppendEntriesResponse { term: 2, success: false, server: 1, match_idx: 0 };
+        assert_eq!(actions, vec![Action::SendMessage(0, response), Action::ResetElectionTimer]);
     }
 }
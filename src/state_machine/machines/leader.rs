@@ -1,9 +1,14 @@
-use super::{follower::Follower, StateMachine};
+use super::{follower::Follower, pre_vote_response, snapshot_saved, start_snapshot, status, vote_response, Role, StateMachine, Status, Transition};
 use crate::state_machine::{
+    actions::Action,
     events::{StateEvent, StateEvent::*},
-    states::{InternalState, LeaderVolatileState, LogEntry, LogEntryId, LogEntryIndex, PersistentState, ServerId, ServerVolatileState, TermId, VoteResult},
+    states::{CatchUp, Command, InternalState, LeaderVolatileState, LogEntry, LogEntryId, LogEntryIndex, PersistentState, ServerId, ServerVolatileState, SnapshotChunk, TermId, TfarCommand},
 };
 use async_trait::async_trait;
+use std::io;
+
+/// maximum rounds to catch up a new server before adding it to the cluster
+const MAX_CATCH_UP_ROUNDS: usize = 10;
 
 pub struct Leader {
     persistent:      PersistentState,
@@ -14,90 +19,790 @@ pub struct Leader {
 
 #[async_trait]
 impl StateMachine for Leader {
-    async fn on_events(self, event: StateEvent) -> Box<dyn StateMachine> {
-        match event {
-            Timeout(_) => panic!("Leader received Timeout event!"),
-            VoteRequest { term, candidate, last_log } => {
+    async fn on_events(self: Box<Self>, event: StateEvent) -> io::Result<Transition> {
+        let transition = match event {
+            ElectionTimeout => self.abort_leadership_transfer().check_catch_up().check_quorum().await?,
+            HeartbeatTimeout => self.send_heartbeats().await?.into(),
+            VoteRequest { term, candidate, last_log, .. } => {
                 if self.accept_vote(term, candidate, &last_log) {
                     // we granted the vote request
-                    Box::new(self.become_follower_and_vote_for(term, candidate).await)
+                    self.become_follower_and_vote_for(term, candidate).await?.into()
                 } else if term > self.term() {
                     // we denied the request, but we found a new term.
-                    Box::new(self.become_follower(term).await)
+                    let id = self.internal.id();
+                    let (follower, mut actions) = self.become_follower(term).await?;
+                    actions.push(vote_response(term, id, candidate, false));
+                    (follower, actions).into()
                 } else {
                     // we denied the vote request
-                    Box::new(self)
+                    let actions = vec![vote_response(self.term(), self.internal.id(), candidate, false)];
+                    (*self, actions).into()
+                }
+            },
+            // always rejected since leader knows itself
+            PreVoteRequest { term, candidate, last_log } => {
+                let actions = vec![pre_vote_response(&self.persistent, &self.internal, term, candidate, &last_log)];
+                (*self, actions).into()
+            },
+            // late response after being elected
+            PreVoteResponse { .. } => (*self, Vec::new()).into(),
+            VoteResponse { term, .. } => {
+                if term > self.term() {
+                    self.become_follower(term).await?.into()
+                } else {
+                    // late response after being elected
+                    (*self, Vec::new()).into()
                 }
             },
-            VoteResponse {..} => panic!("Leader received vote response event!"),
             AppendEntriesRequest { term, leader, prev_log, entries, commit_idx } => {
-                let is_new_leader = term > self.term();
-                let accept_logs = self.accept_logs(&prev_log);
-
-                if is_new_leader && accept_logs {
-                    Box::new(self.become_follower_on_new_leader_with_logs(term, leader, prev_log, entries, commit_idx).await)
-                } else if is_new_leader {
-                    Box::new(self.become_follower_on_new_leader(term, leader, commit_idx).await)
+                if term > self.term() {
+                    self.become_follower_on_new_leader(term, leader, prev_log, entries, commit_idx).await?.into()
                 } else {
-                    Box::new(self)
+                    let response = AppendEntriesResponse {
+                        term:      self.term(),
+                        success:   false,
+                        server:    self.internal.id(),
+                        match_idx: 0,
+                    };
+                    let actions = vec![Action::SendMessage(leader, response)];
+                    (*self, actions).into()
                 }
             },
-            AppendEntriesResponse { term,  success, server} => {
-                if term > self.term()  {
+            AppendEntriesResponse { term, success, server, match_idx } => {
+                if term > self.term() {
                     // found a new leader
-                    Box::new(self.become_follower(term ).await)
+                    self.become_follower(term).await?.into()
+                } else if term < self.term() || !self.leader_volatile.contains(server) {
+                    // stale response from a previous term or a removed server
+                    (*self, Vec::new()).into()
+                } else if success {
+                    self.with_recent_active(server).entries_replicated(server, match_idx).await?
                 } else {
-                    // update appending entries status
-                    panic!("not implemented yet!")
+                    // log mismatched, retry with preceding entries
+                    self.with_recent_active(server).entries_rejected(server).await?.into()
                 }
-            }, 
-            _ => panic!("unrecognized evnet!"),
-        }
+            },
+            InstallSnapshotRequest { term, leader, last_included, membership, offset, data, done, checksum } => {
+                if term > self.term() {
+                    let chunk = SnapshotChunk { last_included, membership, offset, data, done, checksum };
+                    self.install_snapshot_from_new_leader(term, leader, chunk).await?.into()
+                } else {
+                    let response = InstallSnapshotResponse {
+                        term:      self.term(),
+                        server:    self.internal.id(),
+                        match_idx: 0,
+                        offset:    0,
+                    };
+                    let actions = vec![Action::SendMessage(leader, response)];
+                    (*self, actions).into()
+                }
+            },
+            InstallSnapshotResponse { term, server, match_idx, offset } => {
+                if term > self.term() {
+                    // found a new leader
+                    self.become_follower(term).await?.into()
+                } else if term < self.term() || !self.leader_volatile.contains(server) {
+                    // stale response from a previous term or a removed server
+                    (*self, Vec::new()).into()
+                } else if match_idx > 0 {
+                    // the server continues with entries following the snapshot
+                    self.with_recent_active(server).with_snapshot_offset(server, 0).entries_replicated(server, match_idx).await?
+                } else {
+                    // continue with the chunk at the offset the server asked for
+                    self.with_recent_active(server).with_snapshot_offset(server, offset).snapshot_chunk_replicated(server).await?.into()
+                }
+            },
+            TakeSnapshot { elapsed, forced } => {
+                let Leader {
+                    persistent,
+                    volatile,
+                    leader_volatile,
+                    internal,
+                } = *self;
+                let leader = Leader::new(persistent, volatile, leader_volatile, internal.with_elapsed(elapsed));
+                leader.start_snapshot(forced).into()
+            },
+            SnapshotSaved { snapshot } => {
+                let Leader {
+                    persistent,
+                    volatile,
+                    leader_volatile,
+                    internal,
+                } = *self;
+                let (persistent, internal) = snapshot_saved(persistent, internal, snapshot).await?;
+                (Leader::new(persistent, volatile, leader_volatile, internal), Vec::new()).into()
+            },
+            SnapshotFailed => {
+                let Leader {
+                    persistent,
+                    volatile,
+                    leader_volatile,
+                    internal,
+                } = *self;
+                (Leader::new(persistent, volatile, leader_volatile, internal.finish_snapshot()), Vec::new()).into()
+            },
+            // stop accepting client requests during a leadership transfer
+            ClientRequest { .. } if self.leader_volatile.transferee.is_some() => (*self, Vec::new()).into(),
+            // configurations are only changed by ChangeMembership
+            ClientRequest { command: Command::Tfar(_) } => (*self, Vec::new()).into(),
+            ClientRequest { command } => {
+                let (leader, actions) = self.append_entry(command).await?;
+                leader.continue_membership_change(actions).await?
+            },
+            ChangeMembership { servers } => self.change_membership(servers).await?,
+            AddServer { server } => self.add_server(server).await?.into(),
+            RemoveServer { server } => self.remove_server(server).await?,
+            AddLearner { server } => self.add_learner(server).await?,
+            PromoteLearner { server } => self.promote_learner(server).await?,
+            TransferLeadership { target } => self.transfer_leadership(target).await?.into(),
+            // stale request from a previous leader
+            TimeoutNow { .. } => (*self, Vec::new()).into(),
+        };
+        Ok(transition)
+    }
+
+    fn status(&self) -> Status {
+        status(Role::Leader, &self.persistent, &self.volatile, &self.internal)
     }
 }
 
 impl Leader {
+    pub fn new(persistent: PersistentState, volatile: ServerVolatileState, leader_volatile: LeaderVolatileState, internal: InternalState) -> Leader {
+        Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        }
+    }
+
     fn term(&self) -> TermId {
         self.persistent.term()
     }
 
     fn accept_vote(&self, term: TermId, candidate: ServerId, last_log: &LogEntryId) -> bool {
-        let candidate_match = self.persistent.accept_candidate(candidate);
-        let newer_log = self.persistent.accept_log(last_log);
+        // a vote in previous terms doesn't count
+        let candidate_match = self.persistent.accept_term(term) || self.persistent.accept_candidate(candidate);
+        let newer_log = self.persistent.is_up_to_date(last_log);
         term > self.term() && candidate_match && newer_log
     }
 
-    fn accept_logs(&self, prev_log:&LogEntryId) -> bool {
-        self.persistent.accept_log(prev_log)
+    /// Send AppendEntries RPCs to all other servers to maintain authority, they carry entries not yet
+    /// replicated to each server, or no entry at all.
+    pub(super) async fn send_heartbeats(self) -> io::Result<(Leader, Vec<Action>)> {
+        let mut actions = Vec::new();
+        for server in self.replication_targets() {
+            actions.push(self.append_entries_request(server).await?);
+        }
+        actions.push(Action::ResetHeartbeatTimer);
+        Ok((self, actions))
+    }
+
+    /// servers to replicate log entries to, including the one being caught up
+    fn replication_targets(&self) -> Vec<ServerId> {
+        let mut servers = self.internal.peers();
+        servers.extend(self.leader_volatile.catch_up.as_ref().map(|catch_up| catch_up.server));
+        servers
+    }
+
+    /// Step down if the majority of servers has been silent since the last election timeout, the leader
+    /// may have been partitioned from them. Election timer is restarted to check again later.
+    async fn check_quorum(self) -> io::Result<Transition> {
+        if self.leader_volatile.has_active_quorum(self.internal.membership(), self.internal.id()) {
+            let Leader {
+                persistent,
+                volatile,
+                leader_volatile,
+                internal,
+            } = self;
+            let leader = Leader {
+                persistent,
+                volatile,
+                leader_volatile: leader_volatile.clear_recent_active(),
+                internal,
+            };
+            Ok((leader, vec![Action::ResetElectionTimer]).into())
+        } else {
+            let term = self.term();
+            Ok(self.become_follower(term).await?.into())
+        }
+    }
+
+    /// Start transferring leadership to `target`: client requests are not accepted anymore, and
+    /// `target` is asked to start an election as soon as its log is up to date. The transfer is
+    /// aborted if it's not done before next election timeout.
+    async fn transfer_leadership(self, target: ServerId) -> io::Result<(Leader, Vec<Action>)> {
+        if target == self.internal.id() || !self.internal.membership().latest_voters().contains(&target) {
+            return Ok((self, Vec::new()));
+        }
+        let Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        } = self;
+        let leader = Leader {
+            persistent,
+            volatile,
+            leader_volatile: leader_volatile.with_transferee(Some(target)),
+            internal,
+        };
+        let request = if leader.leader_volatile.match_index[&target] < leader.persistent.last_log_index() {
+            // catch up the target first
+            leader.append_entries_request(target).await?
+        } else {
+            leader.timeout_now(target)
+        };
+        // give the transfer a whole election timeout
+        Ok((leader, vec![request, Action::ResetElectionTimer]))
     }
 
-    async fn become_follower_and_vote_for(self, term: TermId, candidate: ServerId) -> Follower {
-        // TODO: write storage
+    fn abort_leadership_transfer(self) -> Leader {
+        let Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        } = self;
+        Leader {
+            persistent,
+            volatile,
+            leader_volatile: leader_volatile.with_transferee(None),
+            internal,
+        }
+    }
+
+    fn timeout_now(&self, server: ServerId) -> Action {
+        let request = TimeoutNow {
+            term:   self.term(),
+            leader: self.internal.id(),
+        };
+        Action::SendMessage(server, request)
+    }
+
+    /// update with a response received from `server`
+    fn with_recent_active(self, server: ServerId) -> Leader {
+        let Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        } = self;
+        Leader {
+            persistent,
+            volatile,
+            leader_volatile: leader_volatile.with_recent_active(server),
+            internal,
+        }
+    }
+
+    /// Create an AppendEntries RPC for `server` with entries starting from its next index, or an InstallSnapshot
+    /// RPC if these entries were replaced by the snapshot.
+    async fn append_entries_request(&self, server: ServerId) -> io::Result<Action> {
+        let next_index = self.leader_volatile.next_index[&server];
+        let prev_index = next_index - 1;
+        // the term of the entry preceding the log is unknown if trailing entries before the snapshot are retained
+        if next_index < self.persistent.first_index() || prev_index > 0 && self.persistent.term_of(prev_index).is_none() {
+            return self.install_snapshot_request(server).await;
+        }
+        let request = AppendEntriesRequest {
+            term:       self.term(),
+            leader:     self.internal.id(),
+            prev_log:   LogEntryId {
+                index: prev_index,
+                term:  self.persistent.term_of(prev_index).unwrap_or(0),
+            },
+            entries:    self.persistent.entries_from(next_index),
+            commit_idx: self.volatile.commit_index,
+        };
+        Ok(Action::SendMessage(server, request))
+    }
+
+    /// Create an InstallSnapshot RPC for `server` with the chunk of the snapshot at its offset, which is read from
+    /// storage. The checksum computed when the snapshot was saved is only sent with the last chunk.
+    async fn install_snapshot_request(&self, server: ServerId) -> io::Result<Action> {
+        // the offset may be acknowledged for a previous snapshot, then the server asks for the beginning of this one
+        let offset = self.leader_volatile.snapshot_offset.get(&server).copied().unwrap_or(0);
+        let chunk_size = self.internal.config().snapshot_chunk_size.max(1);
+        // storage may have saved a newer snapshot than the one replacing the log, which is sent instead
+        let (snapshot, data) = match self.persistent.read_snapshot(offset, chunk_size).await? {
+            Some(snapshot) => snapshot,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "log entries discarded without a snapshot")),
+        };
+        let done = offset + data.len() as u64 >= snapshot.size;
+        let request = InstallSnapshotRequest {
+            term:          self.term(),
+            leader:        self.internal.id(),
+            last_included: snapshot.last_included,
+            membership:    snapshot.membership,
+            offset,
+            data,
+            done,
+            checksum:      if done { snapshot.checksum } else { 0 },
+        };
+        Ok(Action::SendMessage(server, request))
+    }
+
+    /// send the next chunk of the snapshot after `server` received previous ones
+    async fn snapshot_chunk_replicated(self, server: ServerId) -> io::Result<(Leader, Vec<Action>)> {
+        let actions = vec![self.append_entries_request(server).await?];
+        Ok((self, actions))
+    }
+
+    /// create a new leader by recording the offset of the snapshot `server` received
+    fn with_snapshot_offset(self, server: ServerId, offset: u64) -> Leader {
+        let Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        } = self;
+        Leader {
+            persistent,
+            volatile,
+            leader_volatile: leader_volatile.with_snapshot_offset(server, offset),
+            internal,
+        }
+    }
+
+    /// append a command to the log, and replicate it to other servers
+    async fn append_entry(self, command: Command) -> io::Result<(Leader, Vec<Action>)> {
+        let Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        } = self;
+        let last_log_index = persistent.last_log_index();
+        let entry = LogEntry {
+            term: persistent.term(),
+            index: last_log_index + 1,
+            command,
+        };
+        persistent.save_entries(std::slice::from_ref(&entry)).await?;
+        // a configuration takes effect once it's appended, new servers start from the new entry
+        let internal = internal.with_appended_entries(&persistent, std::slice::from_ref(&entry));
+        let leader = Leader {
+            persistent: persistent.with_entry(entry),
+            volatile,
+            leader_volatile: leader_volatile.with_servers(&internal.membership().servers(), last_log_index),
+            internal,
+        };
+
+        // a single server cluster commits the entry right away
+        let (leader, mut actions) = leader.advance_commit_index().await;
+        for server in leader.replication_targets() {
+            actions.push(leader.append_entries_request(server).await?);
+        }
+        Ok((leader, actions))
+    }
+
+    /// Start a membership change to `servers` by appending the joint configuration C_old,new. Only one
+    /// change is allowed at a time, so it's ignored until the current configuration is committed.
+    async fn change_membership(self, servers: Vec<ServerId>) -> io::Result<Transition> {
+        if servers.is_empty() || self.changing_membership() || self.leader_volatile.transferee.is_some() {
+            return Ok((self, Vec::new()).into());
+        }
+        let membership = self.internal.membership();
+        let old = membership.latest_voters().iter().copied().collect();
+        let learners = membership.learners().iter().copied().filter(|learner| !servers.contains(learner)).collect();
+        let (leader, actions) = self.append_entry(Command::Tfar(TfarCommand::JointConfig { old, new: servers, learners })).await?;
+        leader.continue_membership_change(actions).await
+    }
+
+    /// Start adding `server` to the cluster, it's caught up in rounds before a new configuration with it
+    /// is appended. Ignored during another membership change.
+    async fn add_server(self, server: ServerId) -> io::Result<(Leader, Vec<Action>)> {
+        if self.internal.membership().contains(server) || self.changing_membership() || self.leader_volatile.transferee.is_some() {
+            return Ok((self, Vec::new()));
+        }
+        let Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        } = self;
+        let last_log_index = persistent.last_log_index();
+        let catch_up = CatchUp {
+            server,
+            round: 1,
+            round_end: last_log_index,
+            slow: false,
+        };
+        let leader = Leader {
+            persistent,
+            volatile,
+            leader_volatile: leader_volatile.with_catch_up(Some(catch_up)).with_servers(&internal.membership().servers(), last_log_index),
+            internal,
+        };
+        let actions = vec![leader.append_entries_request(server).await?];
+        Ok((leader, actions))
+    }
+
+    /// Remove voter or learner `server` from the cluster by appending a new configuration without it. Ignored
+    /// during another membership change.
+    async fn remove_server(self, server: ServerId) -> io::Result<Transition> {
+        let membership = self.internal.membership();
+        let voters: Vec<ServerId> = membership.latest_voters().iter().copied().filter(|voter| *voter != server).collect();
+        if !membership.contains(server) || voters.is_empty() || self.changing_membership() || self.leader_volatile.transferee.is_some() {
+            return Ok((self, Vec::new()).into());
+        }
+        let learners: Vec<ServerId> = membership.learners().iter().copied().filter(|learner| *learner != server).collect();
+        let (leader, actions) = self.append_entry(new_config(voters, learners)).await?;
+        leader.continue_membership_change(actions).await
+    }
+
+    /// Add `server` to the cluster as a learner, which doesn't need catching up since it's excluded from
+    /// quorums. Ignored during another membership change.
+    async fn add_learner(self, server: ServerId) -> io::Result<Transition> {
+        let membership = self.internal.membership();
+        if membership.contains(server) || self.changing_membership() || self.leader_volatile.transferee.is_some() {
+            return Ok((self, Vec::new()).into());
+        }
+        let voters = membership.latest_voters().clone();
+        let learners: Vec<ServerId> = membership.learners().iter().copied().chain(Some(server)).collect();
+        let (leader, actions) = self.append_entry(new_config(voters, learners)).await?;
+        leader.continue_membership_change(actions).await
+    }
+
+    /// Promote learner `server` to a voter if its log is within the configured lag of leader's. Ignored
+    /// during another membership change.
+    async fn promote_learner(self, server: ServerId) -> io::Result<Transition> {
+        let membership = self.internal.membership();
+        let lagging = match self.leader_volatile.match_index.get(&server) {
+            Some(match_index) => self.persistent.last_log_index().saturating_sub(*match_index) > self.internal.config().learner_max_lag,
+            None => true,
+        };
+        if !membership.is_learner(server) || lagging || self.changing_membership() || self.leader_volatile.transferee.is_some() {
+            return Ok((self, Vec::new()).into());
+        }
+        let voters: Vec<ServerId> = membership.latest_voters().iter().copied().chain(Some(server)).collect();
+        let learners: Vec<ServerId> = membership.learners().iter().copied().filter(|learner| *learner != server).collect();
+        let (leader, actions) = self.append_entry(new_config(voters, learners)).await?;
+        leader.continue_membership_change(actions).await
+    }
+
+    /// whether a membership change is in progress, which is not committed yet or catching up a new server
+    fn changing_membership(&self) -> bool {
+        self.internal.membership().is_joint() || self.internal.membership_index() > self.volatile.commit_index || self.leader_volatile.catch_up.is_some()
+    }
+
+    /// Check the server being caught up on election timeout. Adding it is aborted if it didn't respond
+    /// during the last election timeout, since it's unavailable or too slow.
+    fn check_catch_up(self) -> Leader {
+        let Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        } = self;
+        let catch_up = match leader_volatile.catch_up.clone() {
+            Some(catch_up) if leader_volatile.recent_active.get(&catch_up.server) == Some(&true) => Some(CatchUp { slow: true, ..catch_up }),
+            _ => None,
+        };
+        Leader {
+            leader_volatile: leader_volatile.with_catch_up(catch_up).with_servers(&internal.membership().servers(), persistent.last_log_index()),
+            persistent,
+            volatile,
+            internal,
+        }
+    }
+
+    /// Finish current catch up round once `server` has replicated all entries of the round. The server is
+    /// added to the cluster if the round finished within an election timeout, otherwise another round
+    /// starts, or adding it is aborted after too many rounds.
+    async fn catch_up_replicated(self, server: ServerId, mut actions: Vec<Action>) -> io::Result<(Leader, Vec<Action>)> {
+        let catch_up = match &self.leader_volatile.catch_up {
+            Some(catch_up) if catch_up.server == server && self.leader_volatile.match_index[&server] >= catch_up.round_end => catch_up.clone(),
+            _ => return Ok((self, actions)),
+        };
+        let Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        } = self;
+        let last_log_index = persistent.last_log_index();
+        let next_round = if catch_up.slow && catch_up.round < MAX_CATCH_UP_ROUNDS {
+            Some(CatchUp {
+                round: catch_up.round + 1,
+                round_end: last_log_index,
+                slow: false,
+                ..catch_up
+            })
+        } else {
+            None
+        };
+        if catch_up.slow {
+            let leader = Leader {
+                leader_volatile: leader_volatile.with_catch_up(next_round).with_servers(&internal.membership().servers(), last_log_index),
+                persistent,
+                volatile,
+                internal,
+            };
+            return Ok((leader, actions));
+        }
+
+        // the server has caught up, and its progress is kept since it's in the new configuration
+        let leader = Leader {
+            persistent,
+            volatile,
+            leader_volatile: leader_volatile.with_catch_up(None),
+            internal,
+        };
+        let membership = leader.internal.membership();
+        let voters: Vec<ServerId> = membership.latest_voters().iter().copied().chain(Some(server)).collect();
+        let learners = membership.learners().clone();
+        let (leader, new_actions) = leader.append_entry(new_config(voters, learners)).await?;
+        actions.extend(new_actions);
+        Ok((leader, actions))
+    }
+
+    /// Continue an ongoing membership change after the commit index advanced: C_new is appended once
+    /// C_old,new is committed, and the leader steps down once C_new is committed if it's not a part of it.
+    async fn continue_membership_change(self, mut actions: Vec<Action>) -> io::Result<Transition> {
+        let committed = self.internal.membership_index() <= self.volatile.commit_index;
+        let leader = if committed && self.internal.membership().is_joint() {
+            let membership = self.internal.membership();
+            let command = new_config(membership.latest_voters().clone(), membership.learners().clone());
+            let (leader, new_actions) = self.append_entry(command).await?;
+            actions.extend(new_actions);
+            leader
+        } else {
+            self
+        };
+
+        let committed = leader.internal.membership_index() <= leader.volatile.commit_index;
+        if committed && !leader.internal.is_voter() {
+            let term = leader.term();
+            let (follower, new_actions) = leader.become_follower(term).await?;
+            actions.extend(new_actions);
+            Ok((follower, actions).into())
+        } else {
+            Ok((leader, actions).into())
+        }
+    }
+
+    /// advance the commit index to the highest log entry replicated on a majority of servers, and
+    /// apply newly committed entries
+    async fn advance_commit_index(self) -> (Leader, Vec<Action>) {
+        let Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        } = self;
+        // only log entries from current term are committed by counting replicas (§5.4.2)
+        let majority_match = leader_volatile.quorum_match_index(internal.membership(), internal.id(), persistent.last_log_index());
+        let volatile = if persistent.term_of(majority_match) == Some(persistent.term()) {
+            volatile.with_commit_index(majority_match)
+        } else {
+            volatile
+        };
+        let (volatile, applied) = volatile.apply_committed(&persistent).await;
+        let leader = Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        };
+        let mut actions: Vec<Action> = applied.into_iter().map(|(index, response)| Action::Applied { index, response }).collect();
+        let (leader, snapshot_actions) = leader.start_snapshot(false);
+        actions.extend(snapshot_actions);
+        (leader, actions)
+    }
+
+    /// take a snapshot if it is `forced` or the snapshot policy is met
+    fn start_snapshot(self, forced: bool) -> (Leader, Vec<Action>) {
+        let Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        } = self;
+        let (internal, actions) = start_snapshot(&persistent, &volatile, internal, forced);
+        (Leader::new(persistent, volatile, leader_volatile, internal), actions)
+    }
+
+    /// update replicating progress of `server` and advance the commit index if possible
+    async fn entries_replicated(self, server: ServerId, match_index: LogEntryIndex) -> io::Result<Transition> {
+        let Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        } = self;
+        let leader = Leader {
+            persistent,
+            volatile,
+            leader_volatile: leader_volatile.with_match_index(server, match_index),
+            internal,
+        };
+
+        let (leader, mut actions) = leader.advance_commit_index().await;
+        if leader.leader_volatile.match_index[&server] < leader.persistent.last_log_index() {
+            // keep replicating the rest entries
+            actions.push(leader.append_entries_request(server).await?);
+        } else if leader.leader_volatile.transferee == Some(server) {
+            // the target of leadership transfer is up to date
+            actions.push(leader.timeout_now(server));
+        }
+        let (leader, actions) = leader.catch_up_replicated(server, actions).await?;
+        leader.continue_membership_change(actions).await
+    }
+
+    async fn entries_rejected(self, server: ServerId) -> io::Result<(Leader, Vec<Action>)> {
+        let Leader {
+            persistent,
+            volatile,
+            leader_volatile,
+            internal,
+        } = self;
+        let leader = Leader {
+            persistent,
+            volatile,
+            leader_volatile: leader_volatile.with_next_index_decreased(server),
+            internal,
+        };
+        let actions = vec![leader.append_entries_request(server).await?];
+        Ok((leader, actions))
+    }
+
+    async fn become_follower_and_vote_for(self, term: TermId, candidate: ServerId) -> io::Result<(Follower, Vec<Action>)> {
         let Leader { persistent, volatile, internal, .. } = self;
-        Follower::new(persistent.with_vote(term, candidate), volatile, internal)
+        let persistent = persistent.with_vote(term, candidate);
+        persistent.save_hard_state().await?;
+        let actions = vec![vote_response(term, internal.id(), candidate, true), Action::ResetElectionTimer];
+        Ok((Follower::new(persistent, volatile, internal.without_leader()), actions))
     }
 
-    async fn become_follower(self, term: TermId) -> Follower {
-        // TODO: write storage
+    /// turn leader into follower of `term`, which is the current term when it lost the majority
+    async fn become_follower(self, term: TermId) -> io::Result<(Follower, Vec<Action>)> {
         let Leader { persistent, volatile, internal, .. } = self;
-        Follower::new(persistent.with_new_term(term), volatile, internal)
+        let persistent = if persistent.accept_term(term) {
+            let persistent = persistent.with_new_term(term);
+            persistent.save_hard_state().await?;
+            persistent
+        } else {
+            // keep the vote of current term
+            persistent
+        };
+        let actions = vec![Action::ResetElectionTimer];
+        Ok((Follower::new(persistent, volatile, internal.without_leader()), actions))
     }
 
-    async fn become_follower_on_new_leader_with_logs(self, term: TermId, leader: ServerId, prev_log: LogEntryId, entries: Vec<LogEntry>, leader_commit: LogEntryIndex) -> Follower {
-        // TODO: write storage
-        let Leader { persistent, volatile, internal,.. } = self;
-        let persistent = persistent.with_new_term(term).with_log_entries(prev_log, entries);
-        let volatile = volatile.with_commit_index(leader_commit);
-        let internal = internal.with_leader(leader);
-        Follower::new(persistent, volatile, internal)
+    async fn become_follower_on_new_leader(self, term: TermId, leader: ServerId, prev_log: LogEntryId, entries: Vec<LogEntry>, leader_commit: LogEntryIndex) -> io::Result<(Follower, Vec<Action>)> {
+        let Leader { persistent, volatile, internal, .. } = self;
+        let follower = Follower::new(persistent, volatile, internal);
+        follower.append_entries(term, leader, prev_log, entries, leader_commit).await
     }
 
-    async fn become_follower_on_new_leader(self, term: TermId, leader: ServerId, leader_commit: LogEntryIndex) -> Follower {
-        // TODO: write storage
+    async fn install_snapshot_from_new_leader(self, term: TermId, leader: ServerId, chunk: SnapshotChunk) -> io::Result<(Follower, Vec<Action>)> {
         let Leader { persistent, volatile, internal, .. } = self;
-        let persistent = persistent.with_new_term(term);
-        let volatile = volatile.with_commit_index(leader_commit);
-        let internal = internal.with_leader(leader);
-        Follower::new(persistent, volatile, internal)
+        let follower = Follower::new(persistent, volatile, internal);
+        follower.install_snapshot(term, leader, chunk).await
+    }
+}
+
+/// a command of the configuration with `voters` and `learners`
+fn new_config(voters: impl IntoIterator<Item = ServerId>, learners: impl IntoIterator<Item = ServerId>) -> Command {
+    Command::Tfar(TfarCommand::NewConfig {
+        servers:  voters.into_iter().collect(),
+        learners: learners.into_iter().collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Noop;
+
+    fn entry(term: TermId, index: LogEntryIndex) -> LogEntry {
+        LogEntry {
+            term,
+            index,
+            command: Command::Client(b"command".to_vec()),
+        }
+    }
+
+    fn replicated(server: ServerId, match_idx: LogEntryIndex) -> StateEvent {
+        AppendEntriesResponse { term: 3, success: true, server, match_idx }
+    }
+
+    /// a leader of term 3 in a cluster of servers 0, 1 and 2, with `entries` in its log
+    fn leader_with(entries: Vec<LogEntry>) -> Box<dyn StateMachine> {
+        let (persistent, _) = PersistentState::new().with_new_term(3).with_log_entries(LogEntryId { index: 0, term: 0 }, entries);
+        let internal = InternalState::new(0, vec![0, 1, 2]).with_leader(0);
+        let leader_volatile = LeaderVolatileState::new(persistent.last_log_index(), &internal.membership().servers());
+        Box::new(Leader::new(persistent, ServerVolatileState::new(Box::new(Noop)), leader_volatile, internal))
+    }
+
+    #[tokio::test]
+    async fn send_heartbeats_from_next_index_of_each_server() {
+        let (persistent, _) = PersistentState::new().with_new_term(3).with_log_entries(LogEntryId { index: 0, term: 0 }, vec![entry(1, 1), entry(2, 2)]);
+        let internal = InternalState::new(0, vec![0, 1, 2]).with_leader(0);
+        // server 1 has all entries, while server 2 rejected the last one
+        let leader_volatile = LeaderVolatileState::new(persistent.last_log_index(), &internal.membership().servers()).with_match_index(1, 2).with_next_index_decreased(2);
+        let leader: Box<dyn StateMachine> = Box::new(Leader::new(persistent, ServerVolatileState::new(Box::new(Noop)), leader_volatile, internal));
+
+        let Transition { next, actions } = leader.on_events(HeartbeatTimeout).await.unwrap();
+        assert_eq!(next.status().role, Role::Leader);
+        let heartbeat = AppendEntriesRequest {
+            term:       3,
+            leader:     0,
+            prev_log:   LogEntryId { index: 2, term: 2 },
+            entries:    Vec::new(),
+            commit_idx: 0,
+        };
+        let retry = AppendEntriesRequest {
+            term:       3,
+            leader:     0,
+            prev_log:   LogEntryId { index: 1, term: 1 },
+            entries:    vec![entry(2, 2)],
+            commit_idx: 0,
+        };
+        assert_eq!(actions, vec![Action::SendMessage(1, heartbeat), Action::SendMessage(2, retry), Action::ResetHeartbeatTimer]);
+    }
+
+    #[tokio::test]
+    async fn replicate_client_commands_in_entries_of_current_term() {
+        let leader = leader_with(vec![entry(1, 1)]);
+        let command = Command::Client(b"new command".to_vec());
+        let Transition { actions, .. } = leader.on_events(ClientRequest { command: command.clone() }).await.unwrap();
+        let entry = LogEntry { term: 3, index: 2, command };
+        let request = AppendEntriesRequest {
+            term:       3,
+            leader:     0,
+            prev_log:   LogEntryId { index: 1, term: 1 },
+            entries:    vec![entry],
+            commit_idx: 0,
+        };
+        assert_eq!(actions, vec![Action::SendMessage(1, request.clone()), Action::SendMessage(2, request)]);
+    }
+
+    #[tokio::test]
+    async fn commit_entries_of_previous_terms_with_an_entry_of_current_term() {
+        // entries of terms 1 and 2 are uncommitted
+        let leader = leader_with(vec![entry(1, 1), entry(2, 2)]);
+
+        // the majority matches an entry of term 2, which may still be overwritten by a leader of term 2 (§5.4.2)
+        let leader = leader.on_events(replicated(1, 2)).await.unwrap().next;
+        assert_eq!(leader.status().commit_index, 0);
+        let leader = leader.on_events(replicated(2, 1)).await.unwrap().next;
+        assert_eq!(leader.status().commit_index, 0);
+
+        // the majority matches an entry of term 3, which commits all preceding entries as well
+        let leader = leader.on_events(ClientRequest { command: Command::Client(b"command".to_vec()) }).await.unwrap().next;
+        assert_eq!(leader.status().commit_index, 0);
+        let Transition { next, actions } = leader.on_events(replicated(2, 3)).await.unwrap();
+        assert_eq!(next.status().commit_index, 3);
+        let applied: Vec<LogEntryIndex> = actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Applied { index, .. } => Some(*index),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(applied, vec![1, 2, 3]);
     }
 }
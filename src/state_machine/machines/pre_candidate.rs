@@ -0,0 +1,172 @@
+use super::{candidate::Candidate, follower::Follower, pre_vote_response, snapshot_saved, start_snapshot, status, Role, StateMachine, Status, Transition};
+use crate::state_machine::{
+    actions::Action,
+    events::{StateEvent, StateEvent::*},
+    states::{InternalState, PersistentState, ServerId, ServerVolatileState, TermId, VoteResult},
+};
+use async_trait::async_trait;
+use std::io;
+
+/// A follower checking whether it could win an election before starting one, its term is not
+/// increased until the majority grants the pre-vote.
+pub struct PreCandidate {
+    persistent: PersistentState,
+    volatile:   ServerVolatileState,
+    internal:   InternalState,
+}
+
+#[async_trait]
+impl StateMachine for PreCandidate {
+    async fn on_events(self: Box<Self>, event: StateEvent) -> io::Result<Transition> {
+        let transition = match event {
+            ElectionTimeout => self.start_pre_vote().await?,
+            // stale timer of a previous leadership
+            HeartbeatTimeout => (*self, Vec::new()).into(),
+            PreVoteRequest { term, candidate, last_log } => {
+                let actions = vec![pre_vote_response(&self.persistent, &self.internal, term, candidate, &last_log)];
+                (*self, actions).into()
+            },
+            PreVoteResponse { term, vote_granted, server_id } => {
+                // only pre-votes of other voters in current configuration count
+                let voter = self.internal.voting_peers().contains(&server_id);
+                if voter && vote_granted && term == self.term() + 1 {
+                    self.with_vote(true, server_id).check_votes().await?
+                } else if !vote_granted && term > self.term() {
+                    // rejected by a server in a newer term
+                    self.become_follower(term).await?.into()
+                } else if voter && !vote_granted && term == self.term() {
+                    self.with_vote(false, server_id).check_votes().await?
+                } else {
+                    // stale response from a previous pre-vote, or from a learner or a removed server
+                    (*self, Vec::new()).into()
+                }
+            },
+            TakeSnapshot { elapsed, forced } => {
+                let PreCandidate { persistent, volatile, internal } = *self;
+                let (internal, actions) = start_snapshot(&persistent, &volatile, internal.with_elapsed(elapsed), forced);
+                (PreCandidate { persistent, volatile, internal }, actions).into()
+            },
+            SnapshotSaved { snapshot } => {
+                let PreCandidate { persistent, volatile, internal } = *self;
+                let (persistent, internal) = snapshot_saved(persistent, internal, snapshot).await?;
+                (PreCandidate { persistent, volatile, internal }, Vec::new()).into()
+            },
+            SnapshotFailed => {
+                let PreCandidate { persistent, volatile, internal } = *self;
+                (PreCandidate { persistent, volatile, internal: internal.finish_snapshot() }, Vec::new()).into()
+            },
+            // only leader accepts client requests
+            ClientRequest { .. } | TransferLeadership { .. } | ChangeMembership { .. } | AddServer { .. } | RemoveServer { .. } | AddLearner { .. } | PromoteLearner { .. } => (*self, Vec::new()).into(),
+            // a pre-candidate acts as a follower for all other events
+            event => Box::new(self.into_follower()).on_events(event).await?,
+        };
+        Ok(transition)
+    }
+
+    fn status(&self) -> Status {
+        status(Role::PreCandidate, &self.persistent, &self.volatile, &self.internal)
+    }
+}
+
+impl PreCandidate {
+    pub fn new(persistent: PersistentState, volatile: ServerVolatileState, internal: InternalState) -> PreCandidate {
+        PreCandidate { persistent, volatile, internal }
+    }
+
+    pub fn term(&self) -> TermId {
+        self.persistent.term()
+    }
+
+    /// Start a Pre-Vote round: request pre-votes for the next term from all other servers without
+    /// changing current term.
+    pub(super) async fn start_pre_vote(self) -> io::Result<Transition> {
+        let PreCandidate { persistent, volatile, internal } = self;
+        let pre_candidate = PreCandidate {
+            persistent,
+            volatile,
+            internal: internal.start_voting(),
+        };
+        if let VoteResult::Agreed(_) = pre_candidate.internal.vote_granted() {
+            // the only server in the cluster
+            return pre_candidate.become_candidate().await;
+        }
+
+        let term = pre_candidate.term() + 1;
+        let id = pre_candidate.internal.id();
+        let last_log = pre_candidate.persistent.last_log_id();
+        let mut actions: Vec<Action> = pre_candidate.internal.voting_peers().into_iter().map(|server| Action::SendMessage(server, PreVoteRequest { term, candidate: id, last_log })).collect();
+        actions.push(Action::ResetElectionTimer);
+        Ok((pre_candidate, actions).into())
+    }
+
+    /// create a new pre-candidate by updating current one with a pre-vote response
+    fn with_vote(self, granted: bool, server_id: ServerId) -> PreCandidate {
+        let PreCandidate { persistent, volatile, internal } = self;
+        PreCandidate {
+            persistent,
+            volatile,
+            internal: internal.with_vote(server_id, granted),
+        }
+    }
+
+    async fn check_votes(self) -> io::Result<Transition> {
+        match self.internal.vote_granted() {
+            VoteResult::Agreed(_) => self.become_candidate().await,
+            VoteResult::Rejected(_) => Ok(self.step_down().into()),
+            VoteResult::NotYet(_) => Ok((self, Vec::new()).into()),
+        }
+    }
+
+    /// start a real election after the majority granted the pre-vote
+    async fn become_candidate(self) -> io::Result<Transition> {
+        let PreCandidate { persistent, volatile, internal } = self;
+        Candidate::new(persistent, volatile, internal).start_election(false).await
+    }
+
+    /// turn pre-candidate back into follower of the same term after the majority rejected the pre-vote
+    fn step_down(self) -> (Follower, Vec<Action>) {
+        (self.into_follower(), vec![Action::ResetElectionTimer])
+    }
+
+    async fn become_follower(self, term: TermId) -> io::Result<(Follower, Vec<Action>)> {
+        let PreCandidate { persistent, volatile, internal } = self;
+        let persistent = persistent.with_new_term(term);
+        persistent.save_hard_state().await?;
+        let actions = vec![Action::ResetElectionTimer];
+        Ok((Follower::new(persistent, volatile, internal.clear_voting()), actions))
+    }
+
+    fn into_follower(self) -> Follower {
+        let PreCandidate { persistent, volatile, internal } = self;
+        Follower::new(persistent, volatile, internal.clear_voting())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Noop;
+
+    #[tokio::test]
+    async fn count_pre_votes_of_other_voters() {
+        // responses of pre-votes for term 1 in a cluster of servers 0, 1 and 2, where server 3 was removed
+        let cases: Vec<(Vec<(ServerId, bool)>, Role)> = vec![
+            (vec![(1, true)], Role::Candidate),
+            (vec![(1, false)], Role::PreCandidate),
+            (vec![(1, false), (2, false)], Role::Follower),
+            // its own pre-vote was counted when the pre-vote started
+            (vec![(0, false), (1, false)], Role::PreCandidate),
+            (vec![(3, false), (1, false)], Role::PreCandidate),
+            (vec![(3, true), (3, false)], Role::PreCandidate),
+        ];
+        for (responses, role) in cases {
+            let pre_candidate = PreCandidate::new(PersistentState::new(), ServerVolatileState::new(Box::new(Noop)), InternalState::new(0, vec![0, 1, 2]));
+            let mut server = pre_candidate.start_pre_vote().await.unwrap().next;
+            for (server_id, vote_granted) in responses.clone() {
+                let term = if vote_granted { 1 } else { 0 };
+                server = server.on_events(PreVoteResponse { term, vote_granted, server_id }).await.unwrap().next;
+            }
+            assert_eq!(server.status().role, role, "responses {:?}", responses);
+        }
+    }
+}
@@ -1,12 +1,15 @@
 mod internal;
+mod membership;
 mod persistent;
 mod volatile;
 
-pub use volatile::{LeaderVolatileState, ServerVolatileState};
+pub use volatile::{CatchUp, LeaderVolatileState, ServerVolatileState};
 
-pub use persistent::{Command, LogEntry, PersistentState, TermId};
+pub use persistent::{Command, LogEntry, PersistentState, Snapshot, SnapshotChunk, TermId, TfarCommand};
 
-pub use internal::{InternalState, Server, VoteResult};
+pub use internal::{Config, InternalState, SnapshotPolicy, VoteResult, DEFAULT_SNAPSHOT_CHUNK_SIZE};
+
+pub use membership::Membership;
 
 pub type LogEntryIndex = u64;
 
@@ -14,6 +17,7 @@ pub type LogEntryIndex = u64;
 pub type ServerId = usize;
 
 /// Identifier for a log entry by index and term
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct LogEntryId {
     /// index of this log entry
     pub index: LogEntryIndex,
@@ -1,12 +1,8 @@
-use super::ServerId;
-use std::collections::HashSet;
-
-/// Represents a node in Raft cluster
-#[derive(PartialEq)]
-pub struct Server {
-    address: String,
-    port:    u16,
-}
+use super::{LogEntry, LogEntryId, LogEntryIndex, Membership, PersistentState, ServerId};
+use std::{collections::HashSet, time::Duration};
+
+/// default maximum size of a snapshot chunk sent by InstallSnapshot RPC in bytes
+pub const DEFAULT_SNAPSHOT_CHUNK_SIZE: usize = 1024 * 1024;
 
 /// Tracing the progress of an ongoing Vote event.
 struct Voting {
@@ -14,36 +10,223 @@ struct Voting {
     rejects: HashSet<ServerId>,
 }
 
+/// Progress of a snapshot being received in chunks.
+struct ReceivingSnapshot {
+    /// id of the last log entry replaced by the snapshot
+    last_included: LogEntryId,
+    /// bytes staged so far
+    received:      u64,
+    /// crc32 checksum of bytes staged so far, updated with each chunk
+    hasher:        crc32fast::Hasher,
+}
+
 pub enum VoteResult {
     Agreed(usize),
     Rejected(usize),
     NotYet(usize),
 }
 
+/// Optional behaviours of a Raft server.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Run a Pre-Vote round before starting an election, so that a server rejoining after a partition
+    /// doesn't disrupt the cluster by increasing its term (§9.6 of the Raft thesis).
+    pub pre_vote: bool,
+    /// A learner is promoted to voter only if its log is behind leader's by at most this many entries.
+    pub learner_max_lag: LogEntryIndex,
+    /// maximum size of a snapshot chunk sent by InstallSnapshot RPC in bytes
+    pub snapshot_chunk_size: usize,
+    /// when to take snapshots automatically
+    pub snapshot_policy: SnapshotPolicy,
+}
+
+/// Triggers of taking a snapshot automatically, any of which is enough if there are entries applied
+/// since the last snapshot. All of them are disabled by default.
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotPolicy {
+    /// take a snapshot after this many entries applied since the last one, 0 to disable
+    pub applied_entries:  LogEntryIndex,
+    /// take a snapshot once log entries following the last one exceed this many bytes, 0 to disable
+    pub log_bytes:        u64,
+    /// take a snapshot once this long has elapsed since the last one, which is checked by TakeSnapshot ticks
+    pub interval:         Option<Duration>,
+    /// Number of entries retained in the log before a new snapshot, so that a slightly lagging follower
+    /// can still catch up by AppendEntries rather than a whole snapshot.
+    pub trailing_entries: LogEntryIndex,
+}
+
+impl SnapshotPolicy {
+    /// whether a snapshot should be taken with `applied` entries and `log_bytes` bytes of log following
+    /// the last snapshot taken `elapsed` ago
+    pub fn is_met(&self, applied: LogEntryIndex, log_bytes: u64, elapsed: Duration) -> bool {
+        applied > 0 && (self.applied_entries > 0 && applied >= self.applied_entries || self.log_bytes > 0 && log_bytes >= self.log_bytes || self.interval.map(|interval| elapsed >= interval).unwrap_or(false))
+    }
+
+    /// whether the size of log is needed to check this policy
+    pub fn uses_log_bytes(&self) -> bool {
+        self.log_bytes > 0
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            pre_vote:            false,
+            learner_max_lag:     0,
+            snapshot_chunk_size: DEFAULT_SNAPSHOT_CHUNK_SIZE,
+            snapshot_policy:     SnapshotPolicy::default(),
+        }
+    }
+}
+
 /// State for tfar internal implementations. Some of them are persistent, while some of them are volatile.
 pub struct InternalState {
-    /// list of servers in Raft cluster
-    servers: Vec<Server>,
+    /// id of current server
+    id: ServerId,
+    /// servers in Raft cluster, from the latest configuration in the log
+    membership: Membership,
+    /// index of the log entry with current membership, 0 for the initial one
+    membership_index: LogEntryIndex,
+    /// membership before any configuration in the log
+    initial_membership: Membership,
     /// A possibly ongoing Vot event. Should be non-emtpy for candidate server.
     voting: Option<Voting>,
     /// current leader id
     leader: Option<ServerId>,
+    /// a snapshot being received from the leader
+    receiving_snapshot: Option<ReceivingSnapshot>,
+    /// whether a snapshot taken by current server is being saved in background
+    snapshotting: bool,
+    /// time elapsed since current server took its last snapshot, or started if it hasn't taken any, summed from
+    /// TakeSnapshot ticks
+    since_last_snapshot: Duration,
+    config: Config,
 }
 
 impl InternalState {
-    pub fn new() -> InternalState {
+    /// create a new instance for server `id`, `servers` should contains all servers in the cluster including current one.
+    pub fn new(id: ServerId, servers: Vec<ServerId>) -> InternalState {
+        let membership = Membership::new(servers);
+        InternalState {
+            id,
+            membership: membership.clone(),
+            membership_index: 0,
+            initial_membership: membership,
+            voting: None,
+            leader: None,
+            receiving_snapshot: None,
+            snapshotting: false,
+            since_last_snapshot: Duration::ZERO,
+            config: Config::default(),
+        }
+    }
+
+    /// update with the given config
+    pub fn with_config(self, config: Config) -> InternalState {
+        InternalState { config, ..self }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn id(&self) -> ServerId {
+        self.id
+    }
+
+    pub fn membership(&self) -> &Membership {
+        &self.membership
+    }
+
+    /// index of the log entry with current membership, 0 for the initial one
+    pub fn membership_index(&self) -> LogEntryIndex {
+        self.membership_index
+    }
+
+    /// whether current server is a voter of the cluster, learners and removed servers don't start elections
+    pub fn is_voter(&self) -> bool {
+        self.membership.is_voter(self.id)
+    }
+
+    /// ids of all other servers in the cluster, including learners
+    pub fn peers(&self) -> Vec<ServerId> {
+        self.membership.servers().into_iter().filter(|server| *server != self.id).collect()
+    }
+
+    /// ids of all other voters in the cluster
+    pub fn voting_peers(&self) -> Vec<ServerId> {
+        self.membership.voters().into_iter().filter(|server| *server != self.id).collect()
+    }
+
+    /// Update membership with `entries` just appended to the log of `persistent`, a server always uses
+    /// the latest configuration in its log, no matter it's committed or not.
+    pub fn with_appended_entries(self, persistent: &PersistentState, entries: &[LogEntry]) -> InternalState {
+        let first_index = match entries.first() {
+            Some(entry) => entry.index,
+            None => return self,
+        };
+        let state = if self.membership_index >= first_index {
+            // configuration of current membership was deleted with conflicting entries
+            self.with_membership_before(persistent, first_index)
+        } else {
+            self
+        };
+        entries.iter().fold(state, |state, entry| match Membership::from_command(&entry.command) {
+            Some(membership) => InternalState {
+                membership,
+                membership_index: entry.index,
+                ..state
+            },
+            None => state,
+        })
+    }
+
+    /// Update membership with the latest configuration in the log of `persistent`, this is necessary
+    /// for a state recovered from storage.
+    pub fn with_recovered_membership(self, persistent: &PersistentState) -> InternalState {
+        self.with_membership_before(persistent, persistent.last_log_index() + 1)
+    }
+
+    /// membership as of the log entry at `index` in `persistent`, which should not be replaced by the snapshot
+    pub fn membership_at(&self, persistent: &PersistentState, index: LogEntryIndex) -> Membership {
+        self.membership_before(persistent, index + 1).0
+    }
+
+    /// update with the latest configuration in the log before `index`
+    fn with_membership_before(self, persistent: &PersistentState, index: LogEntryIndex) -> InternalState {
+        let (membership, membership_index) = self.membership_before(persistent, index);
         InternalState {
-            servers: Vec::new(),
-            voting:  None,
-            leader:  None,
+            membership,
+            membership_index,
+            ..self
         }
     }
 
+    /// The latest configuration in the log before `index` with the index of its entry. It falls back to the
+    /// membership kept in the snapshot, and then the initial one.
+    fn membership_before(&self, persistent: &PersistentState, index: LogEntryIndex) -> (Membership, LogEntryIndex) {
+        let latest = (persistent.first_index()..index)
+            .rev()
+            .filter_map(|index| persistent.entry(index))
+            .find_map(|entry| Membership::from_command(&entry.command).map(|membership| (membership, entry.index)));
+        latest
+            .or_else(|| persistent.snapshot().map(|snapshot| (snapshot.membership.clone(), snapshot.last_included.index)))
+            .unwrap_or_else(|| (self.initial_membership.clone(), 0))
+    }
+
     pub fn clear_voting(self) -> InternalState {
+        InternalState { voting: None, ..self }
+    }
+
+    /// creates a new states with an ongoing voting, where current server votes for itself. The known
+    /// leader is forgotten since there is an election.
+    pub fn start_voting(self) -> InternalState {
+        let mut agrees = HashSet::new();
+        agrees.insert(self.id);
         InternalState {
-            servers: self.servers,
-            voting:  None,
-            leader:  self.leader,
+            voting: Some(Voting { agrees, rejects: HashSet::new() }),
+            leader: None,
+            ..self
         }
     }
 
@@ -51,10 +234,9 @@ impl InternalState {
     pub fn with_vote(self, server: ServerId, granted: bool) -> InternalState {
         match self {
             InternalState { voting: None, .. } => panic!("there is no ongoing vote!"),
-            InternalState { servers, voting: Some(voting), leader } => InternalState {
-                servers,
+            InternalState { voting: Some(voting), .. } => InternalState {
                 voting: Some(voting.accept_vote(server, granted)),
-                leader,
+                ..self
             },
         }
     }
@@ -63,22 +245,104 @@ impl InternalState {
     pub fn vote_granted(&self) -> VoteResult {
         match self {
             InternalState { voting: None, .. } => panic!("there is no ongoing vote!"),
-            InternalState { servers, voting: Some(ref voting), .. } => voting.vote_granted(servers.len()),
+            InternalState { membership, voting: Some(ref voting), .. } => voting.vote_granted(membership),
         }
     }
 
     /// update with a new leader
     pub fn with_leader(self, new_leader: ServerId) -> InternalState {
-        let InternalState { servers, .. } = self;
         InternalState {
-            servers: servers,
-            voting:  None,
-            leader:  Some(new_leader),
+            voting: None,
+            leader: Some(new_leader),
+            ..self
         }
     }
 
+    /// update with no known leader
+    pub fn without_leader(self) -> InternalState {
+        InternalState { leader: None, ..self }
+    }
+
+    pub fn leader(&self) -> Option<ServerId> {
+        self.leader
+    }
+
     pub fn has_leader(&self, leader: ServerId) -> bool {
-        self.leader.contains(&leader)
+        self.leader == Some(leader)
+    }
+
+    /// bytes received of the snapshot whose last included entry is `last_included`, 0 if it's not being received
+    pub fn snapshot_received(&self, last_included: &LogEntryId) -> u64 {
+        match &self.receiving_snapshot {
+            Some(receiving) if receiving.last_included == *last_included => receiving.received,
+            _ => 0,
+        }
+    }
+
+    /// Update with `chunk` received following the staged bytes of the snapshot whose last included entry is
+    /// `last_included`, a different snapshot is received from the beginning.
+    pub fn with_snapshot_chunk(self, last_included: LogEntryId, chunk: &[u8]) -> InternalState {
+        let mut receiving = match self.receiving_snapshot {
+            Some(receiving) if receiving.last_included == last_included => receiving,
+            _ => ReceivingSnapshot {
+                last_included,
+                received: 0,
+                hasher: crc32fast::Hasher::new(),
+            },
+        };
+        receiving.received += chunk.len() as u64;
+        receiving.hasher.update(chunk);
+        InternalState {
+            receiving_snapshot: Some(receiving),
+            ..self
+        }
+    }
+
+    /// crc32 checksum of the bytes received of the snapshot being received, 0 if there is none
+    pub fn received_checksum(&self) -> u32 {
+        self.receiving_snapshot.as_ref().map(|receiving| receiving.hasher.clone().finalize()).unwrap_or(0)
+    }
+
+    /// update with no snapshot being received
+    pub fn without_snapshot_received(self) -> InternalState {
+        InternalState { receiving_snapshot: None, ..self }
+    }
+
+    /// whether a snapshot taken by current server is being saved
+    pub fn snapshotting(&self) -> bool {
+        self.snapshotting
+    }
+
+    /// time elapsed since current server took its last snapshot
+    pub fn since_last_snapshot(&self) -> Duration {
+        self.since_last_snapshot
+    }
+
+    /// update with `elapsed` time passed since the previous TakeSnapshot tick
+    pub fn with_elapsed(self, elapsed: Duration) -> InternalState {
+        InternalState {
+            since_last_snapshot: self.since_last_snapshot + elapsed,
+            ..self
+        }
+    }
+
+    /// update with a snapshot taken now and being saved
+    pub fn start_snapshot(self) -> InternalState {
+        InternalState {
+            snapshotting: true,
+            since_last_snapshot: Duration::ZERO,
+            ..self
+        }
+    }
+
+    /// update with the snapshot being saved finished
+    pub fn finish_snapshot(self) -> InternalState {
+        InternalState { snapshotting: false, ..self }
+    }
+
+    /// whether current server has heard from a leader since its last election timeout
+    pub fn knows_leader(&self) -> bool {
+        self.leader.is_some()
     }
 }
 
@@ -96,18 +360,40 @@ impl Voting {
         Voting { agrees, rejects }
     }
 
-    /// check whether or or this voting has been granted
-    fn vote_granted(&self, num_servers: usize) -> VoteResult {
-        let num_valid = num_servers / 2 + 1;
+    /// check whether or or this voting has been granted, by a majority of each configuration in `membership`
+    fn vote_granted(&self, membership: &Membership) -> VoteResult {
         let agrees = self.agrees.len();
         let rejects = self.rejects.len();
 
-        if agrees >= num_valid {
+        if membership.is_quorum(|server| self.agrees.contains(&server)) {
             VoteResult::Agreed(agrees)
-        } else if rejects >= num_valid {
+        } else if !membership.is_quorum(|server| !self.rejects.contains(&server)) {
+            // no quorum even if all remaining servers agree
             VoteResult::Rejected(rejects)
         } else {
             VoteResult::NotYet(agrees + rejects)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voting(agrees: &[ServerId], rejects: &[ServerId]) -> Voting {
+        Voting {
+            agrees:  agrees.iter().copied().collect(),
+            rejects: rejects.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn vote_is_granted_by_majority() {
+        let membership = Membership::new(vec![0, 1, 2]);
+        assert!(matches!(voting(&[0, 1], &[]).vote_granted(&membership), VoteResult::Agreed(2)));
+        assert!(matches!(voting(&[0], &[1]).vote_granted(&membership), VoteResult::NotYet(2)));
+        assert!(matches!(voting(&[0], &[1, 2]).vote_granted(&membership), VoteResult::Rejected(2)));
+        // a server voting again is counted once
+        assert!(matches!(voting(&[0], &[]).accept_vote(1, false).accept_vote(1, false).vote_granted(&membership), VoteResult::NotYet(2)));
+    }
+}
@@ -0,0 +1,197 @@
+use super::{Command, LogEntryIndex, ServerId, TfarCommand};
+use std::collections::BTreeSet;
+
+/// Servers in the cluster. Only votes of voters count in elections and commitment, while learners just
+/// receive log replication. During a membership change voters are in the joint configuration C_old,new,
+/// in which agreement requires separate majorities from both configurations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Membership {
+    voters:   Voters,
+    learners: BTreeSet<ServerId>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Voters {
+    /// a single configuration
+    Stable(BTreeSet<ServerId>),
+    /// the joint configuration C_old,new
+    Joint { old: BTreeSet<ServerId>, new: BTreeSet<ServerId> },
+}
+
+impl Membership {
+    /// a single configuration of `voters` without any learner
+    pub fn new(voters: impl IntoIterator<Item = ServerId>) -> Membership {
+        Membership {
+            voters:   Voters::Stable(voters.into_iter().collect()),
+            learners: BTreeSet::new(),
+        }
+    }
+
+    /// the joint configuration C_old,new without any learner
+    pub fn joint(old: impl IntoIterator<Item = ServerId>, new: impl IntoIterator<Item = ServerId>) -> Membership {
+        Membership {
+            voters:   Voters::Joint {
+                old: old.into_iter().collect(),
+                new: new.into_iter().collect(),
+            },
+            learners: BTreeSet::new(),
+        }
+    }
+
+    pub fn with_learners(self, learners: impl IntoIterator<Item = ServerId>) -> Membership {
+        Membership {
+            learners: learners.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// the membership set by a configuration command, if it is one
+    pub fn from_command(command: &Command) -> Option<Membership> {
+        match command {
+            Command::Tfar(TfarCommand::JointConfig { old, new, learners }) => Some(Membership::joint(old.iter().copied(), new.iter().copied()).with_learners(learners.iter().copied())),
+            Command::Tfar(TfarCommand::NewConfig { servers, learners }) => Some(Membership::new(servers.iter().copied()).with_learners(learners.iter().copied())),
+            _ => None,
+        }
+    }
+
+    /// the configuration command setting this membership, reversing `from_command`
+    pub fn to_config(&self) -> TfarCommand {
+        let learners = self.learners.iter().copied().collect();
+        match &self.voters {
+            Voters::Stable(servers) => TfarCommand::NewConfig {
+                servers: servers.iter().copied().collect(),
+                learners,
+            },
+            Voters::Joint { old, new } => TfarCommand::JointConfig {
+                old: old.iter().copied().collect(),
+                new: new.iter().copied().collect(),
+                learners,
+            },
+        }
+    }
+
+    pub fn is_joint(&self) -> bool {
+        match self.voters {
+            Voters::Stable(_) => false,
+            Voters::Joint { .. } => true,
+        }
+    }
+
+    /// whether `server` belongs to the cluster, as a voter or a learner
+    pub fn contains(&self, server: ServerId) -> bool {
+        self.is_voter(server) || self.is_learner(server)
+    }
+
+    /// whether `server` is a voter in any configuration
+    pub fn is_voter(&self, server: ServerId) -> bool {
+        match &self.voters {
+            Voters::Stable(servers) => servers.contains(&server),
+            Voters::Joint { old, new } => old.contains(&server) || new.contains(&server),
+        }
+    }
+
+    pub fn is_learner(&self, server: ServerId) -> bool {
+        self.learners.contains(&server)
+    }
+
+    /// all servers in the cluster, including learners
+    pub fn servers(&self) -> BTreeSet<ServerId> {
+        self.voters().union(&self.learners).copied().collect()
+    }
+
+    /// voters in any configuration
+    pub fn voters(&self) -> BTreeSet<ServerId> {
+        match &self.voters {
+            Voters::Stable(servers) => servers.clone(),
+            Voters::Joint { old, new } => old.union(new).copied().collect(),
+        }
+    }
+
+    /// voters in the latest configuration, which is C_new for a joint membership
+    pub fn latest_voters(&self) -> &BTreeSet<ServerId> {
+        match &self.voters {
+            Voters::Stable(servers) => servers,
+            Voters::Joint { new, .. } => new,
+        }
+    }
+
+    pub fn learners(&self) -> &BTreeSet<ServerId> {
+        &self.learners
+    }
+
+    /// whether voters matching `agree` form a majority of each configuration
+    pub fn is_quorum(&self, agree: impl Fn(ServerId) -> bool) -> bool {
+        match &self.voters {
+            Voters::Stable(servers) => is_majority(servers, &agree),
+            Voters::Joint { old, new } => is_majority(old, &agree) && is_majority(new, &agree),
+        }
+    }
+
+    /// the highest log index replicated on a majority of voters in each configuration, given
+    /// `match_index` of each server
+    pub fn quorum_index(&self, match_index: impl Fn(ServerId) -> LogEntryIndex) -> LogEntryIndex {
+        match &self.voters {
+            Voters::Stable(servers) => majority_index(servers, &match_index),
+            Voters::Joint { old, new } => majority_index(old, &match_index).min(majority_index(new, &match_index)),
+        }
+    }
+}
+
+fn is_majority(servers: &BTreeSet<ServerId>, agree: &impl Fn(ServerId) -> bool) -> bool {
+    servers.iter().filter(|server| agree(**server)).count() > servers.len() / 2
+}
+
+fn majority_index(servers: &BTreeSet<ServerId>, match_index: &impl Fn(ServerId) -> LogEntryIndex) -> LogEntryIndex {
+    let mut indexes: Vec<LogEntryIndex> = servers.iter().map(|server| match_index(*server)).collect();
+    indexes.sort_unstable_by(|a, b| b.cmp(a));
+    indexes.get(servers.len() / 2).copied().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joint_quorum_requires_both_majorities() {
+        let membership = Membership::joint(vec![0, 1, 2], vec![2, 3, 4]);
+        assert!(!membership.is_quorum(|server| server <= 2));
+        assert!(!membership.is_quorum(|server| server >= 2));
+        assert!(membership.is_quorum(|server| server == 1 || server == 2 || server == 3));
+        assert!(Membership::new(vec![0, 1, 2]).is_quorum(|server| server <= 1));
+    }
+
+    #[test]
+    fn joint_quorum_index_is_the_lower_one() {
+        let match_index = |server: ServerId| [5, 5, 3, 1, 1][server];
+        assert_eq!(Membership::new(vec![0, 1, 2]).quorum_index(match_index), 5);
+        assert_eq!(Membership::new(vec![2, 3, 4]).quorum_index(match_index), 1);
+        assert_eq!(Membership::joint(vec![0, 1, 2], vec![1, 2, 3]).quorum_index(match_index), 3);
+    }
+
+    #[test]
+    fn learners_are_excluded_from_quorum() {
+        let membership = Membership::new(vec![0, 1, 2]).with_learners(vec![3, 4]);
+        assert!(!membership.is_quorum(|server| server == 0 || server >= 3));
+        assert_eq!(membership.quorum_index(|server| [1, 1, 0, 5, 5][server]), 1);
+        assert!(membership.contains(3) && membership.is_learner(3) && !membership.is_voter(3));
+        assert_eq!(membership.servers().len(), 5);
+    }
+
+    #[test]
+    fn membership_from_config_commands() {
+        let command = Command::Tfar(TfarCommand::JointConfig {
+            old:      vec![0, 1],
+            new:      vec![1, 2],
+            learners: vec![3],
+        });
+        assert_eq!(Membership::from_command(&command), Some(Membership::joint(vec![0, 1], vec![1, 2]).with_learners(vec![3])));
+        let command = Command::Tfar(TfarCommand::NewConfig {
+            servers:  vec![1, 2],
+            learners: Vec::new(),
+        });
+        assert_eq!(Membership::from_command(&command), Some(Membership::new(vec![1, 2])));
+        assert_eq!(Membership::from_command(&Command::Client(Vec::new())), None);
+        let membership = Membership::joint(vec![0, 1], vec![1, 2]).with_learners(vec![3]);
+        assert_eq!(Membership::from_command(&Command::Tfar(membership.to_config())), Some(membership));
+    }
+}
@@ -1,5 +1,6 @@
-use super::{LogEntryId, LogEntryIndex, ServerId};
-use std::clone::Clone;
+use super::{LogEntryId, LogEntryIndex, Membership, ServerId};
+use crate::storage::{MemStorage, SnapshotReader, SnapshotWriter, Storage};
+use std::{clone::Clone, io, sync::Arc};
 
 /// In Raft, time are devided into terms, they are in arbitrary length,
 /// and there will be at most one leader in each term. Terms are consecutive
@@ -7,19 +8,89 @@ use std::clone::Clone;
 pub type TermId = u64;
 
 /// A command to be applied on state machines
+#[derive(Clone, Debug, PartialEq)]
 pub enum Command {
     /// tfar internal commands
-    Tfar {},
+    Tfar(TfarCommand),
     /// user defined commands
     Client(Vec<u8>),
 }
 
-#[derive(Clone)]
+/// Commands handled by tfar itself instead of the application
+#[derive(Clone, Debug, PartialEq)]
+pub enum TfarCommand {
+    /// an empty command
+    Noop,
+    /// Start a membership change with the joint configuration C_old,new. A configuration takes effect
+    /// once it's appended to the log.
+    JointConfig {
+        /// servers in current configuration
+        old: Vec<ServerId>,
+        /// servers in the new configuration
+        new: Vec<ServerId>,
+        /// non-voting servers
+        learners: Vec<ServerId>,
+    },
+    /// finish a membership change with the new configuration C_new
+    NewConfig {
+        /// servers in the new configuration
+        servers: Vec<ServerId>,
+        /// non-voting servers
+        learners: Vec<ServerId>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct LogEntry {
-    /// The term this log entry belongs to. None empty when this entry
-    /// was received by leader.
-    pub term: Option<TermId>,
+    /// The term this log entry belongs to, assigned by the leader
+    /// received the command.
+    pub term: TermId,
     pub index: LogEntryIndex,
+    /// command to be applied once this entry is committed
+    pub command: Command,
+}
+
+/// A snapshot of the application replacing all log entries up to the last included one. Only its metadata is kept
+/// in memory, the data is kept by the storage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    /// id of the last log entry replaced by this snapshot
+    pub last_included: LogEntryId,
+    /// membership as of the last included entry, since configuration entries are discarded with the log
+    pub membership:    Membership,
+    /// size of the data written by `Application::snapshot` in bytes
+    pub size:          u64,
+    /// crc32 checksum of the data, computed once when the snapshot is saved
+    pub checksum:      u32,
+}
+
+impl Snapshot {
+    /// create the snapshot taken at `last_included`, whose data is written by `writer`
+    pub fn new(last_included: LogEntryId, membership: Membership, writer: &SnapshotWriter) -> Snapshot {
+        Snapshot {
+            last_included,
+            membership,
+            size: writer.size(),
+            checksum: writer.checksum(),
+        }
+    }
+}
+
+/// A chunk of a snapshot sent by InstallSnapshot RPC, chunks are sent in order until the last one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotChunk {
+    /// id of the last log entry replaced by the snapshot
+    pub last_included: LogEntryId,
+    /// membership as of the last included entry
+    pub membership:    Membership,
+    /// byte offset of this chunk in the snapshot
+    pub offset:        u64,
+    /// raw bytes of this chunk
+    pub data:          Vec<u8>,
+    /// true if this is the last chunk
+    pub done:          bool,
+    /// crc32 checksum of the whole snapshot, only set in the last chunk
+    pub checksum:      u32,
 }
 
 /// persistent state on all servers in Raft cluster.
@@ -29,23 +100,166 @@ pub struct PersistentState {
     current_term: TermId,
     /// CandidateId that received vote in current term (can be empty)
     voted_for: Option<ServerId>,
-    /// Log entries
+    /// Log entries following the snapshot, and possibly some trailing ones replaced by it
     log: Vec<LogEntry>,
+    /// the latest snapshot, replacing log entries up to its last included one
+    snapshot: Option<Snapshot>,
+    /// stable storage where states above are persisted
+    storage: Arc<dyn Storage>,
+}
+
+impl Default for PersistentState {
+    fn default() -> Self {
+        PersistentState::new()
+    }
 }
 
 impl PersistentState {
+    /// create an empty instance backed by a memory storage
     pub fn new() -> PersistentState {
+        PersistentState::with_storage(Arc::new(MemStorage::new()))
+    }
+
+    /// create an empty instance backed by `storage`
+    pub fn with_storage(storage: Arc<dyn Storage>) -> PersistentState {
         PersistentState {
             current_term: 0,
-            voted_for:    Option::None,
-            log:          Vec::new(),
+            voted_for: Option::None,
+            log: Vec::new(),
+            snapshot: None,
+            storage,
+        }
+    }
+
+    /// recover states persisted in `storage` after a restart
+    pub async fn recover(storage: Arc<dyn Storage>) -> io::Result<PersistentState> {
+        let (current_term, voted_for) = storage.hard_state().await?;
+        let snapshot = storage.snapshot().await?;
+        let last_index = storage.last_index().await?;
+        let log = storage.read_entries(1..last_index + 1).await?;
+        // entries replaced by the snapshot may be left by a crash before they were deleted, which are
+        // dropped unless they match the snapshot
+        let log = match &snapshot {
+            Some(snapshot) => {
+                let last_included = snapshot.last_included;
+                let matched = log.iter().any(|entry| entry.index == last_included.index && entry.term == last_included.term);
+                log.into_iter().filter(|entry| matched || entry.index > last_included.index).collect()
+            },
+            None => log,
+        };
+        Ok(PersistentState {
+            current_term,
+            voted_for,
+            log,
+            snapshot,
+            storage,
+        })
+    }
+
+    /// write current term and vote to storage
+    pub async fn save_hard_state(&self) -> io::Result<()> {
+        self.storage.save_hard_state(self.current_term, self.voted_for).await
+    }
+
+    /// write `entries` to storage, existing entries starting from the index of the first one are replaced
+    pub async fn save_entries(&self, entries: &[LogEntry]) -> io::Result<()> {
+        if let Some(first) = entries.first() {
+            self.storage.truncate_entries(first.index).await?;
+            self.storage.append_entries(entries).await?;
+        }
+        Ok(())
+    }
+
+    /// Write the snapshot received from the leader to storage with the staged data, and delete entries discarded
+    /// from the log. Entries following it are deleted first if there is none in the log, so that no entry
+    /// conflicting with the snapshot is left.
+    pub async fn save_staged_snapshot(&self) -> io::Result<()> {
+        if let Some(snapshot) = &self.snapshot {
+            let last_included = snapshot.last_included.index;
+            if self.last_log_index() == last_included {
+                self.storage.truncate_entries(last_included + 1).await?;
+            }
+            self.storage.save_staged_snapshot(snapshot).await?;
+            self.compact_entries().await?;
         }
+        Ok(())
+    }
+
+    /// delete entries discarded from the log in storage, which should be done after saving the snapshot
+    pub async fn compact_entries(&self) -> io::Result<()> {
+        self.storage.compact_entries(self.first_index() - 1).await
+    }
+
+    /// write `chunk` of a snapshot being received at `offset` of the staging area, staged data after it is dropped
+    pub async fn stage_snapshot_chunk(&self, offset: u64, chunk: &[u8]) -> io::Result<()> {
+        self.storage.stage_snapshot_chunk(offset, chunk).await
+    }
+
+    /// read at most `len` bytes of the saved snapshot's data from `offset`, along with the saved snapshot
+    pub async fn read_snapshot(&self, offset: u64, len: usize) -> io::Result<Option<(Snapshot, Vec<u8>)>> {
+        self.storage.read_snapshot(offset, len).await
+    }
+
+    /// open the data of the saved snapshot for reading
+    pub async fn open_snapshot(&self) -> io::Result<SnapshotReader> {
+        self.storage.open_snapshot().await
+    }
+
+    /// open the staged data of a snapshot being received for reading
+    pub async fn open_staged_snapshot(&self) -> io::Result<SnapshotReader> {
+        self.storage.open_staged_snapshot().await
     }
 
     pub fn term(&self) -> TermId {
         self.current_term
     }
 
+    pub fn voted_for(&self) -> Option<ServerId> {
+        self.voted_for
+    }
+
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    /// index of the last entry replaced by the snapshot, 0 if there is no snapshot
+    pub fn snapshot_index(&self) -> LogEntryIndex {
+        self.snapshot.as_ref().map(|snapshot| snapshot.last_included.index).unwrap_or(0)
+    }
+
+    /// index of the first entry retained in the log, entries before it are discarded since the snapshot
+    /// replaced them. It may be before the snapshot index if some trailing entries are retained.
+    pub fn first_index(&self) -> LogEntryIndex {
+        self.log.first().map(|log| log.index).unwrap_or_else(|| self.snapshot_index() + 1)
+    }
+
+    /// approximate size of log entries following the snapshot in bytes
+    pub fn log_bytes(&self) -> u64 {
+        self.entries_after(self.snapshot_index()).iter().map(LogEntry::size).sum()
+    }
+
+    /// the log entry at `index`, None if there is no such entry or it's replaced by the snapshot
+    pub fn entry(&self, index: LogEntryIndex) -> Option<&LogEntry> {
+        if index < self.first_index() {
+            return None;
+        }
+        self.log.get((index - self.first_index()) as usize)
+    }
+
+    /// term of the log entry at `index`, which is still known for the last one replaced by the snapshot.
+    /// None if there is no such entry
+    pub fn term_of(&self, index: LogEntryIndex) -> Option<TermId> {
+        match &self.snapshot {
+            Some(snapshot) if snapshot.last_included.index == index => Some(snapshot.last_included.term),
+            _ => self.entry(index).map(|log| log.term),
+        }
+    }
+
+    /// index of the last log entry, which is the last one replaced by the snapshot if the log is empty
+    pub fn last_log_index(&self) -> LogEntryIndex {
+        self.last_log_id().index
+    }
+
     pub fn accept_candidate(&self, candidate: ServerId) -> bool {
         match self.voted_for {
             None => true,
@@ -54,14 +268,37 @@ impl PersistentState {
         }
     }
 
-    pub fn accept_log(&self, last_log: &LogEntryId) -> bool {
-        if let Some(log) = self.log.last() {
-            last_log.term >= log.term.unwrap_or(0) && last_log.index >= log.index
-        } else {
-            true
+    /// log entries starting from `index`, excluding ones discarded from the log
+    pub fn entries_from(&self, index: LogEntryIndex) -> Vec<LogEntry> {
+        self.entries_after(index.max(1) - 1).to_vec()
+    }
+
+    fn entries_after(&self, index: LogEntryIndex) -> &[LogEntry] {
+        let start = ((index + 1).max(self.first_index()) - self.first_index()).min(self.log.len() as LogEntryIndex);
+        &self.log[start as usize..]
+    }
+
+    /// id of the last log entry, falling back to the last one replaced by the snapshot. index and term
+    /// are 0 if there is no entry at all
+    pub fn last_log_id(&self) -> LogEntryId {
+        match (self.log.last(), &self.snapshot) {
+            (Some(log), _) => LogEntryId {
+                index: log.index,
+                term:  log.term,
+            },
+            (None, Some(snapshot)) => snapshot.last_included,
+            (None, None) => LogEntryId { index: 0, term: 0 },
         }
     }
 
+    /// Election restriction (§5.4.1): check whether a candidate's log ending with `last_log` is at
+    /// least as up-to-date as ours. The log with the later last term is more up-to-date, and the
+    /// longer one is more up-to-date if the last terms are the same.
+    pub fn is_up_to_date(&self, last_log: &LogEntryId) -> bool {
+        let last = self.last_log_id();
+        last_log.term > last.term || last_log.term == last.term && last_log.index >= last.index
+    }
+
     pub fn accept_term(&self, term: TermId) -> bool {
         self.current_term < term
     }
@@ -72,18 +309,87 @@ impl PersistentState {
             current_term: term,
             voted_for:    Some(candidate),
             log:          self.log,
+            snapshot:     self.snapshot,
+            storage:      self.storage,
+        }
+    }
+
+    /// Append log entries following `prev_log` as the receiver of AppendEntries RPC does, returns
+    /// the new state and whether the log contains an entry matching `prev_log`. An existing entry
+    /// conflicting with a new one (same index but different terms) is deleted with all that follow it,
+    /// and only entries not already in the log are appended.
+    pub fn with_log_entries(self, prev_log: LogEntryId, entries: Vec<LogEntry>) -> (PersistentState, bool) {
+        if !self.contains_log(&prev_log) {
+            return (self, false);
+        }
+        let first_index = self.first_index();
+        let PersistentState {
+            current_term,
+            voted_for,
+            mut log,
+            snapshot,
+            storage,
+        } = self;
+        // entries replaced by the snapshot are committed, which never conflict
+        for entry in entries.into_iter().filter(|entry| entry.index >= first_index) {
+            let pos = (entry.index - first_index) as usize;
+            match log.get(pos) {
+                Some(existing) if existing.term == entry.term => continue,
+                Some(_) => {
+                    log.truncate(pos);
+                    log.push(entry);
+                },
+                None => log.push(entry),
+            }
         }
+        let persistent = PersistentState {
+            current_term,
+            voted_for,
+            log,
+            snapshot,
+            storage,
+        };
+        (persistent, true)
     }
 
-    /// TOOD: append log entries according to preceding log entry info
-    pub fn with_log_entries(self, prev_log: LogEntryId, entries: Vec<LogEntry>) -> PersistentState {
-        let mut new_entries = Vec::new();
-        new_entries.extend(entries.iter().cloned());
-        new_entries.extend(self.log.iter().cloned());
+    /// Entries in `entries` not already present in the log, the log should be truncated from the
+    /// first of them when appending.
+    pub fn missing_entries(&self, entries: &[LogEntry]) -> Vec<LogEntry> {
+        let first_index = self.first_index();
+        match entries.iter().position(|entry| entry.index >= first_index && self.term_of(entry.index) != Some(entry.term)) {
+            Some(pos) => entries[pos..].to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Consistency check of AppendEntries RPC: check whether the log contains an entry with the
+    /// same index and term as `log_id`. Entries replaced by the snapshot are committed, so they match
+    /// entries of any leader.
+    pub fn contains_log(&self, log_id: &LogEntryId) -> bool {
+        log_id.index <= self.snapshot_index() || self.term_of(log_id.index) == Some(log_id.term)
+    }
+
+    /// create a new PersistentState by appending `entry` to the end of the log
+    pub fn with_entry(self, entry: LogEntry) -> PersistentState {
+        let mut log = self.log;
+        log.push(entry);
+        PersistentState { log, ..self }
+    }
+
+    /// Create a new PersistentState with `snapshot` replacing log entries up to its last included one.
+    /// Entries following it and `trailing` entries before it are retained if the log contains the last
+    /// included entry, otherwise the whole log is discarded since it conflicts with the snapshot.
+    pub fn with_snapshot(self, snapshot: Snapshot, trailing: LogEntryIndex) -> PersistentState {
+        let last_included = snapshot.last_included;
+        let log = if self.term_of(last_included.index) == Some(last_included.term) {
+            self.entries_after(last_included.index.saturating_sub(trailing)).to_vec()
+        } else {
+            Vec::new()
+        };
         PersistentState {
-            current_term: self.current_term,
-            voted_for:    self.voted_for,
-            log:          new_entries,
+            log,
+            snapshot: Some(snapshot),
+            ..self
         }
     }
 
@@ -93,6 +399,8 @@ impl PersistentState {
             current_term: term,
             voted_for:    None,
             log:          self.log,
+            snapshot:     self.snapshot,
+            storage:      self.storage,
         }
     }
 
@@ -101,6 +409,8 @@ impl PersistentState {
             current_term: self.current_term,
             voted_for:    Some(candidate),
             log:          self.log,
+            snapshot:     self.snapshot,
+            storage:      self.storage,
         }
     }
 
@@ -110,12 +420,269 @@ impl PersistentState {
             current_term: self.current_term + 1,
             voted_for:    None,
             log:          self.log,
+            snapshot:     self.snapshot,
+            storage:      self.storage,
         }
     }
 }
 
 impl LogEntry {
     pub fn zero() -> LogEntry {
-        LogEntry { term: Some(0), index: 0 }
+        LogEntry {
+            term:    0,
+            index:   0,
+            command: Command::Tfar(TfarCommand::Noop),
+        }
+    }
+
+    /// approximate size of this entry in bytes, i.e. its term and index with the client command
+    pub fn size(&self) -> u64 {
+        let command = match &self.command {
+            Command::Client(data) => data.len() as u64,
+            Command::Tfar(_) => 0,
+        };
+        16 + command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn entry(term: TermId, index: LogEntryIndex) -> LogEntry {
+        LogEntry {
+            term,
+            index,
+            command: Command::Client(index.to_le_bytes().to_vec()),
+        }
+    }
+
+    fn log_id(term: TermId, index: LogEntryIndex) -> LogEntryId {
+        LogEntryId { index, term }
+    }
+
+    /// builds a log from the terms of its entries
+    fn log_of(terms: &[TermId]) -> Vec<LogEntry> {
+        terms.iter().enumerate().map(|(i, term)| entry(*term, i as LogEntryIndex + 1)).collect()
+    }
+
+    fn state_of(terms: &[TermId]) -> PersistentState {
+        PersistentState {
+            log: log_of(terms),
+            ..PersistentState::new()
+        }
+    }
+
+    fn terms_of(state: &PersistentState) -> Vec<TermId> {
+        state.log.iter().map(|log| log.term).collect()
+    }
+
+    #[test]
+    fn append_to_empty_log() {
+        let (state, success) = state_of(&[]).with_log_entries(log_id(0, 0), log_of(&[1, 1]));
+        assert!(success);
+        assert_eq!(terms_of(&state), vec![1, 1]);
+    }
+
+    #[test]
+    fn reject_missing_prev_log() {
+        let (state, success) = state_of(&[1]).with_log_entries(log_id(1, 2), vec![entry(1, 3)]);
+        assert!(!success);
+        assert_eq!(terms_of(&state), vec![1]);
+    }
+
+    #[test]
+    fn reject_mismatched_prev_log() {
+        let (state, success) = state_of(&[1, 1]).with_log_entries(log_id(2, 2), vec![entry(2, 3)]);
+        assert!(!success);
+        assert_eq!(terms_of(&state), vec![1, 1]);
+    }
+
+    #[test]
+    fn truncate_conflicting_entries() {
+        let (state, success) = state_of(&[1, 1, 2, 2]).with_log_entries(log_id(1, 2), vec![entry(3, 3)]);
+        assert!(success);
+        assert_eq!(terms_of(&state), vec![1, 1, 3]);
+    }
+
+    #[test]
+    fn keep_entries_after_duplicated_ones() {
+        let (state, success) = state_of(&[1, 1, 2, 2]).with_log_entries(log_id(1, 1), vec![entry(1, 2), entry(2, 3)]);
+        assert!(success);
+        assert_eq!(terms_of(&state), vec![1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn election_restriction() {
+        // (our log terms, candidate's last log term, candidate's last log index, granted)
+        let table: Vec<(&[TermId], TermId, LogEntryIndex, bool)> = vec![
+            (&[], 0, 0, true),
+            (&[], 1, 1, true),
+            (&[], 3, 5, true),
+            (&[1], 0, 0, false),
+            (&[1], 1, 0, false),
+            (&[1], 1, 1, true),
+            (&[1], 1, 2, true),
+            (&[1], 2, 1, true),
+            (&[1, 1, 1], 1, 2, false),
+            (&[1, 1, 1], 1, 3, true),
+            (&[1, 1, 1], 1, 4, true),
+            (&[1, 1, 1], 2, 1, true),
+            (&[1, 1, 1], 2, 2, true),
+            (&[1, 2], 1, 2, false),
+            (&[1, 2], 1, 5, false),
+            (&[1, 2], 2, 1, false),
+            (&[1, 2], 2, 2, true),
+            (&[1, 2], 2, 3, true),
+            (&[1, 2], 3, 1, true),
+            (&[1, 1, 3], 2, 10, false),
+            (&[1, 1, 3], 3, 2, false),
+            (&[1, 1, 3], 3, 3, true),
+            (&[1, 1, 3], 4, 2, true),
+        ];
+        for (terms, term, index, granted) in table {
+            let state = state_of(terms);
+            assert_eq!(state.is_up_to_date(&log_id(term, index)), granted, "log {:?}, candidate's last log ({}, {})", terms, term, index);
+        }
+    }
+
+    #[test]
+    fn contains_prev_log() {
+        // (our log terms, prev log term, prev log index, contained)
+        let table: Vec<(&[TermId], TermId, LogEntryIndex, bool)> = vec![
+            (&[], 0, 0, true),
+            (&[], 1, 1, false),
+            (&[1, 2], 0, 0, true),
+            (&[1, 2], 1, 1, true),
+            (&[1, 2], 2, 1, false),
+            (&[1, 2], 2, 2, true),
+            (&[1, 2], 1, 2, false),
+            (&[1, 2], 2, 3, false),
+        ];
+        for (terms, term, index, contained) in table {
+            let state = state_of(terms);
+            assert_eq!(state.contains_log(&log_id(term, index)), contained, "log {:?}, prev log ({}, {})", terms, term, index);
+        }
+    }
+
+    fn snapshot(term: TermId, index: LogEntryIndex) -> Snapshot {
+        Snapshot {
+            last_included: log_id(term, index),
+            membership:    Membership::new(vec![0, 1, 2]),
+            size:          0,
+            checksum:      0,
+        }
+    }
+
+    #[test]
+    fn lookup_entries_after_snapshot() {
+        let state = state_of(&[1, 1, 2, 2]).with_snapshot(snapshot(1, 2), 0);
+        assert_eq!(terms_of(&state), vec![2, 2]);
+        assert_eq!(state.first_index(), 3);
+        assert_eq!(state.entry(2), None);
+        assert_eq!(state.entry(3).map(|entry| entry.index), Some(3));
+        assert_eq!((state.term_of(1), state.term_of(2), state.term_of(4)), (None, Some(1), Some(2)));
+        assert_eq!(state.entries_from(1).len(), 2);
+        assert_eq!(state.entries_from(4).len(), 1);
+        assert_eq!(state.last_log_id(), log_id(2, 4));
+
+        let state = state_of(&[1, 1]).with_snapshot(snapshot(1, 2), 0);
+        assert_eq!(state.last_log_id(), log_id(1, 2));
+        assert!(state.entries_from(3).is_empty());
+    }
+
+    #[test]
+    fn discard_log_conflicting_with_snapshot() {
+        let state = state_of(&[1, 1, 2]).with_snapshot(snapshot(3, 2), 0);
+        assert!(terms_of(&state).is_empty());
+        assert_eq!(state.last_log_id(), log_id(3, 2));
+        let state = state_of(&[1]).with_snapshot(snapshot(2, 5), 0);
+        assert!(terms_of(&state).is_empty());
+        assert_eq!(state.last_log_id(), log_id(2, 5));
+    }
+
+    #[test]
+    fn append_after_snapshot() {
+        let state = state_of(&[1, 1, 2]).with_snapshot(snapshot(1, 2), 0);
+        // entries replaced by the snapshot are taken as matched
+        assert!(state.contains_log(&log_id(2, 1)));
+        let entries = log_of(&[1, 1, 2, 3]);
+        assert_eq!(state.missing_entries(&entries), entries[3..].to_vec());
+        let (state, success) = state.with_log_entries(log_id(1, 1), entries);
+        assert!(success);
+        assert_eq!(terms_of(&state), vec![2, 3]);
+        assert_eq!(state.last_log_id(), log_id(3, 4));
+    }
+
+    #[test]
+    fn retain_trailing_entries_before_snapshot() {
+        let state = state_of(&[1, 1, 2, 2, 3]).with_snapshot(snapshot(2, 4), 2);
+        assert_eq!(terms_of(&state), vec![2, 2, 3]);
+        assert_eq!((state.first_index(), state.snapshot_index()), (3, 4));
+        assert_eq!(state.entry(3).map(|entry| entry.term), Some(2));
+        assert_eq!(state.entries_from(1).len(), 3);
+        // only the entry after the snapshot is counted, with its 8 bytes command
+        assert_eq!(state.log_bytes(), 24);
+        let state = state_of(&[1, 1]).with_snapshot(snapshot(1, 2), 5);
+        assert_eq!(terms_of(&state), vec![1, 1]);
+    }
+
+    /// A leader of term `index + 1` starts with a prefix of a previous leader's log,
+    /// and appends entries of its own term.
+    fn leader_logs(specs: Vec<(usize, usize, usize)>) -> Vec<Vec<TermId>> {
+        let mut leaders: Vec<Vec<TermId>> = Vec::new();
+        for (term, (parent, prefix, appended)) in specs.into_iter().enumerate() {
+            let mut log = match leaders.len() {
+                0 => Vec::new(),
+                n => {
+                    let parent = &leaders[parent % n];
+                    parent[..prefix % (parent.len() + 1)].to_vec()
+                },
+            };
+            log.extend(vec![term as TermId + 1; appended]);
+            leaders.push(log);
+        }
+        leaders
+    }
+
+    proptest! {
+        #[test]
+        fn log_matching_across_interleaved_appends(
+            specs in prop::collection::vec((0usize..8, 0usize..16, 0usize..5), 1..6),
+            requests in prop::collection::vec((0usize..8, 0usize..3, 0usize..16, 0usize..5), 0..40),
+        ) {
+            let leaders = leader_logs(specs);
+            let mut followers: Vec<PersistentState> = (0..3).map(|_| PersistentState::new()).collect();
+
+            for (leader, follower, prev, count) in requests {
+                let leader_log = &leaders[leader % leaders.len()];
+                let prev = prev % (leader_log.len() + 1);
+                let count = count.min(leader_log.len() - prev);
+                let prev_log = log_id(if prev == 0 { 0 } else { leader_log[prev - 1] }, prev as LogEntryIndex);
+                let entries = log_of(leader_log)[prev..prev + count].to_vec();
+
+                let state = followers.remove(follower);
+                let expected = prev == 0 || terms_of(&state).get(prev - 1) == Some(&prev_log.term);
+                let (state, success) = state.with_log_entries(prev_log, entries);
+                prop_assert_eq!(success, expected);
+                if success {
+                    // the follower's log now matches the leader's log up to the last new entry
+                    prop_assert_eq!(&terms_of(&state)[..prev + count], &leader_log[..prev + count]);
+                }
+                followers.insert(follower, state);
+            }
+
+            // if an entry has the same index and term of an entry from the leader of that term,
+            // all preceding entries are identical, which implies the Log Matching property.
+            for state in followers.iter() {
+                let terms = terms_of(state);
+                for (i, log) in state.log.iter().enumerate() {
+                    prop_assert_eq!(log.index, i as LogEntryIndex + 1);
+                    let leader_log = &leaders[terms[i] as usize - 1];
+                    prop_assert_eq!(&terms[..=i], &leader_log[..=i]);
+                }
+            }
+        }
     }
 }
@@ -1,4 +1,11 @@
-use super::LogEntryIndex;
+use super::{Command, LogEntryIndex, Membership, PersistentState, ServerId};
+use crate::{application::Application, storage::SnapshotReader};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    sync::Arc,
+};
+use tokio::sync::Mutex;
 const LOG_ENTRY_INDEX_ZERO: LogEntryIndex = 0;
 
 /// volatile state for all servers in Raft cluster.
@@ -10,47 +17,382 @@ pub struct ServerVolatileState {
     /// index of highest log entry applied to state machine
     /// (initialized to 0, increases monotonically)
     pub last_applied: LogEntryIndex,
+
+    /// the state machine where committed entries are applied, shared with the host taking snapshots of it
+    application: Arc<Mutex<Box<dyn Application>>>,
 }
 
 /// volatile state for leader
 pub struct LeaderVolatileState {
     /// for each server, index of next log entry to send to that server
     /// (initialized to leader last log index + 1)
-    pub next_index: Vec<LogEntryIndex>,
+    pub next_index: BTreeMap<ServerId, LogEntryIndex>,
 
     /// for each server, index of highest log entry known to be replicated
     /// on that server (initialized to 0, increases monotonically)
-    pub match_index: Vec<LogEntryIndex>,
+    pub match_index: BTreeMap<ServerId, LogEntryIndex>,
+
+    /// for each server, whether leader heard from it since the last election timeout
+    pub recent_active: BTreeMap<ServerId, bool>,
+
+    /// target of an ongoing leadership transfer
+    pub transferee: Option<ServerId>,
+
+    /// a new server being caught up before it's added to the cluster
+    pub catch_up: Option<CatchUp>,
+
+    /// for each server receiving the snapshot, offset of the next chunk to send
+    pub snapshot_offset: BTreeMap<ServerId, u64>,
+}
+
+/// Progress of catching up a new server in rounds, each round replicates entries in leader's log at
+/// the beginning of it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CatchUp {
+    /// the new server
+    pub server: ServerId,
+    /// current round, starting from 1
+    pub round: usize,
+    /// index of the last entry to replicate in current round
+    pub round_end: LogEntryIndex,
+    /// whether an election timeout elapsed during current round
+    pub slow: bool,
 }
 
 impl ServerVolatileState {
-    /// create an empty instance
-    pub fn new() -> ServerVolatileState {
+    /// create an instance applying committed entries to `application`, which continues from
+    /// the last entry it applied.
+    pub fn new(application: Box<dyn Application>) -> ServerVolatileState {
+        // an applied entry must have been committed
+        let last_applied = application.last_applied();
         ServerVolatileState {
-            commit_index: LOG_ENTRY_INDEX_ZERO,
-            last_applied: LOG_ENTRY_INDEX_ZERO,
+            commit_index: last_applied,
+            last_applied,
+            application: Arc::new(Mutex::new(application)),
         }
     }
 
+    /// The application shared with the host, which takes snapshots of it in background. Entries are applied
+    /// only while it's not locked.
+    pub fn application(&self) -> Arc<Mutex<Box<dyn Application>>> {
+        self.application.clone()
+    }
+
     pub fn with_commit_index(self, new_commit_index: LogEntryIndex) -> ServerVolatileState {
         if new_commit_index > self.commit_index {
             ServerVolatileState {
                 commit_index: new_commit_index,
-                last_applied: self.last_applied,
+                ..self
             }
         } else {
             self
         }
     }
+
+    /// Create a new instance by applying committed entries in `persistent` to the application in
+    /// order, returns it with responses of applied client commands by their indexes.
+    pub async fn apply_committed(self, persistent: &PersistentState) -> (ServerVolatileState, Vec<(LogEntryIndex, Vec<u8>)>) {
+        let ServerVolatileState {
+            commit_index,
+            last_applied,
+            application,
+        } = self;
+        let mut responses = Vec::new();
+        if commit_index > last_applied {
+            // wait for a snapshot being taken
+            let mut application = application.lock().await;
+            for index in last_applied + 1..=commit_index {
+                let entry = persistent.entry(index).expect("committed log entry is missing");
+                if let Command::Client(_) = entry.command {
+                    responses.push((index, application.apply(entry).await));
+                }
+            }
+        }
+        let volatile = ServerVolatileState {
+            commit_index,
+            last_applied: commit_index.max(last_applied),
+            application,
+        };
+        (volatile, responses)
+    }
+
+    /// Replace states of the application with `snapshot`, which should be followed by `with_restored`. A recovered
+    /// server should do this as well if its application is behind the saved snapshot, since the entries were
+    /// discarded. The application should be left unchanged if it fails, e.g. on corrupted data.
+    pub async fn restore(&self, snapshot: SnapshotReader) -> io::Result<()> {
+        self.application.lock().await.restore(snapshot).await
+    }
+
+    /// create a new instance after the application is restored from a snapshot replacing entries up to
+    /// `last_included`, which are taken as committed and applied
+    pub fn with_restored(self, last_included: LogEntryIndex) -> ServerVolatileState {
+        ServerVolatileState {
+            commit_index: self.commit_index.max(last_included),
+            last_applied: self.last_applied.max(last_included),
+            ..self
+        }
+    }
 }
 
 impl LeaderVolatileState {
-    /// create a new instance with last applied log index from the leader and
-    /// the number of servers in the cluster.
-    pub fn new(last_applied: LogEntryIndex, num_servers: usize) -> LeaderVolatileState {
+    /// create a new instance with last log index from the leader and
+    /// all servers in the cluster.
+    pub fn new(last_log_index: LogEntryIndex, servers: &BTreeSet<ServerId>) -> LeaderVolatileState {
+        LeaderVolatileState {
+            next_index:      servers.iter().map(|server| (*server, last_log_index + 1)).collect(),
+            match_index:     servers.iter().map(|server| (*server, LOG_ENTRY_INDEX_ZERO)).collect(),
+            recent_active:   servers.iter().map(|server| (*server, false)).collect(),
+            transferee:      None,
+            catch_up:        None,
+            snapshot_offset: BTreeMap::new(),
+        }
+    }
+
+    /// Create a new instance tracking `servers` and the server being caught up after a membership change.
+    /// New servers start from the end of leader's log, and are taken as active until next election timeout.
+    pub fn with_servers(self, servers: &BTreeSet<ServerId>, last_log_index: LogEntryIndex) -> LeaderVolatileState {
+        let LeaderVolatileState {
+            mut next_index,
+            mut match_index,
+            mut recent_active,
+            transferee,
+            catch_up,
+            mut snapshot_offset,
+        } = self;
+        let mut servers = servers.clone();
+        if let Some(catch_up) = &catch_up {
+            servers.insert(catch_up.server);
+        }
+        next_index.retain(|server, _| servers.contains(server));
+        match_index.retain(|server, _| servers.contains(server));
+        recent_active.retain(|server, _| servers.contains(server));
+        snapshot_offset.retain(|server, _| servers.contains(server));
+        for server in &servers {
+            next_index.entry(*server).or_insert(last_log_index + 1);
+            match_index.entry(*server).or_insert(LOG_ENTRY_INDEX_ZERO);
+            recent_active.entry(*server).or_insert(true);
+        }
         LeaderVolatileState {
-            next_index:  vec![last_applied + 1; num_servers],
-            match_index: vec![LOG_ENTRY_INDEX_ZERO; num_servers],
+            next_index,
+            match_index,
+            recent_active,
+            transferee,
+            catch_up,
+            snapshot_offset,
+        }
+    }
+
+    /// whether replicating progress of `server` is tracked
+    pub fn contains(&self, server: ServerId) -> bool {
+        self.match_index.contains_key(&server)
+    }
+
+    /// create a new instance by recording log entries up to `index` as replicated on `server`
+    pub fn with_match_index(self, server: ServerId, index: LogEntryIndex) -> LeaderVolatileState {
+        let LeaderVolatileState {
+            mut next_index,
+            mut match_index,
+            recent_active,
+            transferee,
+            catch_up,
+            snapshot_offset,
+        } = self;
+        if let Some(match_index) = match_index.get_mut(&server) {
+            *match_index = index.max(*match_index);
+        }
+        if let Some(next_index) = next_index.get_mut(&server) {
+            *next_index = (index + 1).max(*next_index);
+        }
+        LeaderVolatileState {
+            next_index,
+            match_index,
+            recent_active,
+            transferee,
+            catch_up,
+            snapshot_offset,
+        }
+    }
+
+    /// create a new instance by decreasing the next index of `server` after a rejected append
+    pub fn with_next_index_decreased(self, server: ServerId) -> LeaderVolatileState {
+        let LeaderVolatileState {
+            mut next_index,
+            match_index,
+            recent_active,
+            transferee,
+            catch_up,
+            snapshot_offset,
+        } = self;
+        if let (Some(next_index), Some(match_index)) = (next_index.get_mut(&server), match_index.get(&server)) {
+            if *next_index > match_index + 1 {
+                *next_index -= 1;
+            }
+        }
+        LeaderVolatileState {
+            next_index,
+            match_index,
+            recent_active,
+            transferee,
+            catch_up,
+            snapshot_offset,
+        }
+    }
+
+    /// create a new instance by recording that leader heard from `server`
+    pub fn with_recent_active(self, server: ServerId) -> LeaderVolatileState {
+        let mut recent_active = self.recent_active;
+        if let Some(active) = recent_active.get_mut(&server) {
+            *active = true;
         }
+        LeaderVolatileState { recent_active, ..self }
+    }
+
+    /// create a new instance with all servers inactive, for a new election timeout
+    pub fn clear_recent_active(self) -> LeaderVolatileState {
+        let recent_active = self.recent_active.keys().map(|server| (*server, false)).collect();
+        LeaderVolatileState { recent_active, ..self }
+    }
+
+    /// create a new instance with a new leadership transfer to `target`, or without any
+    pub fn with_transferee(self, transferee: Option<ServerId>) -> LeaderVolatileState {
+        LeaderVolatileState { transferee, ..self }
+    }
+
+    /// create a new instance catching up a new server, or without any
+    pub fn with_catch_up(self, catch_up: Option<CatchUp>) -> LeaderVolatileState {
+        LeaderVolatileState { catch_up, ..self }
+    }
+
+    /// create a new instance by recording the offset of the next snapshot chunk to send to `server`, 0 to start over
+    pub fn with_snapshot_offset(self, server: ServerId, offset: u64) -> LeaderVolatileState {
+        let mut snapshot_offset = self.snapshot_offset;
+        if offset == 0 {
+            snapshot_offset.remove(&server);
+        } else {
+            snapshot_offset.insert(server, offset);
+        }
+        LeaderVolatileState { snapshot_offset, ..self }
+    }
+
+    /// whether recently active servers, including the `leader` itself, form a quorum of `membership`
+    pub fn has_active_quorum(&self, membership: &Membership, leader: ServerId) -> bool {
+        membership.is_quorum(|server| server == leader || self.recent_active.get(&server) == Some(&true))
+    }
+
+    /// the highest log index replicated on a quorum of `membership`, taking `leader_last_log` as
+    /// the match index of the `leader` itself.
+    pub fn quorum_match_index(&self, membership: &Membership, leader: ServerId, leader_last_log: LogEntryIndex) -> LogEntryIndex {
+        membership.quorum_index(|server| if server == leader { leader_last_log } else { self.match_index.get(&server).copied().unwrap_or(LOG_ENTRY_INDEX_ZERO) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        state_machine::states::{LogEntry, TfarCommand},
+        storage::{MemStorage, SnapshotWriter, Storage},
+    };
+    use async_trait::async_trait;
+
+    /// an application recording indexes of applied entries, which outlive the application like a database
+    struct Recorder {
+        applied: Arc<std::sync::Mutex<Vec<LogEntryIndex>>>,
+    }
+
+    #[async_trait]
+    impl Application for Recorder {
+        async fn apply(&mut self, entry: &LogEntry) -> Vec<u8> {
+            self.applied.lock().unwrap().push(entry.index);
+            entry.index.to_le_bytes().to_vec()
+        }
+
+        fn last_applied(&self) -> LogEntryIndex {
+            self.applied.lock().unwrap().last().copied().unwrap_or(0)
+        }
+
+        async fn snapshot(&self, _writer: &mut SnapshotWriter) -> io::Result<()> {
+            Ok(())
+        }
+
+        async fn restore(&mut self, _snapshot: SnapshotReader) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// entries of term 1 with `commands` from index 1
+    fn entries(commands: Vec<Command>) -> Vec<LogEntry> {
+        commands
+            .into_iter()
+            .enumerate()
+            .map(|(i, command)| LogEntry {
+                term: 1,
+                index: i as LogEntryIndex + 1,
+                command,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn track_replication_progress() {
+        // leader 0 with 4 log entries in a cluster of 3 servers
+        let membership = Membership::new(vec![0, 1, 2]);
+        let leader_volatile = LeaderVolatileState::new(4, &membership.servers());
+        assert_eq!(leader_volatile.quorum_match_index(&membership, 0, 4), 0);
+
+        let leader_volatile = leader_volatile.with_match_index(1, 3);
+        assert_eq!((leader_volatile.next_index[&1], leader_volatile.match_index[&1]), (5, 3));
+        assert_eq!(leader_volatile.quorum_match_index(&membership, 0, 4), 3);
+        // a stale response doesn't move the progress backwards
+        let leader_volatile = leader_volatile.with_match_index(1, 2);
+        assert_eq!((leader_volatile.next_index[&1], leader_volatile.match_index[&1]), (5, 3));
+
+        // the next index is decreased after a rejection, but never to a matched entry
+        let leader_volatile = leader_volatile.with_next_index_decreased(2).with_next_index_decreased(1).with_next_index_decreased(1);
+        assert_eq!(leader_volatile.next_index.values().copied().collect::<Vec<_>>(), vec![5, 4, 4]);
+    }
+
+    #[tokio::test]
+    async fn apply_committed_client_commands_in_order() {
+        let storage = Arc::new(MemStorage::new());
+        let commands = vec![Command::Client(b"a".to_vec()), Command::Tfar(TfarCommand::Noop), Command::Client(b"b".to_vec()), Command::Client(b"c".to_vec())];
+        storage.append_entries(&entries(commands)).await.unwrap();
+        let persistent = PersistentState::recover(storage).await.unwrap();
+        let applied = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let volatile = ServerVolatileState::new(Box::new(Recorder { applied: applied.clone() }));
+
+        let (volatile, responses) = volatile.with_commit_index(3).apply_committed(&persistent).await;
+        assert_eq!(responses, vec![(1, 1u64.to_le_bytes().to_vec()), (3, 3u64.to_le_bytes().to_vec())]);
+        assert_eq!((volatile.commit_index, volatile.last_applied), (3, 3));
+
+        // nothing is applied again until more entries are committed
+        let (volatile, responses) = volatile.with_commit_index(2).apply_committed(&persistent).await;
+        assert!(responses.is_empty());
+        let (volatile, responses) = volatile.with_commit_index(4).apply_committed(&persistent).await;
+        assert_eq!(responses, vec![(4, 4u64.to_le_bytes().to_vec())]);
+        assert_eq!(volatile.last_applied, 4);
+        assert_eq!(*applied.lock().unwrap(), vec![1, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn apply_each_entry_once_across_restarts() {
+        let storage = Arc::new(MemStorage::new());
+        let commands = (1..=4u8).map(|i| Command::Client(vec![i])).collect();
+        storage.append_entries(&entries(commands)).await.unwrap();
+        let applied = Arc::new(std::sync::Mutex::new(Vec::new()));
+        {
+            let persistent = PersistentState::recover(storage.clone()).await.unwrap();
+            let volatile = ServerVolatileState::new(Box::new(Recorder { applied: applied.clone() }));
+            volatile.with_commit_index(2).apply_committed(&persistent).await;
+        }
+
+        // the restarted server continues from the last entry applied before the restart
+        let persistent = PersistentState::recover(storage).await.unwrap();
+        let volatile = ServerVolatileState::new(Box::new(Recorder { applied: applied.clone() }));
+        assert_eq!((volatile.commit_index, volatile.last_applied), (2, 2));
+        let (volatile, responses) = volatile.with_commit_index(4).apply_committed(&persistent).await;
+        assert_eq!(responses.iter().map(|(index, _)| *index).collect::<Vec<_>>(), vec![3, 4]);
+        assert_eq!(volatile.last_applied, 4);
+        assert_eq!(*applied.lock().unwrap(), vec![1, 2, 3, 4]);
     }
 }
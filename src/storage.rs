@@ -0,0 +1,125 @@
+mod memory;
+mod wal;
+
+pub use memory::MemStorage;
+pub use wal::{WalStorage, DEFAULT_SEGMENT_SIZE};
+
+use crate::state_machine::states::{LogEntry, LogEntryIndex, ServerId, Snapshot, TermId};
+use async_trait::async_trait;
+use std::{
+    io,
+    ops::Range,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// data of a saved snapshot, read by the application to restore from it
+pub type SnapshotReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// A writer of a new snapshot's data to storage, computing the size and checksum of the data as it's written by
+/// the application, so that the data is never held in memory as a whole.
+pub struct SnapshotWriter {
+    inner:  Box<dyn AsyncWrite + Send + Unpin>,
+    size:   u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl SnapshotWriter {
+    /// create a writer to `inner`, which is provided by a storage
+    pub fn new(inner: impl AsyncWrite + Send + Unpin + 'static) -> SnapshotWriter {
+        SnapshotWriter {
+            inner:  Box::new(inner),
+            size:   0,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    /// number of bytes written
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// crc32 checksum of bytes written
+    pub fn checksum(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+impl AsyncWrite for SnapshotWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let written = match Pin::new(&mut self.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(written)) => written,
+            poll => return poll,
+        };
+        self.size += written as u64;
+        self.hasher.update(&buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Stable storage for states which should survive restarts, i.e. current term, vote, log entries and the snapshot.
+/// A write should be durable once the returned future completes.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// load current term and the candidate voted for in current term
+    async fn hard_state(&self) -> io::Result<(TermId, Option<ServerId>)>;
+
+    /// save current term and the candidate voted for in current term
+    async fn save_hard_state(&self, term: TermId, voted_for: Option<ServerId>) -> io::Result<()>;
+
+    /// append entries following the last one in storage
+    async fn append_entries(&self, entries: &[LogEntry]) -> io::Result<()>;
+
+    /// delete entries with index not less than `index`
+    async fn truncate_entries(&self, index: LogEntryIndex) -> io::Result<()>;
+
+    /// Read entries with index within `range`. Entries replaced by the snapshot may be omitted, as well as entries
+    /// after the last one, but it fails if any other entry is missing.
+    async fn read_entries(&self, range: Range<LogEntryIndex>) -> io::Result<Vec<LogEntry>>;
+
+    /// index of the last entry in storage, which is the last one replaced by the snapshot if there is no entry
+    /// left. 0 if there is neither entry nor snapshot
+    async fn last_index(&self) -> io::Result<LogEntryIndex>;
+
+    /// Create a writer for the data of a new snapshot, dropping data written by a previous writer but not saved.
+    /// Nothing written is required to be durable until the snapshot is saved.
+    async fn create_snapshot(&self) -> io::Result<SnapshotWriter>;
+
+    /// Save `snapshot` with the data written by the last created writer, which is shut down, replacing the previous
+    /// snapshot. A snapshot not newer than the saved one is ignored.
+    async fn save_snapshot(&self, snapshot: &Snapshot) -> io::Result<()>;
+
+    /// Delete entries with index not greater than `index`, which should be replaced by the saved snapshot.
+    /// A storage may keep some of them if it can't delete them separately.
+    async fn compact_entries(&self, index: LogEntryIndex) -> io::Result<()>;
+
+    /// load the latest saved snapshot, None if there is no snapshot
+    async fn snapshot(&self) -> io::Result<Option<Snapshot>>;
+
+    /// Read at most `len` bytes of the saved snapshot's data from `offset`, along with the snapshot since a newer
+    /// one may have been saved meanwhile. None if there is no snapshot.
+    async fn read_snapshot(&self, offset: u64, len: usize) -> io::Result<Option<(Snapshot, Vec<u8>)>>;
+
+    /// open the data of the saved snapshot for reading from the beginning
+    async fn open_snapshot(&self) -> io::Result<SnapshotReader>;
+
+    /// Write `chunk` of a snapshot being received at `offset` of a staging area, dropping staged data after
+    /// it. Staged data is not required to be durable, since a snapshot is only saved after it's complete.
+    async fn stage_snapshot_chunk(&self, offset: u64, chunk: &[u8]) -> io::Result<()>;
+
+    /// open the staged data for reading from the beginning, which is complete once the last chunk is received
+    async fn open_staged_snapshot(&self) -> io::Result<SnapshotReader>;
+
+    /// Save `snapshot` with the staged data, which is complete once its last chunk is received. A snapshot not
+    /// newer than the saved one is ignored.
+    async fn save_staged_snapshot(&self, snapshot: &Snapshot) -> io::Result<()>;
+}
@@ -0,0 +1,160 @@
+use super::{SnapshotReader, SnapshotWriter, Storage};
+use crate::state_machine::states::{LogEntry, LogEntryIndex, ServerId, Snapshot, TermId};
+use async_trait::async_trait;
+use std::{
+    io::{self, Cursor},
+    mem,
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::io::AsyncWrite;
+
+/// A storage keeping everything in memory, which is not durable at all. Mostly for testing.
+#[derive(Default)]
+pub struct MemStorage {
+    inner: Mutex<MemStorageInner>,
+}
+
+#[derive(Default)]
+struct MemStorageInner {
+    term:      TermId,
+    voted_for: Option<ServerId>,
+    log:       Vec<LogEntry>,
+    snapshot:  Option<Snapshot>,
+    /// data of the snapshot
+    data:      Vec<u8>,
+    /// data of a new snapshot written by the last created writer
+    written:   Arc<Mutex<Vec<u8>>>,
+    staged:    Vec<u8>,
+}
+
+/// a writer appending to the data of a new snapshot
+struct MemWriter(Arc<Mutex<Vec<u8>>>);
+
+impl AsyncWrite for MemWriter {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl MemStorage {
+    pub fn new() -> MemStorage {
+        MemStorage::default()
+    }
+}
+
+#[async_trait]
+impl Storage for MemStorage {
+    async fn hard_state(&self) -> io::Result<(TermId, Option<ServerId>)> {
+        let inner = self.inner.lock().unwrap();
+        Ok((inner.term, inner.voted_for))
+    }
+
+    async fn save_hard_state(&self, term: TermId, voted_for: Option<ServerId>) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.term = term;
+        inner.voted_for = voted_for;
+        Ok(())
+    }
+
+    async fn append_entries(&self, entries: &[LogEntry]) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.log.extend_from_slice(entries);
+        Ok(())
+    }
+
+    async fn truncate_entries(&self, index: LogEntryIndex) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.log.retain(|entry| entry.index < index);
+        Ok(())
+    }
+
+    async fn read_entries(&self, range: Range<LogEntryIndex>) -> io::Result<Vec<LogEntry>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.log.iter().filter(|entry| range.contains(&entry.index)).cloned().collect())
+    }
+
+    async fn last_index(&self) -> io::Result<LogEntryIndex> {
+        let inner = self.inner.lock().unwrap();
+        let snapshot_index = inner.snapshot.as_ref().map(|snapshot| snapshot.last_included.index).unwrap_or(0);
+        Ok(inner.log.last().map(|entry| entry.index).unwrap_or(snapshot_index))
+    }
+
+    async fn create_snapshot(&self) -> io::Result<SnapshotWriter> {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        self.inner.lock().unwrap().written = written.clone();
+        Ok(SnapshotWriter::new(MemWriter(written)))
+    }
+
+    async fn save_snapshot(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.is_newer(snapshot) {
+            inner.snapshot = Some(snapshot.clone());
+            let data = mem::take(&mut *inner.written.lock().unwrap());
+            inner.data = data;
+        }
+        Ok(())
+    }
+
+    async fn compact_entries(&self, index: LogEntryIndex) -> io::Result<()> {
+        self.inner.lock().unwrap().log.retain(|entry| entry.index > index);
+        Ok(())
+    }
+
+    async fn snapshot(&self) -> io::Result<Option<Snapshot>> {
+        Ok(self.inner.lock().unwrap().snapshot.clone())
+    }
+
+    async fn stage_snapshot_chunk(&self, offset: u64, chunk: &[u8]) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.staged.resize(offset as usize, 0);
+        inner.staged.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    async fn read_snapshot(&self, offset: u64, len: usize) -> io::Result<Option<(Snapshot, Vec<u8>)>> {
+        let inner = self.inner.lock().unwrap();
+        let start = (offset as usize).min(inner.data.len());
+        let end = inner.data.len().min(start + len);
+        Ok(inner.snapshot.clone().map(|snapshot| (snapshot, inner.data[start..end].to_vec())))
+    }
+
+    async fn open_snapshot(&self) -> io::Result<SnapshotReader> {
+        let inner = self.inner.lock().unwrap();
+        if inner.snapshot.is_none() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no snapshot"));
+        }
+        Ok(Box::new(Cursor::new(inner.data.clone())))
+    }
+
+    async fn open_staged_snapshot(&self) -> io::Result<SnapshotReader> {
+        Ok(Box::new(Cursor::new(self.inner.lock().unwrap().staged.clone())))
+    }
+
+    async fn save_staged_snapshot(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.is_newer(snapshot) {
+            inner.snapshot = Some(snapshot.clone());
+            inner.data = mem::take(&mut inner.staged);
+        }
+        Ok(())
+    }
+}
+
+impl MemStorageInner {
+    /// whether `snapshot` is newer than the saved one
+    fn is_newer(&self, snapshot: &Snapshot) -> bool {
+        self.snapshot.as_ref().map(|saved| saved.last_included.index < snapshot.last_included.index).unwrap_or(true)
+    }
+}
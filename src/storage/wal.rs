@@ -0,0 +1,856 @@
+use super::{SnapshotReader, SnapshotWriter, Storage};
+use crate::{
+    codec,
+    state_machine::states::{LogEntry, LogEntryIndex, ServerId, Snapshot, TermId},
+};
+use async_trait::async_trait;
+use log::warn;
+use std::{
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// default maximum size of a log segment file in bytes
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+const META_FILE: &str = "meta";
+const META_TMP_FILE: &str = "meta.tmp";
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+const SNAPSHOT_STAGING_FILE: &str = "snapshot.staging";
+const SNAPSHOT_DATA_TMP_FILE: &str = "snapshot.data.tmp";
+const SNAPSHOT_EXTENSION: &str = "snapshot";
+const SEGMENT_EXTENSION: &str = "log";
+/// a record starts with the length and the crc32 checksum of its payload
+const RECORD_HEADER_SIZE: usize = 8;
+
+/// A storage writing log entries to segmented append-only files in a directory, with hard state
+/// kept in a separated meta file.
+///
+/// Each log entry is written as a record with a crc32 checksum, segments are named by the index of
+/// their first entry and rolled over once exceeding the segment size. Writes are synced to disk
+/// before returning, and the meta file is replaced atomically by writing and renaming a temporary
+/// file, so is the snapshot file with the metadata of the snapshot. The data of the snapshot is written to a file
+/// named by its last included index before the snapshot file refers to it. Log compaction deletes segments with
+/// only entries replaced by the snapshot, and chunks of a snapshot being received are staged in a separated file.
+/// On opening, a torn or corrupted tail of the last segment is dropped, which could be left by a
+/// crash during appending. File operations run on the blocking thread pool of tokio, not to stall
+/// the executor while syncing to disk.
+pub struct WalStorage {
+    inner: Arc<Mutex<Wal>>,
+}
+
+struct Wal {
+    dir:            PathBuf,
+    segment_size:   u64,
+    segments:       Vec<Segment>,
+    term:           TermId,
+    voted_for:      Option<ServerId>,
+    /// the saved snapshot
+    snapshot:       Option<Snapshot>,
+}
+
+struct Segment {
+    /// index of the first entry in this segment
+    first_index: LogEntryIndex,
+    file:        File,
+    path:        PathBuf,
+    /// offsets of records in the file, in order of their indexes
+    offsets:     Vec<u64>,
+    /// size of the file in bytes
+    size:        u64,
+}
+
+impl WalStorage {
+    /// open the storage in `dir` with the default segment size, the directory is created if missing
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<WalStorage> {
+        WalStorage::with_segment_size(dir, DEFAULT_SEGMENT_SIZE)
+    }
+
+    /// open the storage in `dir`, rolling segments over once exceeding `segment_size` bytes
+    pub fn with_segment_size<P: AsRef<Path>>(dir: P, segment_size: u64) -> io::Result<WalStorage> {
+        let wal = Wal::open(dir.as_ref().to_path_buf(), segment_size)?;
+        Ok(WalStorage { inner: Arc::new(Mutex::new(wal)) })
+    }
+
+    /// run `f` with the WAL locked on a thread where blocking is acceptable
+    async fn blocking<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Wal) -> io::Result<T> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        match tokio::task::spawn_blocking(move || f(&mut inner.lock().unwrap())).await {
+            Ok(result) => result,
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for WalStorage {
+    async fn hard_state(&self) -> io::Result<(TermId, Option<ServerId>)> {
+        // the lock may be held by a write syncing to disk
+        self.blocking(|wal| Ok((wal.term, wal.voted_for))).await
+    }
+
+    async fn save_hard_state(&self, term: TermId, voted_for: Option<ServerId>) -> io::Result<()> {
+        self.blocking(move |wal| wal.save_hard_state(term, voted_for)).await
+    }
+
+    async fn append_entries(&self, entries: &[LogEntry]) -> io::Result<()> {
+        let entries = entries.to_vec();
+        self.blocking(move |wal| wal.append(&entries)).await
+    }
+
+    async fn truncate_entries(&self, index: LogEntryIndex) -> io::Result<()> {
+        self.blocking(move |wal| wal.truncate(index)).await
+    }
+
+    async fn read_entries(&self, range: Range<LogEntryIndex>) -> io::Result<Vec<LogEntry>> {
+        self.blocking(move |wal| wal.read(range)).await
+    }
+
+    async fn last_index(&self) -> io::Result<LogEntryIndex> {
+        self.blocking(|wal| Ok(wal.last_index())).await
+    }
+
+    async fn create_snapshot(&self) -> io::Result<SnapshotWriter> {
+        let file = self.blocking(|wal| File::create(wal.dir.join(SNAPSHOT_DATA_TMP_FILE))).await?;
+        Ok(SnapshotWriter::new(tokio::fs::File::from_std(file)))
+    }
+
+    async fn save_snapshot(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let snapshot = snapshot.clone();
+        self.blocking(move |wal| {
+            if wal.is_newer(&snapshot) {
+                let data_path = wal.dir.join(SNAPSHOT_DATA_TMP_FILE);
+                // written data was not synced
+                File::open(&data_path)?.sync_all()?;
+                wal.save_snapshot(snapshot, &data_path)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn compact_entries(&self, index: LogEntryIndex) -> io::Result<()> {
+        self.blocking(move |wal| wal.compact(index)).await
+    }
+
+    async fn snapshot(&self) -> io::Result<Option<Snapshot>> {
+        self.blocking(|wal| Ok(wal.snapshot.clone())).await
+    }
+
+    async fn read_snapshot(&self, offset: u64, len: usize) -> io::Result<Option<(Snapshot, Vec<u8>)>> {
+        self.blocking(move |wal| {
+            let snapshot = match &wal.snapshot {
+                Some(snapshot) => snapshot.clone(),
+                None => return Ok(None),
+            };
+            let mut file = File::open(snapshot_data_path(&wal.dir, &snapshot))?;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut data = Vec::with_capacity(len);
+            file.take(len as u64).read_to_end(&mut data)?;
+            Ok(Some((snapshot, data)))
+        })
+        .await
+    }
+
+    async fn open_snapshot(&self) -> io::Result<SnapshotReader> {
+        let file = self
+            .blocking(|wal| match &wal.snapshot {
+                Some(snapshot) => File::open(snapshot_data_path(&wal.dir, snapshot)),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "no snapshot")),
+            })
+            .await?;
+        // the file stays readable even if a newer snapshot replaces it
+        Ok(Box::new(tokio::fs::File::from_std(file)))
+    }
+
+    async fn stage_snapshot_chunk(&self, offset: u64, chunk: &[u8]) -> io::Result<()> {
+        let chunk = chunk.to_vec();
+        self.blocking(move |wal| {
+            let mut file = OpenOptions::new().write(true).create(true).truncate(false).open(wal.dir.join(SNAPSHOT_STAGING_FILE))?;
+            file.set_len(offset)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&chunk)
+        })
+        .await
+    }
+
+    async fn open_staged_snapshot(&self) -> io::Result<SnapshotReader> {
+        let file = self.blocking(|wal| File::open(wal.dir.join(SNAPSHOT_STAGING_FILE))).await?;
+        Ok(Box::new(tokio::fs::File::from_std(file)))
+    }
+
+    async fn save_staged_snapshot(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let snapshot = snapshot.clone();
+        self.blocking(move |wal| {
+            if wal.is_newer(&snapshot) {
+                let staging_path = wal.dir.join(SNAPSHOT_STAGING_FILE);
+                // staged chunks were not synced
+                File::open(&staging_path)?.sync_all()?;
+                wal.save_snapshot(snapshot, &staging_path)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+impl Wal {
+    fn open(dir: PathBuf, segment_size: u64) -> io::Result<Wal> {
+        fs::create_dir_all(&dir)?;
+        let (term, voted_for) = read_meta(&dir.join(META_FILE))?;
+        let snapshot = read_snapshot(&dir.join(SNAPSHOT_FILE))?;
+        // data of a replaced snapshot, or of a snapshot not saved due to a crash
+        let data_path = snapshot.as_ref().map(|snapshot| snapshot_data_path(&dir, snapshot));
+
+        let mut paths = Vec::new();
+        for dir_entry in fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            if let Some(first_index) = segment_first_index(&path) {
+                paths.push((first_index, path));
+            } else if path.extension().map(|extension| extension == SNAPSHOT_EXTENSION).unwrap_or(false) && Some(&path) != data_path.as_ref() {
+                fs::remove_file(&path)?;
+            }
+        }
+        paths.sort();
+
+        let mut segments: Vec<Segment> = Vec::new();
+        let num_segments = paths.len();
+        for (i, (first_index, path)) in paths.into_iter().enumerate() {
+            if let Some(last) = segments.last() {
+                if first_index != last.first_index + last.offsets.len() as LogEntryIndex {
+                    return Err(invalid_data(format!("missing log entries before segment {:?}", path)));
+                }
+            }
+            let segment = Segment::recover(first_index, path, i + 1 == num_segments)?;
+            if segment.offsets.is_empty() {
+                // nothing left in a torn last segment
+                fs::remove_file(&segment.path)?;
+                sync_dir(&dir)?;
+            } else {
+                segments.push(segment);
+            }
+        }
+        let snapshot_index = snapshot.as_ref().map(|snapshot| snapshot.last_included.index).unwrap_or(0);
+        if let Some(first) = segments.first() {
+            if first.first_index > snapshot_index + 1 {
+                return Err(invalid_data(format!("missing log entries between snapshot {} and segment {:?}", snapshot_index, first.path)));
+            }
+        }
+
+        Ok(Wal {
+            dir,
+            segment_size,
+            segments,
+            term,
+            voted_for,
+            snapshot,
+        })
+    }
+
+    fn save_hard_state(&mut self, term: TermId, voted_for: Option<ServerId>) -> io::Result<()> {
+        let mut payload = Vec::with_capacity(17);
+        payload.extend_from_slice(&term.to_le_bytes());
+        match voted_for {
+            Some(candidate) => {
+                payload.push(1);
+                payload.extend_from_slice(&(candidate as u64).to_le_bytes());
+            },
+            None => {
+                payload.push(0);
+                payload.extend_from_slice(&0u64.to_le_bytes());
+            },
+        }
+        replace_file(&self.dir, META_TMP_FILE, META_FILE, &encode_record(&payload))?;
+
+        self.term = term;
+        self.voted_for = voted_for;
+        Ok(())
+    }
+
+    /// whether `snapshot` is newer than the saved one
+    fn is_newer(&self, snapshot: &Snapshot) -> bool {
+        snapshot.last_included.index > self.snapshot_index()
+    }
+
+    /// index of the last entry replaced by the snapshot, 0 if there is no snapshot
+    fn snapshot_index(&self) -> LogEntryIndex {
+        self.snapshot.as_ref().map(|snapshot| snapshot.last_included.index).unwrap_or(0)
+    }
+
+    /// Save `snapshot` with its data synced to the file at `data_path`, which is moved next to the snapshot file
+    /// before the snapshot file refers to it. Data of the previous snapshot is deleted afterwards.
+    fn save_snapshot(&mut self, snapshot: Snapshot, data_path: &Path) -> io::Result<()> {
+        fs::rename(data_path, snapshot_data_path(&self.dir, &snapshot))?;
+        sync_dir(&self.dir)?;
+        replace_file(&self.dir, SNAPSHOT_TMP_FILE, SNAPSHOT_FILE, &encode_record(&codec::to_bytes(&snapshot)))?;
+        if let Some(previous) = self.snapshot.replace(snapshot) {
+            fs::remove_file(snapshot_data_path(&self.dir, &previous))?;
+        }
+        Ok(())
+    }
+
+    /// delete segments with only entries up to `index`, a segment with some entries after it is kept as a whole
+    fn compact(&mut self, index: LogEntryIndex) -> io::Result<()> {
+        let mut removed = 0;
+        for segment in &self.segments {
+            if segment.first_index + segment.offsets.len() as LogEntryIndex > index + 1 {
+                break;
+            }
+            fs::remove_file(&segment.path)?;
+            removed += 1;
+        }
+        if removed > 0 {
+            self.segments.drain(..removed);
+            sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    fn last_index(&self) -> LogEntryIndex {
+        match self.segments.last() {
+            Some(segment) => segment.first_index + segment.offsets.len() as LogEntryIndex - 1,
+            None => self.snapshot_index(),
+        }
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        // the log continues right after the snapshot once all segments are deleted
+        let next_index = self.last_index() + 1;
+        for (i, entry) in entries.iter().enumerate() {
+            let expected_index = next_index + i as LogEntryIndex;
+            if entry.index != expected_index {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("expected log entry {}, got {}", expected_index, entry.index)));
+            }
+
+            let roll_over = match self.segments.last() {
+                Some(segment) => segment.size >= self.segment_size,
+                None => true,
+            };
+            if roll_over {
+                if let Some(segment) = self.segments.last() {
+                    segment.file.sync_data()?;
+                }
+                let segment = Segment::create(&self.dir, entry.index)?;
+                sync_dir(&self.dir)?;
+                self.segments.push(segment);
+            }
+
+            let segment = self.segments.last_mut().unwrap();
+            segment.append(&encode_record(&codec::to_bytes(entry)))?;
+        }
+        // the end of an append is a commit point
+        if let Some(segment) = self.segments.last() {
+            segment.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, index: LogEntryIndex) -> io::Result<()> {
+        let mut removed = false;
+        while let Some(segment) = self.segments.last_mut() {
+            if segment.first_index >= index {
+                let segment = self.segments.pop().unwrap();
+                fs::remove_file(&segment.path)?;
+                removed = true;
+            } else {
+                let kept = (index - segment.first_index) as usize;
+                if kept < segment.offsets.len() {
+                    let size = segment.offsets[kept];
+                    segment.file.set_len(size)?;
+                    segment.file.sync_data()?;
+                    segment.offsets.truncate(kept);
+                    segment.size = size;
+                }
+                break;
+            }
+        }
+        if removed {
+            sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    /// read entries within `range`, failing if any entry after the snapshot and up to the last one is not in segments
+    fn read(&mut self, range: Range<LogEntryIndex>) -> io::Result<Vec<LogEntry>> {
+        let first_index = self.segments.first().map(|segment| segment.first_index).unwrap_or(self.last_index() + 1);
+        let missing = range.start.max(self.snapshot_index() + 1)..range.end.min(first_index);
+        if !missing.is_empty() {
+            return Err(invalid_data(format!("missing log entries {:?}", missing)));
+        }
+
+        let mut entries = Vec::new();
+        for segment in self.segments.iter_mut() {
+            let end = segment.first_index + segment.offsets.len() as LogEntryIndex;
+            let start = range.start.max(segment.first_index);
+            for index in start..range.end.min(end) {
+                let offset = segment.offsets[(index - segment.first_index) as usize];
+                entries.push(segment.read(offset)?);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Segment {
+    fn create(dir: &Path, first_index: LogEntryIndex) -> io::Result<Segment> {
+        let path = dir.join(format!("{:020}.{}", first_index, SEGMENT_EXTENSION));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        Ok(Segment {
+            first_index,
+            file,
+            path,
+            offsets: Vec::new(),
+            size: 0,
+        })
+    }
+
+    /// Scan records of a segment file. An invalid record is considered as a torn tail in the last
+    /// segment, which is dropped along with everything after it, otherwise the log is corrupted.
+    fn recover(first_index: LogEntryIndex, path: PathBuf, is_last: bool) -> io::Result<Segment> {
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut offsets = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let expected_index = first_index + offsets.len() as LogEntryIndex;
+            match decode_record(&data[pos..]).and_then(|(payload, size)| decode_entry(payload).map(|entry| (entry, size))) {
+                Ok((entry, size)) if entry.index == expected_index => {
+                    offsets.push(pos as u64);
+                    pos += size;
+                },
+                result => {
+                    let reason = match result {
+                        Ok((entry, _)) => format!("unexpected log entry {}", entry.index),
+                        Err(e) => e.to_string(),
+                    };
+                    if !is_last {
+                        return Err(invalid_data(format!("corrupted segment {:?} at {}: {}", path, pos, reason)));
+                    }
+                    warn!("dropping torn tail of segment {:?} from {}: {}", path, pos, reason);
+                    file.set_len(pos as u64)?;
+                    file.sync_data()?;
+                    break;
+                },
+            }
+        }
+
+        Ok(Segment {
+            first_index,
+            file,
+            path,
+            offsets,
+            size: pos as u64,
+        })
+    }
+
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.size))?;
+        self.file.write_all(record)?;
+        self.offsets.push(self.size);
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    fn read(&mut self, offset: u64) -> io::Result<LogEntry> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        self.file.read_exact(&mut header)?;
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let mut record = header.to_vec();
+        record.resize(RECORD_HEADER_SIZE + len, 0);
+        self.file.read_exact(&mut record[RECORD_HEADER_SIZE..])?;
+        let (payload, _) = decode_record(&record)?;
+        decode_entry(payload)
+    }
+}
+
+/// parse the index of the first entry from a segment file name
+fn segment_first_index(path: &Path) -> Option<LogEntryIndex> {
+    if path.extension()? != SEGMENT_EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// path of the file with the data of `snapshot` in `dir`
+fn snapshot_data_path(dir: &Path, snapshot: &Snapshot) -> PathBuf {
+    dir.join(format!("{:020}.{}", snapshot.last_included.index, SNAPSHOT_EXTENSION))
+}
+
+fn read_meta(path: &Path) -> io::Result<(TermId, Option<ServerId>)> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, None)),
+        Err(e) => return Err(e),
+    };
+    let (payload, _) = decode_record(&data)?;
+    if payload.len() != 17 {
+        return Err(invalid_data("invalid meta file".to_string()));
+    }
+    let term = u64::from_le_bytes(payload[0..8].try_into().unwrap());
+    let voted_for = match payload[8] {
+        0 => None,
+        _ => Some(u64::from_le_bytes(payload[9..17].try_into().unwrap()) as ServerId),
+    };
+    Ok((term, voted_for))
+}
+
+fn read_snapshot(path: &Path) -> io::Result<Option<Snapshot>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let (payload, _) = decode_record(&data)?;
+    let snapshot = codec::from_bytes(payload).map_err(|e| invalid_data(format!("invalid snapshot: {}", e)))?;
+    Ok(Some(snapshot))
+}
+
+/// replace file `name` in `dir` with `data` atomically, by writing and renaming the temporary file `tmp_name`
+fn replace_file(dir: &Path, tmp_name: &str, name: &str, data: &[u8]) -> io::Result<()> {
+    let tmp_path = dir.join(tmp_name);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, dir.join(name))?;
+    sync_dir(dir)
+}
+
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// decode a record from the beginning of `data`, returns its payload and the size of the record
+fn decode_record(data: &[u8]) -> io::Result<(&[u8], usize)> {
+    if data.len() < RECORD_HEADER_SIZE {
+        return Err(invalid_data("incomplete record header".to_string()));
+    }
+    let len = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    let payload = data.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len).ok_or_else(|| invalid_data("incomplete record payload".to_string()))?;
+    if crc32fast::hash(payload) != checksum {
+        return Err(invalid_data("record checksum mismatched".to_string()));
+    }
+    Ok((payload, RECORD_HEADER_SIZE + len))
+}
+
+fn decode_entry(payload: &[u8]) -> io::Result<LogEntry> {
+    codec::from_bytes(payload).map_err(|e| invalid_data(format!("invalid log entry: {}", e)))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// make changes of directory entries durable
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        state_machine::{
+            states::{Command, InternalState, LogEntryId, Membership, PersistentState, ServerVolatileState},
+            Follower, StateMachine,
+        },
+        testing::Noop,
+    };
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn entries(terms: &[TermId], first_index: LogEntryIndex) -> Vec<LogEntry> {
+        terms
+            .iter()
+            .enumerate()
+            .map(|(i, term)| {
+                let index = first_index + i as LogEntryIndex;
+                LogEntry {
+                    term: *term,
+                    index,
+                    command: Command::Client(format!("command {}", index).into_bytes()),
+                }
+            })
+            .collect()
+    }
+
+    fn segment_paths(dir: &Path) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).filter(|path| segment_first_index(path).is_some()).collect();
+        paths.sort();
+        paths
+    }
+
+    fn snapshot_of(index: LogEntryIndex, term: TermId, data: &[u8]) -> Snapshot {
+        Snapshot {
+            last_included: LogEntryId { index, term },
+            membership:    Membership::new(vec![0, 1]),
+            size:          data.len() as u64,
+            checksum:      crc32fast::hash(data),
+        }
+    }
+
+    /// write `data` of `snapshot` in pieces and save it
+    async fn save_snapshot(storage: &WalStorage, snapshot: &Snapshot, data: &[u8]) {
+        let mut writer = storage.create_snapshot().await.unwrap();
+        for piece in data.chunks(3) {
+            writer.write_all(piece).await.unwrap();
+        }
+        writer.shutdown().await.unwrap();
+        assert_eq!((writer.size(), writer.checksum()), (snapshot.size, snapshot.checksum));
+        storage.save_snapshot(snapshot).await.unwrap();
+    }
+
+    async fn read_all(storage: &WalStorage) -> Vec<LogEntry> {
+        let last_index = storage.last_index().await.unwrap();
+        storage.read_entries(0..last_index + 1).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn recover_entries_and_hard_state() {
+        let dir = TempDir::new().unwrap();
+        {
+            let storage = WalStorage::open(dir.path()).unwrap();
+            storage.save_hard_state(3, Some(2)).await.unwrap();
+            storage.append_entries(&entries(&[1, 1, 2], 1)).await.unwrap();
+            storage.append_entries(&entries(&[3], 4)).await.unwrap();
+        }
+        let storage = WalStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.hard_state().await.unwrap(), (3, Some(2)));
+        assert_eq!(read_all(&storage).await, entries(&[1, 1, 2, 3], 1));
+        assert_eq!(storage.read_entries(2..4).await.unwrap(), entries(&[1, 2], 2));
+    }
+
+    #[tokio::test]
+    async fn truncate_across_segments() {
+        let dir = TempDir::new().unwrap();
+        {
+            let storage = WalStorage::with_segment_size(dir.path(), 1).unwrap();
+            storage.append_entries(&entries(&[1, 1, 1, 2, 2], 1)).await.unwrap();
+            assert_eq!(segment_paths(dir.path()).len(), 5);
+            storage.truncate_entries(3).await.unwrap();
+            storage.append_entries(&entries(&[3], 3)).await.unwrap();
+        }
+        let storage = WalStorage::with_segment_size(dir.path(), 1).unwrap();
+        assert_eq!(segment_paths(dir.path()).len(), 3);
+        assert_eq!(read_all(&storage).await, entries(&[1, 1, 3], 1));
+    }
+
+    #[tokio::test]
+    async fn reject_gap_in_appended_entries() {
+        let dir = TempDir::new().unwrap();
+        let storage = WalStorage::open(dir.path()).unwrap();
+        // the first entry follows the snapshot, which is none yet
+        assert_eq!(storage.append_entries(&entries(&[1], 2)).await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        storage.append_entries(&entries(&[1], 1)).await.unwrap();
+        assert_eq!(storage.append_entries(&entries(&[1], 3)).await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn drop_truncated_tail_record() {
+        let dir = TempDir::new().unwrap();
+        {
+            let storage = WalStorage::open(dir.path()).unwrap();
+            storage.append_entries(&entries(&[1, 1, 2], 1)).await.unwrap();
+        }
+        let path = &segment_paths(dir.path())[0];
+        let len = fs::metadata(path).unwrap().len();
+        OpenOptions::new().write(true).open(path).unwrap().set_len(len - 3).unwrap();
+
+        let storage = WalStorage::open(dir.path()).unwrap();
+        assert_eq!(read_all(&storage).await, entries(&[1, 1], 1));
+        // appending continues right after the recovered entries
+        storage.append_entries(&entries(&[3], 3)).await.unwrap();
+        let storage = WalStorage::open(dir.path()).unwrap();
+        assert_eq!(read_all(&storage).await, entries(&[1, 1, 3], 1));
+    }
+
+    #[tokio::test]
+    async fn drop_corrupted_tail_record() {
+        let dir = TempDir::new().unwrap();
+        {
+            let storage = WalStorage::open(dir.path()).unwrap();
+            storage.append_entries(&entries(&[1, 1, 2], 1)).await.unwrap();
+        }
+        let path = &segment_paths(dir.path())[0];
+        let mut data = fs::read(path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(path, data).unwrap();
+
+        let storage = WalStorage::open(dir.path()).unwrap();
+        assert_eq!(read_all(&storage).await, entries(&[1, 1], 1));
+    }
+
+    #[tokio::test]
+    async fn drop_torn_last_segment() {
+        let dir = TempDir::new().unwrap();
+        {
+            let storage = WalStorage::with_segment_size(dir.path(), 1).unwrap();
+            storage.append_entries(&entries(&[1, 1], 1)).await.unwrap();
+        }
+        let path = segment_paths(dir.path())[1].clone();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(RECORD_HEADER_SIZE as u64 - 1).unwrap();
+
+        let storage = WalStorage::with_segment_size(dir.path(), 1).unwrap();
+        assert!(!path.exists());
+        assert_eq!(read_all(&storage).await, entries(&[1], 1));
+    }
+
+    #[tokio::test]
+    async fn fail_on_corrupted_middle_segment() {
+        let dir = TempDir::new().unwrap();
+        {
+            let storage = WalStorage::with_segment_size(dir.path(), 1).unwrap();
+            storage.append_entries(&entries(&[1, 1, 1], 1)).await.unwrap();
+        }
+        let path = &segment_paths(dir.path())[1];
+        let mut data = fs::read(path).unwrap();
+        data[RECORD_HEADER_SIZE] ^= 0xff;
+        fs::write(path, data).unwrap();
+
+        let error = WalStorage::with_segment_size(dir.path(), 1).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn fail_on_missing_entries_after_snapshot() {
+        let dir = TempDir::new().unwrap();
+        {
+            let storage = WalStorage::with_segment_size(dir.path(), 1).unwrap();
+            storage.append_entries(&entries(&[1, 1, 1], 1)).await.unwrap();
+            save_snapshot(&storage, &snapshot_of(1, 1, b"snapshot"), b"snapshot").await;
+            // entries after the snapshot are not to be deleted
+            storage.compact_entries(2).await.unwrap();
+            let error = storage.read_entries(1..4).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(storage.read_entries(3..4).await.unwrap(), entries(&[1], 3));
+        }
+
+        let error = WalStorage::with_segment_size(dir.path(), 1).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn fail_on_corrupted_meta() {
+        let dir = TempDir::new().unwrap();
+        {
+            let storage = WalStorage::open(dir.path()).unwrap();
+            storage.save_hard_state(1, None).await.unwrap();
+        }
+        let path = dir.path().join(META_FILE);
+        let mut data = fs::read(&path).unwrap();
+        data[RECORD_HEADER_SIZE] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        assert!(WalStorage::open(dir.path()).is_err());
+    }
+
+    #[tokio::test]
+    async fn delete_segments_replaced_by_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let snapshot = snapshot_of(3, 1, b"snapshot");
+        {
+            let storage = WalStorage::with_segment_size(dir.path(), 1).unwrap();
+            storage.append_entries(&entries(&[1, 1, 1, 2, 2], 1)).await.unwrap();
+            save_snapshot(&storage, &snapshot, b"snapshot").await;
+            // the last entry replaced by the snapshot is retained
+            storage.compact_entries(2).await.unwrap();
+            assert_eq!(segment_paths(dir.path()).len(), 3);
+        }
+        let storage = Arc::new(WalStorage::with_segment_size(dir.path(), 1).unwrap());
+        assert_eq!(storage.snapshot().await.unwrap(), Some(snapshot.clone()));
+        assert_eq!(storage.read_snapshot(4, 8).await.unwrap(), Some((snapshot.clone(), b"shot".to_vec())));
+        assert_eq!(read_all(&storage).await, entries(&[1, 2, 2], 3));
+
+        // an older snapshot is ignored
+        save_snapshot(&storage, &snapshot_of(2, 1, b"older"), b"older").await;
+        assert_eq!(storage.snapshot().await.unwrap(), Some(snapshot));
+
+        let persistent = PersistentState::recover(storage.clone()).await.unwrap();
+        assert_eq!((persistent.first_index(), persistent.snapshot_index()), (3, 3));
+        assert_eq!(persistent.term_of(3), Some(1));
+        assert_eq!(persistent.last_log_index(), 5);
+
+        // appending continues after the snapshot once all segments are deleted
+        storage.truncate_entries(7).await.unwrap();
+        save_snapshot(&storage, &snapshot_of(6, 3, b""), b"").await;
+        storage.compact_entries(6).await.unwrap();
+        assert!(segment_paths(dir.path()).is_empty());
+        assert_eq!(storage.last_index().await.unwrap(), 6);
+        storage.append_entries(&entries(&[3], 7)).await.unwrap();
+        assert_eq!(read_all(&storage).await, entries(&[3], 7));
+    }
+
+    #[tokio::test]
+    async fn save_staged_snapshot_chunks() {
+        let dir = TempDir::new().unwrap();
+        {
+            let storage = WalStorage::open(dir.path()).unwrap();
+            save_snapshot(&storage, &snapshot_of(1, 1, b"first"), b"first").await;
+            storage.stage_snapshot_chunk(0, b"0123").await.unwrap();
+            storage.stage_snapshot_chunk(4, b"4567").await.unwrap();
+            // restaging a chunk drops everything after it
+            storage.stage_snapshot_chunk(2, b"ab").await.unwrap();
+            storage.stage_snapshot_chunk(4, b"cd").await.unwrap();
+            let mut staged = Vec::new();
+            storage.open_staged_snapshot().await.unwrap().read_to_end(&mut staged).await.unwrap();
+            assert_eq!(staged, b"01abcd".to_vec());
+            storage.save_staged_snapshot(&snapshot_of(2, 1, b"01abcd")).await.unwrap();
+        }
+        let storage = WalStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.snapshot().await.unwrap(), Some(snapshot_of(2, 1, b"01abcd")));
+        let mut data = Vec::new();
+        storage.open_snapshot().await.unwrap().read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"01abcd".to_vec());
+        // data of the replaced snapshot is deleted
+        assert!(!dir.path().join(format!("{:020}.{}", 1, SNAPSHOT_EXTENSION)).exists());
+    }
+
+    #[tokio::test]
+    async fn recover_persistent_state_for_follower() {
+        let dir = TempDir::new().unwrap();
+        let snapshot = snapshot_of(2, 1, b"snapshot");
+        {
+            let storage = WalStorage::with_segment_size(dir.path(), 1).unwrap();
+            storage.save_hard_state(2, Some(0)).await.unwrap();
+            storage.append_entries(&entries(&[1, 1, 2, 2], 1)).await.unwrap();
+            save_snapshot(&storage, &snapshot, b"snapshot").await;
+            storage.compact_entries(2).await.unwrap();
+        }
+        let storage = Arc::new(WalStorage::open(dir.path()).unwrap());
+        let persistent = PersistentState::recover(storage).await.unwrap();
+        assert_eq!(persistent.term(), 2);
+        assert_eq!(persistent.voted_for(), Some(0));
+        assert_eq!(persistent.snapshot(), Some(&snapshot));
+        assert_eq!((persistent.first_index(), persistent.snapshot_index()), (3, 2));
+        assert_eq!(persistent.term_of(2), Some(1));
+        assert_eq!(persistent.entries_from(3), entries(&[2, 2], 3));
+        assert_eq!(persistent.last_log_index(), 4);
+
+        let follower = Follower::new(persistent, ServerVolatileState::new(Box::new(Noop)), InternalState::new(0, vec![0, 1]));
+        assert_eq!(follower.status().term, 2);
+    }
+}
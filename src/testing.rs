@@ -0,0 +1,126 @@
+//! Fixtures shared by tests of different modules.
+
+use crate::{
+    application::Application,
+    state_machine::states::{LogEntry, LogEntryIndex, ServerId, Snapshot, TermId},
+    storage::{MemStorage, SnapshotReader, SnapshotWriter, Storage},
+};
+use async_trait::async_trait;
+use std::{
+    io,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use tokio::io::AsyncWriteExt;
+
+/// an application ignoring commands, with a constant snapshot
+pub struct Noop;
+
+#[async_trait]
+impl Application for Noop {
+    async fn apply(&mut self, _entry: &LogEntry) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn last_applied(&self) -> LogEntryIndex {
+        0
+    }
+
+    async fn snapshot(&self, writer: &mut SnapshotWriter) -> io::Result<()> {
+        writer.write_all(b"noop snapshot").await
+    }
+
+    async fn restore(&mut self, _snapshot: SnapshotReader) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// a memory storage failing to save the next `snapshot_failures` snapshots, and any hard state once `hard_state_fails`
+pub struct FailingStorage {
+    inner:                 MemStorage,
+    pub snapshot_failures: AtomicUsize,
+    /// number of snapshots tried to save
+    pub snapshot_attempts: AtomicUsize,
+    pub hard_state_fails:  AtomicBool,
+}
+
+impl FailingStorage {
+    pub fn new() -> FailingStorage {
+        FailingStorage {
+            inner:             MemStorage::new(),
+            snapshot_failures: AtomicUsize::new(0),
+            snapshot_attempts: AtomicUsize::new(0),
+            hard_state_fails:  AtomicBool::new(false),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for FailingStorage {
+    async fn hard_state(&self) -> io::Result<(TermId, Option<ServerId>)> {
+        self.inner.hard_state().await
+    }
+
+    async fn save_hard_state(&self, term: TermId, voted_for: Option<ServerId>) -> io::Result<()> {
+        if self.hard_state_fails.load(Ordering::SeqCst) {
+            return Err(io::Error::other("disk full"));
+        }
+        self.inner.save_hard_state(term, voted_for).await
+    }
+
+    async fn append_entries(&self, entries: &[LogEntry]) -> io::Result<()> {
+        self.inner.append_entries(entries).await
+    }
+
+    async fn truncate_entries(&self, index: LogEntryIndex) -> io::Result<()> {
+        self.inner.truncate_entries(index).await
+    }
+
+    async fn read_entries(&self, range: Range<LogEntryIndex>) -> io::Result<Vec<LogEntry>> {
+        self.inner.read_entries(range).await
+    }
+
+    async fn last_index(&self) -> io::Result<LogEntryIndex> {
+        self.inner.last_index().await
+    }
+
+    async fn create_snapshot(&self) -> io::Result<SnapshotWriter> {
+        self.inner.create_snapshot().await
+    }
+
+    async fn save_snapshot(&self, snapshot: &Snapshot) -> io::Result<()> {
+        self.snapshot_attempts.fetch_add(1, Ordering::SeqCst);
+        if self.snapshot_failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |failures| failures.checked_sub(1)).is_ok() {
+            return Err(io::Error::other("disk full"));
+        }
+        self.inner.save_snapshot(snapshot).await
+    }
+
+    async fn compact_entries(&self, index: LogEntryIndex) -> io::Result<()> {
+        self.inner.compact_entries(index).await
+    }
+
+    async fn snapshot(&self) -> io::Result<Option<Snapshot>> {
+        self.inner.snapshot().await
+    }
+
+    async fn read_snapshot(&self, offset: u64, len: usize) -> io::Result<Option<(Snapshot, Vec<u8>)>> {
+        self.inner.read_snapshot(offset, len).await
+    }
+
+    async fn open_snapshot(&self) -> io::Result<SnapshotReader> {
+        self.inner.open_snapshot().await
+    }
+
+    async fn stage_snapshot_chunk(&self, offset: u64, chunk: &[u8]) -> io::Result<()> {
+        self.inner.stage_snapshot_chunk(offset, chunk).await
+    }
+
+    async fn open_staged_snapshot(&self) -> io::Result<SnapshotReader> {
+        self.inner.open_staged_snapshot().await
+    }
+
+    async fn save_staged_snapshot(&self, snapshot: &Snapshot) -> io::Result<()> {
+        self.inner.save_staged_snapshot(snapshot).await
+    }
+}
@@ -0,0 +1,22 @@
+mod channel;
+mod tcp;
+mod tls;
+
+pub use channel::{ChannelNetwork, ChannelTransport};
+pub use tcp::{Server, TcpConfig, TcpTransport};
+pub use tls::TlsConfig;
+
+use crate::state_machine::{events::StateEvent, states::ServerId};
+use async_trait::async_trait;
+use std::io;
+
+/// Delivers Raft messages between servers addressed by their ids. Delivery is not reliable, a message may
+/// be lost, duplicated or reordered, which Raft tolerates by retrying.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// send `event` to server `to`, an error means the message is known to be not delivered
+    async fn send(&self, to: ServerId, event: StateEvent) -> io::Result<()>;
+
+    /// receive the next message sent to current server, None if the transport is closed
+    async fn recv(&mut self) -> Option<StateEvent>;
+}
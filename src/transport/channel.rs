@@ -0,0 +1,70 @@
+use super::Transport;
+use crate::state_machine::{events::StateEvent, states::ServerId};
+use async_trait::async_trait;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// An in-process network connecting transports of servers by channels, in which some servers may be isolated
+/// to simulate network partitions. Mostly for testing.
+#[derive(Clone, Default)]
+pub struct ChannelNetwork {
+    inner: Arc<Mutex<Network>>,
+}
+
+#[derive(Default)]
+struct Network {
+    inboxes:  HashMap<ServerId, UnboundedSender<StateEvent>>,
+    isolated: HashSet<ServerId>,
+}
+
+/// The endpoint of a server in a ChannelNetwork.
+pub struct ChannelTransport {
+    id:      ServerId,
+    network: ChannelNetwork,
+    inbox:   UnboundedReceiver<StateEvent>,
+}
+
+impl ChannelNetwork {
+    pub fn new() -> ChannelNetwork {
+        ChannelNetwork::default()
+    }
+
+    /// create the transport of server `id`, which replaces a previous one of the server
+    pub fn transport(&self, id: ServerId) -> ChannelTransport {
+        let (sender, inbox) = mpsc::unbounded_channel();
+        self.inner.lock().unwrap().inboxes.insert(id, sender);
+        ChannelTransport { id, network: self.clone(), inbox }
+    }
+
+    /// drop all messages from or to server `id`
+    pub fn isolate(&self, id: ServerId) {
+        self.inner.lock().unwrap().isolated.insert(id);
+    }
+
+    /// reconnect all isolated servers
+    pub fn heal(&self) {
+        self.inner.lock().unwrap().isolated.clear();
+    }
+}
+
+#[async_trait]
+impl Transport for ChannelTransport {
+    async fn send(&self, to: ServerId, event: StateEvent) -> io::Result<()> {
+        let network = self.network.inner.lock().unwrap();
+        if network.isolated.contains(&self.id) || network.isolated.contains(&to) {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, format!("server {} or {} is isolated", self.id, to)));
+        }
+        match network.inboxes.get(&to) {
+            Some(inbox) => inbox.send(event).map_err(|_| io::Error::new(io::ErrorKind::NotConnected, format!("server {} is closed", to))),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, format!("unknown server {}", to))),
+        }
+    }
+
+    async fn recv(&mut self) -> Option<StateEvent> {
+        self.inbox.recv().await
+    }
+}
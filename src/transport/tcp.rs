@@ -0,0 +1,510 @@
+use super::{
+    tls::{Tls, TlsConfig},
+    Transport,
+};
+use crate::{
+    codec,
+    state_machine::{events::StateEvent, states::ServerId},
+};
+use async_trait::async_trait;
+use log::debug;
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, error::TrySendError, Receiver, Sender},
+        OwnedSemaphorePermit, Semaphore,
+    },
+    time::{sleep, timeout},
+};
+
+/// maximum time of a TLS handshake, after which the connection is closed
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// network address of a server
+#[derive(Clone, Debug, PartialEq)]
+pub struct Server {
+    pub address: String,
+    pub port:    u16,
+}
+
+#[derive(Clone, Debug)]
+pub struct TcpConfig {
+    /// maximum number of messages queued for each peer, and received but not consumed yet
+    pub queue_size:      usize,
+    /// maximum size of a frame in bytes, a connection receiving a larger one is closed
+    pub max_frame_size:  usize,
+    /// maximum number of incoming connections served at the same time, others are closed once accepted
+    pub max_connections: usize,
+    /// delay before reconnecting after the first failure, which is doubled after each failure
+    pub min_backoff:     Duration,
+    /// maximum delay before reconnecting
+    pub max_backoff:     Duration,
+    /// mutual TLS between servers, messages are sent in plain text if it's not set
+    pub tls:             Option<TlsConfig>,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            queue_size:      1024,
+            // room for a snapshot chunk of the default size, or a batch of log entries
+            max_frame_size:  16 * 1024 * 1024,
+            max_connections: 64,
+            min_backoff:     Duration::from_millis(50),
+            max_backoff:     Duration::from_secs(5),
+            tls:             None,
+        }
+    }
+}
+
+/// A transport connecting servers by TCP.
+///
+/// Messages are written as frames of a 4-byte little endian length followed by the versioned message. Each peer
+/// has one persistent connection fed by a bounded queue, and a message is dropped if the queue is full, so that
+/// a slow or unreachable peer can't exhaust memory. A failed connection is reconnected with exponential backoff,
+/// while the message being written is lost.
+///
+/// With TLS, a server connecting is identified by its certificate, and only messages sent by that server are
+/// accepted from the connection.
+pub struct TcpTransport {
+    peers:      HashMap<ServerId, Sender<StateEvent>>,
+    inbox:      Receiver<StateEvent>,
+    local_addr: SocketAddr,
+}
+
+impl TcpTransport {
+    /// listen on the address of server `id` in `servers`, and connect to all other servers
+    pub async fn bind(id: ServerId, servers: HashMap<ServerId, Server>, config: TcpConfig) -> io::Result<TcpTransport> {
+        let server = servers.get(&id).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("no address of server {}", id)))?;
+        let listener = TcpListener::bind((server.address.as_str(), server.port)).await?;
+        TcpTransport::with_listener(id, listener, servers, config)
+    }
+
+    /// create the transport of server `id` accepting connections from `listener`, and connect to all other servers
+    pub fn with_listener(id: ServerId, listener: TcpListener, servers: HashMap<ServerId, Server>, config: TcpConfig) -> io::Result<TcpTransport> {
+        let local_addr = listener.local_addr()?;
+        let tls = config.tls.as_ref().map(|tls| Tls::load(tls, &servers)).transpose()?.map(Arc::new);
+        let (sender, inbox) = mpsc::channel(config.queue_size);
+        tokio::spawn(accept(listener, sender, config.max_frame_size, config.max_connections, tls.clone()));
+        let peers = servers
+            .into_iter()
+            .filter(|(server_id, _)| *server_id != id)
+            .map(|(server_id, server)| {
+                let (sender, queue) = mpsc::channel(config.queue_size);
+                tokio::spawn(connect(server_id, server, queue, config.clone(), tls.clone()));
+                (server_id, sender)
+            })
+            .collect();
+        Ok(TcpTransport { peers, inbox, local_addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    async fn send(&self, to: ServerId, event: StateEvent) -> io::Result<()> {
+        let queue = self.peers.get(&to).ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, format!("unknown server {}", to)))?;
+        queue.try_send(event).map_err(|e| match e {
+            TrySendError::Full(_) => io::Error::new(io::ErrorKind::WouldBlock, format!("queue of server {} is full", to)),
+            TrySendError::Closed(_) => io::Error::new(io::ErrorKind::NotConnected, format!("connection to server {} is closed", to)),
+        })
+    }
+
+    async fn recv(&mut self) -> Option<StateEvent> {
+        self.inbox.recv().await
+    }
+}
+
+/// accept incoming connections until the transport is dropped, serving at most `max_connections` of them at a time
+async fn accept(listener: TcpListener, inbox: Sender<StateEvent>, max_frame_size: usize, max_connections: usize, tls: Option<Arc<Tls>>) {
+    let connections = Arc::new(Semaphore::new(max_connections));
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    debug!("failed to accept connection: {}", e);
+                    continue;
+                },
+            },
+            _ = inbox.closed() => return,
+        };
+        let permit = match connections.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                debug!("rejected incoming connection: too many connections");
+                continue;
+            },
+        };
+        tokio::spawn(serve(stream, inbox.clone(), max_frame_size, tls.clone(), permit));
+    }
+}
+
+/// Receive messages from an incoming connection, once the server connecting is authenticated if TLS is enabled.
+/// The connection is counted against the limit until `_permit` is dropped.
+async fn serve(stream: TcpStream, inbox: Sender<StateEvent>, max_frame_size: usize, tls: Option<Arc<Tls>>, _permit: OwnedSemaphorePermit) {
+    let tls = match tls {
+        Some(tls) => tls,
+        None => return receive(stream, inbox, max_frame_size, None).await,
+    };
+    match timeout(HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+        Ok(Ok((peer, stream))) => receive(stream, inbox, max_frame_size, Some(peer)).await,
+        Ok(Err(e)) => debug!("rejected incoming connection: {}", e),
+        Err(_) => debug!("rejected incoming connection: TLS handshake timed out"),
+    }
+}
+
+/// Feed messages from an incoming connection to the inbox, until the connection fails or the transport is dropped.
+/// Only messages sent by `peer` are accepted if the connection is authenticated.
+async fn receive<S: AsyncRead + Unpin>(mut stream: S, inbox: Sender<StateEvent>, max_frame_size: usize, peer: Option<ServerId>) {
+    loop {
+        let frame = tokio::select! {
+            frame = read_frame(&mut stream, max_frame_size) => frame,
+            _ = inbox.closed() => return,
+        };
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                debug!("closing incoming connection: {}", e);
+                return;
+            },
+        };
+        // a message not understood, e.g. from a server of another protocol version, is dropped alone
+        let event = match codec::decode_message(&frame) {
+            Ok(event) => event,
+            Err(e) => {
+                debug!("dropped a malformed message: {}", e);
+                continue;
+            },
+        };
+        // only messages between servers are accepted from the network, never local events such as timeouts
+        match (event.sender(), peer) {
+            (None, _) => {
+                debug!("dropped a local event from the network: {:?}", event);
+                continue;
+            },
+            (Some(sender), Some(peer)) if sender != peer => {
+                debug!("dropped a message not sent by server {}: {:?}", peer, event);
+                continue;
+            },
+            _ => {},
+        }
+        if inbox.send(event).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// deliver messages in `queue` to server `id` at `server` until the transport is dropped
+async fn connect(id: ServerId, server: Server, mut queue: Receiver<StateEvent>, config: TcpConfig, tls: Option<Arc<Tls>>) {
+    let mut backoff = config.min_backoff;
+    while !queue.is_closed() {
+        let mut stream = match open(id, &server, tls.as_deref()).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("failed to connect to {}:{}: {}", server.address, server.port, e);
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(config.max_backoff);
+                continue;
+            },
+        };
+        backoff = config.min_backoff;
+        while let Some(event) = queue.recv().await {
+            let payload = match codec::encode_message(&event) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    debug!("dropped a message to {}:{}: {}", server.address, server.port, e);
+                    continue;
+                },
+            };
+            if payload.len() > config.max_frame_size {
+                debug!("dropped a message of {} bytes to {}:{}", payload.len(), server.address, server.port);
+                continue;
+            }
+            if let Err(e) = write_frame(&mut stream, &payload).await {
+                debug!("connection to {}:{} failed: {}", server.address, server.port, e);
+                break;
+            }
+        }
+    }
+}
+
+/// open a connection to server `id` at `server`, which is authenticated if TLS is enabled
+async fn open(id: ServerId, server: &Server, tls: Option<&Tls>) -> io::Result<Box<dyn AsyncWrite + Send + Unpin>> {
+    let stream = TcpStream::connect((server.address.as_str(), server.port)).await?;
+    if let Err(e) = stream.set_nodelay(true) {
+        debug!("failed to set TCP_NODELAY: {}", e);
+    }
+    match tls {
+        Some(tls) => {
+            let stream = timeout(HANDSHAKE_TIMEOUT, tls.connect(id, stream)).await.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+            Ok(Box::new(stream))
+        },
+        None => Ok(Box::new(stream)),
+    }
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S, max_frame_size: usize) -> io::Result<Vec<u8>> {
+    let len = stream.read_u32_le().await? as usize;
+    if len > max_frame_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes exceeds the limit", len)));
+    }
+    // memory grows with the bytes actually received, rather than the length claimed by the peer
+    let mut frame = Vec::new();
+    stream.take(len as u64).read_to_end(&mut frame).await?;
+    if frame.len() < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed within a frame"));
+    }
+    Ok(frame)
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).await?;
+    // a TLS stream buffers records until flushed
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_machine::states::LogEntryId;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+    use tempfile::TempDir;
+
+    fn server(addr: SocketAddr) -> Server {
+        Server {
+            address: addr.ip().to_string(),
+            port:    addr.port(),
+        }
+    }
+
+    fn vote_request(term: u64) -> StateEvent {
+        vote_request_from(0, term)
+    }
+
+    fn vote_request_from(candidate: ServerId, term: u64) -> StateEvent {
+        StateEvent::VoteRequest {
+            term,
+            candidate,
+            last_log: LogEntryId { index: 1, term: 1 },
+            leader_transfer: false,
+        }
+    }
+
+    /// keep sending vote requests of `term` until one is received, since messages may be lost during reconnecting
+    async fn deliver(from: &TcpTransport, to: &mut TcpTransport, term: u64) {
+        let deliver = async {
+            loop {
+                let _ = from.send(1, vote_request(term)).await;
+                if let Ok(Some(StateEvent::VoteRequest { term: received, .. })) = timeout(Duration::from_millis(100), to.recv()).await {
+                    if received == term {
+                        return;
+                    }
+                }
+            }
+        };
+        timeout(Duration::from_secs(10), deliver).await.expect("message not delivered");
+    }
+
+    #[tokio::test]
+    async fn exchange_messages_and_reconnect() {
+        let listeners = vec![TcpListener::bind("127.0.0.1:0").await.unwrap(), TcpListener::bind("127.0.0.1:0").await.unwrap()];
+        let addrs: Vec<SocketAddr> = listeners.iter().map(|listener| listener.local_addr().unwrap()).collect();
+        let servers: HashMap<ServerId, Server> = addrs.iter().enumerate().map(|(id, addr)| (id, server(*addr))).collect();
+        let mut listeners = listeners.into_iter();
+        let transport = TcpTransport::with_listener(0, listeners.next().unwrap(), servers.clone(), TcpConfig::default()).unwrap();
+        let mut peer = TcpTransport::with_listener(1, listeners.next().unwrap(), servers.clone(), TcpConfig::default()).unwrap();
+        deliver(&transport, &mut peer, 1).await;
+
+        // the peer restarts on the same address, once its listener is closed in background
+        drop(peer);
+        let mut peer = loop {
+            match TcpTransport::bind(1, servers.clone(), TcpConfig::default()).await {
+                Ok(peer) => break peer,
+                Err(_) => sleep(Duration::from_millis(10)).await,
+            }
+        };
+        deliver(&transport, &mut peer, 2).await;
+    }
+
+    #[tokio::test]
+    async fn drop_local_events_from_network() {
+        let listeners = vec![TcpListener::bind("127.0.0.1:0").await.unwrap(), TcpListener::bind("127.0.0.1:0").await.unwrap()];
+        let servers: HashMap<ServerId, Server> = listeners.iter().enumerate().map(|(id, listener)| (id, server(listener.local_addr().unwrap()))).collect();
+        let mut listeners = listeners.into_iter();
+        let transport = TcpTransport::with_listener(0, listeners.next().unwrap(), servers.clone(), TcpConfig::default()).unwrap();
+        let mut peer = TcpTransport::with_listener(1, listeners.next().unwrap(), servers, TcpConfig::default()).unwrap();
+        for event in [StateEvent::ElectionTimeout, StateEvent::TakeSnapshot { elapsed: Duration::ZERO, forced: true }, StateEvent::AddServer { server: 2 }, vote_request(1)] {
+            transport.send(1, event).await.unwrap();
+        }
+        let received = timeout(Duration::from_secs(10), peer.recv()).await.unwrap();
+        assert!(matches!(received, Some(StateEvent::VoteRequest { term: 1, .. })));
+
+        // nor are frames of local events from a server of a previous draft of the protocol
+        let mut stream = TcpStream::connect(peer.local_addr()).await.unwrap();
+        for payload in [vec![codec::PROTOCOL_VERSION, 10], codec::encode_message(&vote_request(2)).unwrap()] {
+            write_frame(&mut stream, &payload).await.unwrap();
+        }
+        let received = timeout(Duration::from_secs(10), peer.recv()).await.unwrap();
+        assert!(matches!(received, Some(StateEvent::VoteRequest { term: 2, .. })));
+    }
+
+    #[tokio::test]
+    async fn drop_messages_to_unreachable_peer_once_queue_is_full() {
+        // nothing listens on the peer's address
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let servers = vec![(0, server(listener.local_addr().unwrap())), (1, server(addr))].into_iter().collect();
+        let config = TcpConfig { queue_size: 4, ..TcpConfig::default() };
+        let transport = TcpTransport::with_listener(0, listener, servers, config).unwrap();
+        for term in 0..4 {
+            transport.send(1, vote_request(term)).await.unwrap();
+        }
+        assert_eq!(transport.send(1, vote_request(4)).await.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(transport.send(2, vote_request(0)).await.unwrap_err().kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[tokio::test]
+    async fn close_incoming_connections_beyond_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let servers = vec![(1, server(listener.local_addr().unwrap()))].into_iter().collect();
+        let config = TcpConfig { max_connections: 1, ..TcpConfig::default() };
+        let mut peer = TcpTransport::with_listener(1, listener, servers, config).unwrap();
+
+        let mut first = TcpStream::connect(peer.local_addr()).await.unwrap();
+        write_frame(&mut first, &codec::encode_message(&vote_request(1)).unwrap()).await.unwrap();
+        assert!(matches!(timeout(Duration::from_secs(10), peer.recv()).await.unwrap(), Some(StateEvent::VoteRequest { term: 1, .. })));
+        let mut second = TcpStream::connect(peer.local_addr()).await.unwrap();
+        assert_eq!(timeout(Duration::from_secs(10), second.read(&mut [0; 1])).await.unwrap().unwrap(), 0);
+
+        // a connection is accepted again once the first one is closed
+        drop(first);
+        let deliver = async {
+            loop {
+                let mut stream = TcpStream::connect(peer.local_addr()).await.unwrap();
+                write_frame(&mut stream, &codec::encode_message(&vote_request(2)).unwrap()).await.unwrap();
+                if let Ok(Some(StateEvent::VoteRequest { term: 2, .. })) = timeout(Duration::from_millis(100), peer.recv()).await {
+                    return;
+                }
+            }
+        };
+        timeout(Duration::from_secs(10), deliver).await.expect("message not delivered");
+    }
+
+    #[tokio::test]
+    async fn close_connection_within_frame() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let reader = tokio::spawn(async move {
+            let mut server = server;
+            read_frame(&mut server, 1024).await
+        });
+        // a frame claiming more bytes than sent before the connection is closed
+        client.write_all(&1000u32.to_le_bytes()).await.unwrap();
+        client.write_all(b"partial").await.unwrap();
+        drop(client);
+        assert_eq!(reader.await.unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    /// A self-signed certificate authority issuing certificates in `dir`.
+    struct Authority {
+        dir:         PathBuf,
+        key:         KeyPair,
+        certificate: rcgen::Certificate,
+    }
+
+    impl Authority {
+        fn new(dir: &Path, name: &str) -> Authority {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let certificate = params.self_signed(&key).unwrap();
+            fs::write(dir.join(format!("{}.pem", name)), certificate.pem()).unwrap();
+            Authority { dir: dir.to_path_buf(), key, certificate }
+        }
+
+        /// issue a certificate for `name`, with the authority `ca` trusted by its owner
+        fn issue(&self, name: &str, ca: &str) -> TlsConfig {
+            let key = KeyPair::generate().unwrap();
+            let certificate = CertificateParams::new(vec![name.to_string()]).unwrap().signed_by(&key, &self.certificate, &self.key).unwrap();
+            let config = TlsConfig {
+                certificate:     self.dir.join(format!("{}.pem", name)),
+                private_key:     self.dir.join(format!("{}.key", name)),
+                ca_certificates: self.dir.join(format!("{}.pem", ca)),
+                names:           vec![(0, "server-0".to_string()), (1, "server-1".to_string())].into_iter().collect(),
+            };
+            fs::write(&config.certificate, certificate.pem()).unwrap();
+            fs::write(&config.private_key, key.serialize_pem()).unwrap();
+            config
+        }
+    }
+
+    async fn tls_servers() -> (Vec<TcpListener>, HashMap<ServerId, Server>) {
+        let listeners = vec![TcpListener::bind("127.0.0.1:0").await.unwrap(), TcpListener::bind("127.0.0.1:0").await.unwrap()];
+        let servers = listeners.iter().enumerate().map(|(id, listener)| (id, server(listener.local_addr().unwrap()))).collect();
+        (listeners, servers)
+    }
+
+    fn tls_transport(id: ServerId, listener: TcpListener, servers: &HashMap<ServerId, Server>, tls: TlsConfig) -> TcpTransport {
+        let config = TcpConfig { tls: Some(tls), ..TcpConfig::default() };
+        TcpTransport::with_listener(id, listener, servers.clone(), config).unwrap()
+    }
+
+    /// assert that `transport` receives nothing within a while
+    async fn assert_silent(transport: &mut TcpTransport) {
+        assert!(timeout(Duration::from_millis(500), transport.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn exchange_messages_over_mutual_tls() {
+        let dir = TempDir::new().unwrap();
+        let ca = Authority::new(dir.path(), "ca");
+        let (listeners, servers) = tls_servers().await;
+        let mut listeners = listeners.into_iter();
+        let transport = tls_transport(0, listeners.next().unwrap(), &servers, ca.issue("server-0", "ca"));
+        let mut peer = tls_transport(1, listeners.next().unwrap(), &servers, ca.issue("server-1", "ca"));
+        deliver(&transport, &mut peer, 1).await;
+
+        // a server can't send messages on behalf of another one
+        transport.send(1, vote_request_from(1, 2)).await.unwrap();
+        transport.send(1, vote_request_from(0, 3)).await.unwrap();
+        assert_eq!(timeout(Duration::from_secs(10), peer.recv()).await.unwrap(), Some(vote_request_from(0, 3)));
+    }
+
+    #[tokio::test]
+    async fn reject_certificates_not_in_cluster() {
+        let dir = TempDir::new().unwrap();
+        let ca = Authority::new(dir.path(), "ca");
+        let rogue_ca = Authority::new(dir.path(), "rogue-ca");
+        let (listeners, servers) = tls_servers().await;
+        let mut listeners = listeners.into_iter();
+        let listener = listeners.next().unwrap();
+        let mut peer = tls_transport(1, listeners.next().unwrap(), &servers, ca.issue("server-1", "ca"));
+
+        // a certificate issued by the trusted authority for a server out of the cluster
+        let intruder = tls_transport(0, listener, &servers, ca.issue("intruder", "ca"));
+        for term in 0..10 {
+            intruder.send(1, vote_request(term)).await.unwrap();
+        }
+        assert_silent(&mut peer).await;
+
+        // a certificate for a server in the cluster, but issued by an untrusted authority
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let impostor = tls_transport(0, listener, &servers, rogue_ca.issue("server-0", "ca"));
+        for term in 0..10 {
+            impostor.send(1, vote_request(term)).await.unwrap();
+        }
+        assert_silent(&mut peer).await;
+    }
+}
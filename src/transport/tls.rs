@@ -0,0 +1,110 @@
+use super::Server;
+use crate::state_machine::states::ServerId;
+use std::{collections::HashMap, convert::TryFrom, io, path::PathBuf, sync::Arc};
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    client,
+    rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+        server::WebPkiClientVerifier,
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    server, TlsAcceptor, TlsConnector,
+};
+
+/// Mutual TLS between servers, with certificates in PEM files. Each server presents a certificate issued by a
+/// trusted authority for its own name, so that a connection is bound to a server of the cluster on both ends.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// certificate chain of current server, starting with its own certificate
+    pub certificate:     PathBuf,
+    /// private key of current server's certificate
+    pub private_key:     PathBuf,
+    /// certificates of the authorities trusted to issue certificates of servers
+    pub ca_certificates: PathBuf,
+    /// Name of each server, a DNS name or an IP address which its certificate must be valid for. The address of
+    /// a server is used if its name is not given.
+    pub names:           HashMap<ServerId, String>,
+}
+
+/// TLS settings loaded from files, shared by connections of a transport
+pub(super) struct Tls {
+    connector: TlsConnector,
+    acceptor:  TlsAcceptor,
+    names:     HashMap<ServerId, ServerName<'static>>,
+}
+
+impl Tls {
+    /// load TLS settings of `config` for a cluster of `servers`
+    pub(super) fn load(config: &TlsConfig, servers: &HashMap<ServerId, Server>) -> io::Result<Tls> {
+        let chain: Vec<CertificateDer<'static>> = CertificateDer::pem_file_iter(&config.certificate).map_err(invalid_data)?.collect::<Result<_, _>>().map_err(invalid_data)?;
+        let key = PrivateKeyDer::from_pem_file(&config.private_key).map_err(invalid_data)?;
+        let mut roots = RootCertStore::empty();
+        for certificate in CertificateDer::pem_file_iter(&config.ca_certificates).map_err(invalid_data)? {
+            roots.add(certificate.map_err(invalid_data)?).map_err(invalid_data)?;
+        }
+        let roots = Arc::new(roots);
+        let provider = Arc::new(ring::default_provider());
+
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone()).build().map_err(invalid_input)?;
+        let server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(chain.clone(), key.clone_key())
+            .map_err(invalid_input)?;
+        let client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?
+            .with_root_certificates(roots)
+            .with_client_auth_cert(chain, key)
+            .map_err(invalid_input)?;
+
+        let names = servers
+            .iter()
+            .map(|(id, server)| {
+                let name = config.names.get(id).unwrap_or(&server.address).clone();
+                ServerName::try_from(name).map(|name| (*id, name)).map_err(invalid_input)
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Tls {
+            connector: TlsConnector::from(Arc::new(client_config)),
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            names,
+        })
+    }
+
+    /// handshake over an outgoing connection to server `id`, whose certificate must be valid for its name
+    pub(super) async fn connect(&self, id: ServerId, stream: TcpStream) -> io::Result<client::TlsStream<TcpStream>> {
+        let name = self.names.get(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, format!("unknown server {}", id)))?;
+        self.connector.connect(name.clone(), stream).await
+    }
+
+    /// Handshake over an incoming connection, and identify the server connecting by its certificate, which must be
+    /// valid for the name of exactly one server in the cluster.
+    pub(super) async fn accept(&self, stream: TcpStream) -> io::Result<(ServerId, server::TlsStream<TcpStream>)> {
+        let stream = self.acceptor.accept(stream).await?;
+        let certificate = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "no certificate of peer"))?;
+        let certificate = webpki::EndEntityCert::try_from(certificate).map_err(invalid_data)?;
+        let mut servers = self.names.iter().filter(|(_, name)| certificate.verify_is_valid_for_subject_name(name).is_ok()).map(|(id, _)| *id);
+        match (servers.next(), servers.next()) {
+            (Some(id), None) => Ok((id, stream)),
+            (None, _) => Err(io::Error::new(io::ErrorKind::PermissionDenied, "certificate of no server in the cluster")),
+            (Some(_), Some(_)) => Err(io::Error::new(io::ErrorKind::PermissionDenied, "certificate of multiple servers in the cluster")),
+        }
+    }
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn invalid_input(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
@@ -0,0 +1,209 @@
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tfar::{
+    application::Application,
+    node::{Node, NodeHandle, Timeouts},
+    state_machine::{
+        events::StateEvent,
+        states::{Command, Config, InternalState, LogEntry, LogEntryIndex, ServerId},
+        Role,
+    },
+    storage::{MemStorage, SnapshotReader, SnapshotWriter},
+    transport::{ChannelNetwork, Server, TcpConfig, TcpTransport, Transport},
+};
+use tokio::{
+    net::TcpListener,
+    time::{sleep, timeout},
+};
+
+/// client commands applied by a server, in order
+type Applied = Arc<Mutex<Vec<Vec<u8>>>>;
+
+struct Recorder {
+    applied:      Applied,
+    last_applied: LogEntryIndex,
+}
+
+#[async_trait]
+impl Application for Recorder {
+    async fn apply(&mut self, entry: &LogEntry) -> Vec<u8> {
+        if let Command::Client(command) = &entry.command {
+            self.applied.lock().unwrap().push(command.clone());
+        }
+        self.last_applied = entry.index;
+        Vec::new()
+    }
+
+    fn last_applied(&self) -> LogEntryIndex {
+        self.last_applied
+    }
+
+    async fn snapshot(&self, _writer: &mut SnapshotWriter) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn restore(&mut self, _snapshot: SnapshotReader) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// servers running in the same process, connected by a channel network unless specified
+struct Cluster {
+    network:  Option<ChannelNetwork>,
+    handles:  Vec<NodeHandle>,
+    applied:  Vec<Applied>,
+    isolated: Vec<ServerId>,
+}
+
+impl Cluster {
+    async fn start(num_servers: usize, config: Config) -> Cluster {
+        let network = ChannelNetwork::new();
+        let transports = (0..num_servers).map(|id| network.transport(id)).collect();
+        let mut cluster = Cluster::with_transports(transports, config).await;
+        cluster.network = Some(network);
+        cluster
+    }
+
+    /// start a server with each of `transports`, whose ids are their positions
+    async fn with_transports<T: Transport + 'static>(transports: Vec<T>, config: Config) -> Cluster {
+        let num_servers = transports.len();
+        let mut handles = Vec::new();
+        let mut applied = Vec::new();
+        for (id, transport) in transports.into_iter().enumerate() {
+            let recorder = Recorder {
+                applied:      Applied::default(),
+                last_applied: 0,
+            };
+            applied.push(recorder.applied.clone());
+            let internal = InternalState::new(id, (0..num_servers).collect()).with_config(config.clone());
+            let (node, handle) = Node::new(Arc::new(MemStorage::new()), Box::new(recorder), internal, transport, Timeouts::default()).await.unwrap();
+            tokio::spawn(node.run());
+            handles.push(handle);
+        }
+        Cluster {
+            network: None,
+            handles,
+            applied,
+            isolated: Vec::new(),
+        }
+    }
+
+    fn connected(&self) -> Vec<ServerId> {
+        (0..self.handles.len()).filter(|id| !self.isolated.contains(id)).collect()
+    }
+
+    /// wait until connected servers agree on a single leader
+    async fn leader(&self) -> ServerId {
+        let mut leader = None;
+        eventually(|| {
+            let statuses: Vec<_> = self.connected().into_iter().map(|id| self.handles[id].status()).collect();
+            let leaders: Vec<_> = statuses.iter().filter(|status| status.role == Role::Leader).collect();
+            leader = match leaders.as_slice() {
+                [status] => Some(status.id),
+                _ => None,
+            };
+            leader.is_some() && statuses.iter().all(|status| status.leader == leader)
+        })
+        .await;
+        leader.unwrap()
+    }
+
+    fn isolate(&mut self, id: ServerId) {
+        self.network.as_ref().unwrap().isolate(id);
+        self.isolated.push(id);
+    }
+
+    fn heal(&mut self) {
+        self.network.as_ref().unwrap().heal();
+        self.isolated.clear();
+    }
+
+    fn request(&self, leader: ServerId, command: &[u8]) {
+        let request = StateEvent::ClientRequest { command: Command::Client(command.to_vec()) };
+        assert!(self.handles[leader].send(request));
+    }
+
+    /// wait until `servers` have applied exactly `commands`
+    async fn applied(&self, servers: Vec<ServerId>, commands: &[&[u8]]) {
+        eventually(|| servers.iter().all(|id| *self.applied[*id].lock().unwrap() == commands)).await;
+    }
+}
+
+async fn eventually(mut condition: impl FnMut() -> bool) {
+    let wait = async {
+        while !condition() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    };
+    timeout(Duration::from_secs(10), wait).await.expect("condition not met in time");
+}
+
+#[tokio::test]
+async fn three_servers_elect_leader_and_replicate_entries() {
+    let cluster = Cluster::start(3, Config::default()).await;
+    let leader = cluster.leader().await;
+    for command in &[b"x", b"y", b"z"] {
+        cluster.request(leader, *command);
+    }
+    cluster.applied(vec![0, 1, 2], &[b"x", b"y", b"z"]).await;
+    assert_eq!(cluster.leader().await, leader);
+}
+
+#[tokio::test]
+async fn five_servers_elect_new_leader_after_partition() {
+    let config = Config { pre_vote: true, ..Config::default() };
+    let mut cluster = Cluster::start(5, config).await;
+    let old_leader = cluster.leader().await;
+    let old_term = cluster.handles[old_leader].status().term;
+    cluster.request(old_leader, b"a");
+    cluster.applied((0..5).collect(), &[b"a"]).await;
+
+    // the rest servers elect a new leader without the partitioned one
+    cluster.isolate(old_leader);
+    let leader = cluster.leader().await;
+    assert_ne!(leader, old_leader);
+    assert!(cluster.handles[leader].status().term > old_term);
+    cluster.request(leader, b"b");
+    cluster.applied(cluster.connected(), &[b"a", b"b"]).await;
+
+    // the old leader follows the new one once the partition heals
+    cluster.heal();
+    cluster.applied((0..5).collect(), &[b"a", b"b"]).await;
+    assert_eq!(cluster.leader().await, leader);
+}
+
+#[tokio::test]
+async fn three_servers_over_tcp() {
+    let mut listeners = Vec::new();
+    for _ in 0..3 {
+        listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    }
+    let servers: HashMap<ServerId, Server> = listeners
+        .iter()
+        .enumerate()
+        .map(|(id, listener)| {
+            let addr = listener.local_addr().unwrap();
+            let server = Server {
+                address: addr.ip().to_string(),
+                port:    addr.port(),
+            };
+            (id, server)
+        })
+        .collect();
+    let transports = listeners
+        .into_iter()
+        .enumerate()
+        .map(|(id, listener)| TcpTransport::with_listener(id, listener, servers.clone(), TcpConfig::default()).unwrap())
+        .collect();
+
+    let cluster = Cluster::with_transports(transports, Config::default()).await;
+    let leader = cluster.leader().await;
+    cluster.request(leader, b"x");
+    cluster.request(leader, b"y");
+    cluster.applied(vec![0, 1, 2], &[b"x", b"y"]).await;
+}